@@ -0,0 +1,129 @@
+/// How the outputs of every control are combined into a single fan duty.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+	/// Hottest control relative to its setpoint drives the fans.
+	Max,
+	/// Each output scaled by its control's weight and summed, negative outputs pull the total down.
+	WeightedSum,
+	/// First control(in config order) above its setpoint drives the fans, falling back to `Max` if none are.
+	Priority,
+	/// Positive outputs summed, so several warm controls add up instead of only the hottest counting.
+	ClampedSum
+}
+
+impl Aggregation {
+	pub fn parse(name: &str) -> Result<Aggregation, String> {
+		match name {
+			"max" => Ok(Aggregation::Max),
+			"weighted_sum" => Ok(Aggregation::WeightedSum),
+			"priority" => Ok(Aggregation::Priority),
+			"clamped_sum" => Ok(Aggregation::ClampedSum),
+			_ => Err(format!("unknown aggregation \"{}\", expected max, weighted_sum, priority or clamped_sum", name))
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Aggregation::Max => "max",
+			Aggregation::WeightedSum => "weighted_sum",
+			Aggregation::Priority => "priority",
+			Aggregation::ClampedSum => "clamped_sum"
+		}
+	}
+}
+
+/// Output of a single control ready to be combined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Input {
+	pub output: f32,
+	pub weight: f32,
+	pub above_setpoint: bool
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Combined {
+	pub output: f32,
+	/// Share of `output` from each input, in the same order.
+	pub contributions: Vec<f32>,
+	/// Index of the input contributing the most, None if nothing is pushing the fans above their minimum.
+	pub winner: Option<usize>
+}
+
+pub fn combine(aggregation: Aggregation, inputs: &[Input]) -> Combined {
+	match aggregation {
+		Aggregation::Max => single(inputs, largest(inputs.iter().map(|v| v.output))),
+		Aggregation::Priority => match inputs.iter().position(|v| v.above_setpoint) {
+			Some(idx) => single(inputs, Some(idx)),
+			None => single(inputs, largest(inputs.iter().map(|v| v.output)))
+		},
+		Aggregation::WeightedSum => sum(inputs.iter().map(|v| v.output * v.weight).collect()),
+		Aggregation::ClampedSum => sum(inputs.iter().map(|v| v.output.max(0.0)).collect())
+	}
+}
+
+fn largest<I: Iterator<Item=f32>>(values: I) -> Option<usize> {
+	values.enumerate()
+		.filter(|(_, v)| *v > 0.0)
+		.fold(None, |best: Option<(usize, f32)>, (idx, v)| match best {
+			Some((_, best_v)) if best_v >= v => best,
+			_ => Some((idx, v))
+		})
+		.map(|(idx, _)| idx)
+}
+
+/// A single input drives the output on its own.
+fn single(inputs: &[Input], winner: Option<usize>) -> Combined {
+	let output = winner.map(|idx| inputs[idx].output.max(0.0)).unwrap_or(0.0);
+
+	Combined {
+		output,
+		contributions: (0..inputs.len()).map(|idx| if Some(idx) == winner { output } else { 0.0 }).collect(),
+		winner
+	}
+}
+
+fn sum(contributions: Vec<f32>) -> Combined {
+	let output = contributions.iter().sum::<f32>().clamp(0.0, 1.0);
+
+	Combined {
+		output,
+		winner: largest(contributions.iter().cloned()),
+		contributions
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn inputs(values: &[(f32, f32, bool)]) -> Vec<Input> {
+		values.iter().map(|(output, weight, above_setpoint)| Input { output: *output, weight: *weight, above_setpoint: *above_setpoint }).collect()
+	}
+
+	#[test]
+	fn combines_outputs() {
+		let values = inputs(&[(0.2, 1.0, true), (0.5, 0.5, true), (-0.1, 1.0, false)]);
+
+		assert_eq!(combine(Aggregation::Max, &values), Combined { output: 0.5, contributions: vec!(0.0, 0.5, 0.0), winner: Some(1) });
+		assert_eq!(combine(Aggregation::Priority, &values), Combined { output: 0.2, contributions: vec!(0.2, 0.0, 0.0), winner: Some(0) });
+
+		let weighted = combine(Aggregation::WeightedSum, &values);
+		assert!((weighted.output - 0.35).abs() < 1e-6);
+		assert_eq!(weighted.contributions, vec!(0.2, 0.25, -0.1));
+		assert_eq!(weighted.winner, Some(1));
+
+		assert_eq!(combine(Aggregation::ClampedSum, &values).contributions, vec!(0.2, 0.5, 0.0));
+		assert_eq!(combine(Aggregation::ClampedSum, &inputs(&[(0.7, 1.0, true), (0.6, 1.0, true)])).output, 1.0);
+	}
+
+	#[test]
+	fn priority_falls_back_to_max() {
+		let values = inputs(&[(-0.2, 1.0, false), (0.1, 1.0, false)]);
+		assert_eq!(combine(Aggregation::Priority, &values).winner, Some(1));
+
+		let idle = inputs(&[(-0.2, 1.0, false), (-0.1, 1.0, false)]);
+		assert_eq!(combine(Aggregation::Max, &idle), Combined { output: 0.0, contributions: vec!(0.0, 0.0), winner: None });
+		assert_eq!(Aggregation::parse("weighted_sum"), Ok(Aggregation::WeightedSum));
+		assert!(Aggregation::parse("min").is_err());
+	}
+}
@@ -0,0 +1,93 @@
+use std::time::{Duration, Instant};
+
+use crate::status::BudgetStatus;
+
+/// Tracks how often and for how long fans ran above the duty cap.
+pub struct AcousticBudget {
+	exceeded_since: Option<Instant>,
+	/// Time spent above the cap by previous exceedances.
+	total: Duration,
+	count: u64,
+	/// Control that pushed fans above the cap most recently.
+	cause: Option<String>
+}
+
+impl AcousticBudget {
+	pub fn new() -> AcousticBudget {
+		AcousticBudget {
+			exceeded_since: None,
+			total: Duration::from_secs(0),
+			count: 0,
+			cause: None
+		}
+	}
+
+	/// `cause` is the control past its emergency threshold while the duty is above the cap, None otherwise.
+	pub fn update(&mut self, cause: Option<&str>, now: Instant) {
+		match (cause, self.exceeded_since) {
+			(Some(cause), None) => {
+				self.count += 1;
+				self.exceeded_since = Some(now);
+				warn!("{} passed its emergency threshold, exceeding the acoustic budget({} times since startup)", cause, self.count);
+			},
+			(None, Some(since)) => {
+				self.total += now.duration_since(since);
+				self.exceeded_since = None;
+				info!("Back within the acoustic budget after {}s", now.duration_since(since).as_secs());
+			},
+			_ => ()
+		}
+
+		if let Some(cause) = cause {
+			self.cause = Some(cause.to_string());
+		}
+	}
+
+	pub fn exceeded(&self) -> bool {
+		self.exceeded_since.is_some()
+	}
+
+	/// Total time spent above the cap, including the current exceedance.
+	pub fn exceeded_for(&self, now: Instant) -> Duration {
+		self.total + self.exceeded_since.map(|v| now.duration_since(v)).unwrap_or_default()
+	}
+
+	pub fn status(&self, now: Instant) -> BudgetStatus {
+		BudgetStatus {
+			exceeded: self.exceeded(),
+			count: self.count,
+			exceeded_secs: self.exceeded_for(now).as_secs(),
+			cause: self.cause.clone()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tracks_exceedances() {
+		let start = Instant::now();
+		let at = |secs| start + Duration::from_secs(secs);
+		let mut budget = AcousticBudget::new();
+
+		budget.update(None, at(0));
+		assert!(!budget.exceeded());
+
+		budget.update(Some("Temp 3.1"), at(10));
+		budget.update(Some("Temp 3.1"), at(20));
+		assert_eq!(budget.exceeded_for(at(25)), Duration::from_secs(15));
+
+		budget.update(None, at(30));
+		budget.update(Some("Exhaust Temp"), at(100));
+		budget.update(None, at(105));
+
+		assert_eq!(budget.status(at(200)), BudgetStatus {
+			exceeded: false,
+			count: 2,
+			exceeded_secs: 25,
+			cause: Some("Exhaust Temp".to_string())
+		});
+	}
+}
@@ -0,0 +1,300 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::control::ControlLoop;
+use crate::ipmi::*;
+use crate::vendor::FanProfile;
+
+pub const DEFAULT_PATH: &str = "/var/lib/thermal_watchdog/calibration.json";
+
+/// How often sensors are read while waiting for fans to settle.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+/// Samples at the end of each step averaged into the recorded RPM.
+const STEADY_SAMPLES: usize = 3;
+/// A fan whose RPM at the top duty isn't at least this much above the bottom duty isn't following commands.
+const RESPONSE_RATIO: f32 = 1.2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CurvePoint {
+	pub duty: f32,
+	pub rpm: f32
+}
+
+/// Steady-state RPM of a single fan at each calibrated duty, ordered by duty.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FanCurve {
+	pub name: String,
+	pub points: Vec<CurvePoint>
+}
+
+impl FanCurve {
+	/// RPM expected at `duty`, interpolated between calibrated points and clamped to the ends of the curve.
+	pub fn expected(&self, duty: f32) -> Option<f32> {
+		interpolate(&self.points, duty)
+	}
+
+	/// 1.0 when the fan runs exactly as calibrated, falling to 0.0 as it strays to double or zero the expected RPM.
+	pub fn health(&self, duty: f32, rpm: u32) -> Option<f32> {
+		let expected = self.expected(duty).filter(|v| *v > 0.0)?;
+
+		Some((1.0 - (rpm as f32 - expected).abs() / expected).clamp(0.0, 1.0))
+	}
+
+	/// Fans that were stopped or didn't speed up across the calibrated duties.
+	pub fn problem(&self) -> Option<&'static str> {
+		let first = self.points.first()?;
+		let last = self.points.last()?;
+
+		if last.rpm <= 0.0 {
+			Some("not spinning")
+		} else if last.rpm < first.rpm * RESPONSE_RATIO {
+			Some("not responding to duty")
+		} else {
+			None
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+	/// Milliseconds since the unix epoch.
+	pub timestamp: u64,
+	/// Fan profile used to drive the fans while calibrating.
+	pub profile: String,
+	pub fans: Vec<FanCurve>
+}
+
+impl Calibration {
+	pub fn load(path: &str) -> Result<Calibration> {
+		let content = ::std::fs::read_to_string(path)?;
+
+		serde_json::from_str(&content)
+			.map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid calibration in {}: {}", path, e)))
+	}
+
+	pub fn save(&self, path: &str) -> Result<()> {
+		if let Some(parent) = Path::new(path).parent() {
+			::std::fs::create_dir_all(parent)?;
+		}
+
+		let content = serde_json::to_string_pretty(self)
+			.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+		::std::fs::write(path, content)
+	}
+
+	pub fn fan(&self, name: &str) -> Option<&FanCurve> {
+		self.fans.iter().find(|v| v.name == name)
+	}
+
+	/// Average RPM of the healthy fans at each calibrated duty.
+	pub fn average_curve(&self) -> Vec<CurvePoint> {
+		let fans = self.fans.iter().filter(|v| v.problem().is_none()).collect::<Vec<_>>();
+
+		let duties = match fans.first() {
+			Some(fan) => fan.points.iter().map(|v| v.duty).collect::<Vec<_>>(),
+			None => return vec!()
+		};
+
+		duties.into_iter()
+			.filter_map(|duty| {
+				let rpms = fans.iter()
+					.filter_map(|fan| fan.points.iter().find(|v| v.duty == duty))
+					.map(|v| v.rpm)
+					.collect::<Vec<_>>();
+
+				if rpms.len() == fans.len() {
+					Some(CurvePoint { duty, rpm: rpms.iter().sum::<f32>() / rpms.len() as f32 })
+				} else {
+					None
+				}
+			})
+			.collect()
+	}
+}
+
+pub fn interpolate(points: &[CurvePoint], duty: f32) -> Option<f32> {
+	let first = points.first()?;
+	let last = points.last()?;
+
+	if duty <= first.duty {
+		return Some(first.rpm)
+	}
+
+	if duty >= last.duty {
+		return Some(last.rpm)
+	}
+
+	points.windows(2)
+		.find(|v| duty >= v[0].duty && duty <= v[1].duty)
+		.map(|v| {
+			let span = v[1].duty - v[0].duty;
+
+			if span <= 0.0 {
+				v[0].rpm
+			} else {
+				v[0].rpm + (v[1].rpm - v[0].rpm) * (duty - v[0].duty) / span
+			}
+		})
+}
+
+/// Reads sensors and fails if any control can't be read or is at or above its failsafe.
+fn check_failsafes(control_loop: &mut ControlLoop) -> ::std::result::Result<(), String> {
+	control_loop.refresh().map_err(|e| format!("unable to read sensors: {}", e))?;
+
+	for control in control_loop.status() {
+		match control.temp {
+			None => return Err(format!("unable to read {}", control.label)),
+			Some(temp) if control.tripped() => return Err(format!("{} is at {} which meets its failsafe of {}", control.label, temp, control.failsafe)),
+			_ => ()
+		}
+	}
+
+	Ok(())
+}
+
+/// Holds each duty in `duties` for `settle`, recording the RPM every fan settles at.
+///
+/// Aborts as soon as any control reaches its failsafe. Fan control is always handed back to the BMC before returning.
+pub fn calibrate(profile: &FanProfile, control_loop: &mut ControlLoop, duties: &[f32], settle: Duration) -> ::std::result::Result<Calibration, String> {
+	check_failsafes(control_loop)?;
+
+	ipmi_set_fan_manual(profile, true).map_err(|e| e.to_string())?;
+
+	let result = step_duties(profile, control_loop, duties, settle);
+
+	if let Err(e) = ipmi_set_fan_manual(profile, false) {
+		error!("Failed to restore automatic fan control: {}", e);
+	}
+
+	let fans = result?;
+
+	Ok(Calibration {
+		timestamp: crate::status::now_ms(),
+		profile: profile.name.clone(),
+		fans
+	})
+}
+
+fn step_duties(profile: &FanProfile, control_loop: &mut ControlLoop, duties: &[f32], settle: Duration) -> ::std::result::Result<Vec<FanCurve>, String> {
+	let mut curves: Vec<FanCurve> = vec!();
+
+	for duty in duties {
+		info!("Holding fans at {:.0}% for {}s", duty * 100.0, settle.as_secs());
+		ipmi_set_fan_speed(profile, *duty).map_err(|e| e.to_string())?;
+
+		let started = Instant::now();
+		let mut samples = vec!();
+
+		loop {
+			check_failsafes(control_loop).map_err(|e| format!("aborted calibration at {:.0}% duty, {}", duty * 100.0, e))?;
+			samples.push(control_loop.fans().to_vec());
+
+			if started.elapsed() >= settle && samples.len() >= STEADY_SAMPLES {
+				break
+			}
+
+			thread::sleep(SAMPLE_INTERVAL);
+		}
+
+		let steady = &samples[samples.len() - STEADY_SAMPLES..];
+
+		for (name, _) in steady[0].iter() {
+			let rpms = steady.iter()
+				.filter_map(|sample| sample.iter().find(|(v, _)| v == name))
+				.map(|(_, rpm)| *rpm as f32)
+				.collect::<Vec<_>>();
+			let rpm = rpms.iter().sum::<f32>() / rpms.len() as f32;
+
+			debug!("{} settled at {:.0} RPM", name, rpm);
+
+			match curves.iter_mut().find(|v| &v.name == name) {
+				Some(curve) => curve.points.push(CurvePoint { duty: *duty, rpm }),
+				None => curves.push(FanCurve { name: name.clone(), points: vec!(CurvePoint { duty: *duty, rpm }) })
+			}
+		}
+	}
+
+	Ok(curves)
+}
+
+/// Prints the RPM of every fan at each duty and flags fans that look unhealthy.
+pub fn print_summary(calibration: &Calibration) {
+	let duties = calibration.fans.first()
+		.map(|v| v.points.iter().map(|v| v.duty).collect::<Vec<_>>())
+		.unwrap_or_default();
+
+	print!("{:<20}", "Fan");
+	for duty in duties.iter() {
+		print!(" {:>6}", format!("{:.0}%", duty * 100.0));
+	}
+	println!("  Status");
+
+	for fan in calibration.fans.iter() {
+		print!("{:<20}", fan.name);
+		for duty in duties.iter() {
+			match fan.points.iter().find(|v| v.duty == *duty) {
+				Some(point) => print!(" {:>6.0}", point.rpm),
+				None => print!(" {:>6}", "-")
+			}
+		}
+		println!("  {}", fan.problem().unwrap_or("ok"));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn curve(name: &str, points: &[(f32, f32)]) -> FanCurve {
+		FanCurve {
+			name: name.to_string(),
+			points: points.iter().map(|(duty, rpm)| CurvePoint { duty: *duty, rpm: *rpm }).collect()
+		}
+	}
+
+	#[test]
+	fn interpolates_curve() {
+		let fan = curve("FAN 1", &[(0.1, 2000.0), (0.5, 6000.0), (1.0, 12000.0)]);
+
+		assert_eq!(fan.expected(0.0), Some(2000.0));
+		assert_eq!(fan.expected(0.3), Some(4000.0));
+		assert_eq!(fan.expected(0.75), Some(9000.0));
+		assert_eq!(fan.expected(1.5), Some(12000.0));
+
+		assert_eq!(fan.health(0.5, 6000), Some(1.0));
+		assert_eq!(fan.health(0.5, 4500), Some(0.75));
+		assert_eq!(fan.health(0.5, 0), Some(0.0));
+		assert_eq!(fan.problem(), None);
+
+		assert_eq!(curve("FAN 2", &[]).expected(0.5), None);
+	}
+
+	#[test]
+	fn averages_healthy_fans() {
+		let calibration = Calibration {
+			timestamp: 0,
+			profile: "dell".to_string(),
+			fans: vec!(
+				curve("FAN 1", &[(0.1, 2000.0), (1.0, 12000.0)]),
+				curve("FAN 2", &[(0.1, 3000.0), (1.0, 13000.0)]),
+				curve("FAN 3", &[(0.1, 0.0), (1.0, 0.0)]),
+				curve("FAN 4", &[(0.1, 9000.0), (1.0, 9100.0)])
+			)
+		};
+
+		assert_eq!(calibration.fans[2].problem(), Some("not spinning"));
+		assert_eq!(calibration.fans[3].problem(), Some("not responding to duty"));
+		assert_eq!(calibration.average_curve(), vec!(
+			CurvePoint { duty: 0.1, rpm: 2500.0 },
+			CurvePoint { duty: 1.0, rpm: 12500.0 }
+		));
+
+		let path = ::std::env::temp_dir().join(format!("twd-calibration-{}", ::std::process::id())).join("calibration.json");
+		let path = path.to_str().unwrap();
+		calibration.save(path).unwrap();
+		assert_eq!(Calibration::load(path).unwrap(), calibration);
+	}
+}
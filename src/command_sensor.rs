@@ -0,0 +1,215 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ipmi::{IPMIValue, SdrEntry, SensorSource};
+use crate::ipmitool;
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Readings older than this many intervals are treated as missing.
+pub const DEFAULT_STALE_INTERVALS: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandConfig {
+	pub name: String,
+	pub program: String,
+	pub args: Vec<String>,
+	pub interval: Duration,
+	pub timeout: Duration,
+	pub stale: Duration,
+	/// JSON pointer to the temperature when the command prints JSON, I.E. "/gpu/temp".
+	pub field: Option<String>
+}
+
+/// Temperature read by running an external command at its own interval.
+///
+/// Commands run on their own thread so a slow script never holds up the control loop, the sensor reads
+/// as unknown(like a missing SDR entry) until the first reading and once the last reading goes stale.
+pub struct CommandSensor {
+	name: String,
+	stale: Duration,
+	reading: Arc<Mutex<Option<(i32, Instant)>>>,
+	/// Set once the command has run for the first time, whether or not it succeeded.
+	started: Arc<AtomicBool>,
+	/// Dropped along with the sensor, which stops its thread.
+	_stop: mpsc::Sender<()>
+}
+
+impl CommandSensor {
+	/// Runs the command for the first time on its thread, see `SensorSource::ready`.
+	pub fn start(config: CommandConfig) -> CommandSensor {
+		let reading = Arc::new(Mutex::new(None));
+		let started = Arc::new(AtomicBool::new(false));
+		let (stop, stopped) = mpsc::channel::<()>();
+
+		let name = config.name.clone();
+		let stale = config.stale;
+
+		let thread_reading = reading.clone();
+		let thread_started = started.clone();
+		thread::spawn(move || {
+			update(&config, &thread_reading);
+			thread_started.store(true, Ordering::SeqCst);
+
+			while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(config.interval) {
+				update(&config, &thread_reading);
+			}
+
+			trace!("Stopped reading {}", config.name);
+		});
+
+		CommandSensor {
+			name,
+			stale,
+			reading,
+			started,
+			_stop: stop
+		}
+	}
+
+	pub fn value(&self) -> IPMIValue {
+		let reading = *self.reading.lock().unwrap_or_else(|e| e.into_inner());
+
+		match reading {
+			Some((temp, at)) if at.elapsed() <= self.stale => IPMIValue::Temp(temp),
+			Some((_, at)) => {
+				debug!("Last reading of {} is {}s old", self.name, at.elapsed().as_secs());
+				IPMIValue::Unknown
+			},
+			None => IPMIValue::Unknown
+		}
+	}
+}
+
+impl SensorSource for CommandSensor {
+	fn read(&mut self) -> Vec<SdrEntry> {
+		vec!(SdrEntry {
+			name: self.name.clone(),
+			number: None,
+			entity: None,
+			value: self.value()
+		})
+	}
+
+	fn ready(&self) -> bool {
+		self.started.load(Ordering::SeqCst)
+	}
+
+	fn seed(&mut self, previous: &[SdrEntry]) {
+		let mut reading = self.reading.lock().unwrap_or_else(|e| e.into_inner());
+
+		// Only fresh readings make it into a pass, so the previous one is treated as just taken
+		if let (None, Some(IPMIValue::Temp(temp))) = (*reading, previous.iter().find(|v| v.name == self.name).map(|v| &v.value)) {
+			*reading = Some((*temp, Instant::now()));
+		}
+	}
+}
+
+fn update(config: &CommandConfig, reading: &Mutex<Option<(i32, Instant)>>) {
+	match run(config) {
+		Ok(temp) => {
+			trace!("Read {} for {}", temp, config.name);
+			*reading.lock().unwrap_or_else(|e| e.into_inner()) = Some((temp, Instant::now()));
+		},
+		// The previous reading is kept until it goes stale
+		Err(e) => warn!("Unable to read {}: {}", config.name, e)
+	}
+}
+
+fn run(config: &CommandConfig) -> Result<i32, String> {
+	let args = config.args.iter().map(|v| v.as_str()).collect::<Vec<_>>();
+	let output = ipmitool::run_process(&config.program, &args, config.timeout)
+		.map_err(|e| e.to_string())?;
+
+	if !output.success {
+		return Err(format!("{} failed, {}", config.program, output.stderr.trim()))
+	}
+
+	parse_output(&output.stdout, config.field.as_deref())
+}
+
+/// Reads a plain number or, with `field`, a JSON pointer into the output.
+fn parse_output(stdout: &str, field: Option<&str>) -> Result<i32, String> {
+	let stdout = stdout.trim();
+
+	let value = match field {
+		Some(field) => {
+			let json: serde_json::Value = serde_json::from_str(stdout).map_err(|e| format!("invalid JSON output, {}", e))?;
+
+			json.pointer(field)
+				.and_then(|v| v.as_f64())
+				.ok_or_else(|| format!("no number at {} in output", field))?
+		},
+		None => stdout.parse::<f64>().map_err(|_| format!("expected a number, got \"{}\"", stdout))?
+	};
+
+	if !value.is_finite() {
+		return Err(format!("{} is not a temperature", value))
+	}
+
+	Ok(value.round() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(script: &str) -> CommandConfig {
+		CommandConfig {
+			name: "GPU".to_string(),
+			program: "/bin/sh".to_string(),
+			args: vec!("-c".to_string(), script.to_string()),
+			interval: Duration::from_millis(50),
+			timeout: Duration::from_millis(500),
+			stale: Duration::from_millis(300),
+			field: None
+		}
+	}
+
+	#[test]
+	fn parses_output() {
+		assert_eq!(parse_output("41.6\n", None), Ok(42));
+		assert_eq!(parse_output(r#"{"gpu": {"temp": 67}}"#, Some("/gpu/temp")), Ok(67));
+		assert!(parse_output(r#"{"gpu": {}}"#, Some("/gpu/temp")).is_err());
+		assert!(parse_output("hot", None).is_err());
+	}
+
+	fn wait_ready(sensor: &CommandSensor) {
+		let started = Instant::now();
+		while !sensor.ready() && started.elapsed() < Duration::from_secs(5) {
+			thread::sleep(Duration::from_millis(10));
+		}
+	}
+
+	#[test]
+	fn reads_command_until_stale() {
+		let mut sensor = CommandSensor::start(config("echo 45"));
+		wait_ready(&sensor);
+		assert_eq!(sensor.read()[0].value, IPMIValue::Temp(45));
+
+		// Succeeds once, then keeps failing so the reading goes stale
+		let marker = ::std::env::temp_dir().join(format!("twd-command-{}", ::std::process::id()));
+		::std::fs::remove_file(&marker).unwrap_or(());
+		let script = format!("test -f {0} && exit 1; touch {0}; echo 50", marker.display());
+
+		let sensor = CommandSensor::start(config(&script));
+		wait_ready(&sensor);
+		assert_eq!(sensor.value(), IPMIValue::Temp(50));
+		thread::sleep(Duration::from_millis(500));
+		assert_eq!(sensor.value(), IPMIValue::Unknown);
+		::std::fs::remove_file(&marker).unwrap_or(());
+
+		// A hung command never holds up starting the sensor, which reads as unknown until it has a reading
+		let started = Instant::now();
+		let mut sensor = CommandSensor::start(config("sleep 5; echo 30"));
+		assert!(started.elapsed() < Duration::from_millis(100));
+		assert!(!sensor.ready());
+		assert_eq!(sensor.value(), IPMIValue::Unknown);
+
+		// On reload it starts from the reading of the sensor it replaces
+		sensor.seed(&[SdrEntry { name: "GPU".to_string(), number: None, entity: None, value: IPMIValue::Temp(61) }]);
+		assert_eq!(sensor.value(), IPMIValue::Temp(61));
+	}
+}
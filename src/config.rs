@@ -0,0 +1,1230 @@
+use crate::mqtt;
+use crate::web;
+use crate::ipmi::SensorSelector;
+use crate::ipmitool;
+use crate::vendor::FanProfile;
+use crate::verify::VerifySettings;
+use crate::calibration::{self, Calibration, CurvePoint};
+use crate::feedforward::{FeedForward, Mapping};
+use crate::aggregate::Aggregation;
+use crate::schedule::{self, Period};
+use crate::expr::Expr;
+use crate::virtual_sensors::VirtualSensor;
+use crate::control::AmbientCompensation;
+use crate::drives::{self, DriveConfig, DriveGroup, GroupAggregate};
+use crate::command_sensor::{self, CommandConfig};
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Deserialize)]
+pub struct AppConfig {
+	pub metrics: Option<AppMetricConfig>,
+	pub mqtt: Option<mqtt::MqttConfig>,
+	pub socket: Option<AppSocketConfig>,
+	pub web: Option<web::WebConfig>,
+	pub ipmi: Option<AppIpmiConfig>,
+	pub fans: Option<AppFanConfig>,
+	pub pid: Option<AppPIDConfig>,
+	pub schedule: Option<AppScheduleConfig>,
+	pub drives: Option<AppDrivesConfig>,
+	pub command_sensors: Option<Vec<AppCommandSensorConfig>>,
+	pub virtual_sensors: Option<Vec<AppVirtualSensorConfig>>,
+	pub controls: Option<Vec<AppControlConfig>>
+}
+
+#[derive(Deserialize)]
+pub struct AppMetricConfig {
+	pub influx_addr: String,
+	pub influx_db: String,
+	pub influx_user: Option<String>,
+	pub influx_pw: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AppSocketConfig {
+	pub enabled: Option<bool>,
+	pub path: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct AppIpmiConfig {
+	/// Keep a single `ipmitool shell` running instead of starting ipmitool for every command.
+	pub session: Option<bool>,
+	pub read_timeout_ms: Option<u64>,
+	pub write_timeout_ms: Option<u64>,
+	pub retries: Option<u32>,
+	pub retry_delay_ms: Option<u64>
+}
+
+#[derive(Deserialize)]
+pub struct AppFanConfig {
+	/// "auto", "dell", "supermicro" or "custom", defaults to "custom" if raw commands are listed and "auto" otherwise.
+	pub profile: Option<String>,
+	/// Raw commands for a custom profile, I.E. "0x30 0x30 0x02 0xff {duty}".
+	pub manual: Option<Vec<String>>,
+	pub automatic: Option<Vec<String>>,
+	pub speed: Option<Vec<String>>,
+	pub duty_max: Option<u8>,
+	/// Periodically confirm fans follow the commanded duty and re-assert manual control if they don't.
+	pub verify: Option<bool>,
+	pub verify_interval_secs: Option<u64>,
+	/// Average fan RPM at 0% and 100% duty.
+	pub rpm_min: Option<f32>,
+	pub rpm_max: Option<f32>,
+	pub rpm_tolerance: Option<f32>,
+	/// State file written by the calibrate subcommand.
+	pub calibration: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct AppScheduleConfig {
+	/// Seconds taken to ramp between periods.
+	pub ramp_secs: Option<u64>,
+	/// Checked in order, the first active period applies.
+	pub periods: Option<Vec<AppPeriodConfig>>
+}
+
+#[derive(Deserialize)]
+pub struct AppPeriodConfig {
+	pub name: String,
+	/// "mon" to "sun", every day if omitted.
+	pub days: Option<Vec<String>>,
+	/// "HH:MM" local time.
+	pub start: String,
+	pub end: String,
+	/// Setpoints by control label, I.E. "Temp 3.1".
+	pub setpoints: Option<HashMap<String, f32>>,
+	/// Duty from 0 to 100, like `min` in [pid].
+	pub min: Option<usize>,
+	pub max: Option<usize>
+}
+
+pub const DEFAULT_SCHEDULE_RAMP_SECS: u64 = 300;
+
+/// NVMe and SATA/SAS drive temperatures, each drive and group can be watched by name like any other sensor.
+#[derive(Deserialize)]
+pub struct AppDrivesConfig {
+	/// Devices read with smartctl rather than hwmon, I.E. "/dev/sda".
+	pub smartctl: Option<Vec<String>>,
+	pub smartctl_interval_secs: Option<u64>,
+	pub groups: Option<Vec<AppDriveGroupConfig>>
+}
+
+#[derive(Deserialize)]
+pub struct AppDriveGroupConfig {
+	pub name: String,
+	/// Drive names, I.E. "nvme0" or "sda", every drive found if omitted.
+	pub drives: Option<Vec<String>>,
+	/// "max" or "avg", defaults to "max".
+	pub aggregate: Option<String>
+}
+
+/// Temperature printed by an external command, watched by `name` like any other sensor.
+#[derive(Deserialize)]
+pub struct AppCommandSensorConfig {
+	pub name: String,
+	/// Program followed by its arguments, I.E. ["/usr/local/bin/gpu-temp", "--json"].
+	pub command: Vec<String>,
+	pub interval_secs: Option<u64>,
+	pub timeout_ms: Option<u64>,
+	/// Age after which the last reading is treated as missing, defaults to three intervals.
+	pub stale_secs: Option<u64>,
+	/// JSON pointer to the temperature when the command prints JSON, I.E. "/gpu/temp".
+	pub field: Option<String>
+}
+
+/// Temperature computed from other sensors that controls can watch by `name`.
+#[derive(Deserialize)]
+pub struct AppVirtualSensorConfig {
+	pub name: String,
+	/// I.E. "exhaust - inlet", "max(cpu1, cpu2)" or "avg(cpu1, cpu2)".
+	pub expr: String,
+	/// Sensor read by each variable in `expr`.
+	pub inputs: HashMap<String, AppSensorRef>
+}
+
+#[derive(Deserialize)]
+pub struct AppSensorRef {
+	pub name: String,
+	pub sensor: Option<String>,
+	pub entity: Option<String>
+}
+
+#[derive(Deserialize)]
+pub struct AppPIDConfig {
+	pub k_factor: f32,
+	pub i_factor: f32,
+	pub d_factor: f32,
+	pub filter_points: Option<usize>,
+	pub min: Option<usize>,
+	/// Maximum fan duty(0-100).
+	pub max: Option<usize>,
+	/// Only allow duty above `max` while a control is past its emergency threshold, tracking how often it happens.
+	pub acoustic_budget: Option<bool>,
+	/// How outputs of every control are combined: "max", "weighted_sum", "priority" or "clamped_sum".
+	pub aggregation: Option<String>
+}
+
+#[derive(Deserialize,Clone)]
+pub struct AppControlConfig {
+	pub name: String,
+	/// Sensor number from `ipmitool sdr elist`, I.E. "0Eh".
+	pub sensor: Option<String>,
+	/// Entity id and instance from `ipmitool sdr elist`, I.E. "3.1".
+	pub entity: Option<String>,
+	pub setpoint: f32,
+	/// Defaults to a margin below the BMC's upper threshold for the sensor when omitted.
+	pub failsafe: Option<f32>,
+	pub feed_forward: Option<AppFeedForwardConfig>,
+	/// Scale for this control's output when aggregating with "weighted_sum", defaults to 1.0.
+	pub weight: Option<f32>,
+	/// Temperature above which fans may exceed the duty cap in acoustic budget mode.
+	pub emergency: Option<f32>,
+	pub ambient: Option<AppAmbientConfig>
+}
+
+/// Moves a control's setpoint by `gain` for every degree `sensor` is away from `reference`.
+#[derive(Deserialize,Clone)]
+pub struct AppAmbientConfig {
+	/// Sensor to follow, selected like a control.
+	pub name: String,
+	pub sensor: Option<String>,
+	pub entity: Option<String>,
+	pub reference: f32,
+	pub gain: f32,
+	/// Range the compensated setpoint is clamped to, `max` must be below the failsafe.
+	pub min: f32,
+	pub max: f32
+}
+
+/// Duty added to a control's output from CPU load, either `gain` or `curve` must be set.
+#[derive(Deserialize,Clone)]
+pub struct AppFeedForwardConfig {
+	/// Duty added per unit of CPU load(0.0-1.0).
+	pub gain: Option<f32>,
+	/// [load, duty] points, both from 0.0 to 1.0.
+	pub curve: Option<Vec<(f32, f32)>>,
+	/// Seconds of load rise to anticipate.
+	pub lead_secs: Option<f32>,
+	/// Time constant in seconds for the term to fall once load drops.
+	pub decay_secs: Option<f32>
+}
+
+pub const DEFAULT_FF_LEAD_SECS: f32 = 5.0;
+pub const DEFAULT_FF_DECAY_SECS: f32 = 30.0;
+
+impl AppControlConfig {
+	pub fn selector(&self) -> Result<SensorSelector, String> {
+		SensorSelector::new(&self.name, self.sensor.as_deref(), self.entity.as_deref())
+	}
+
+	pub fn ambient(&self) -> Result<Option<AmbientCompensation>, String> {
+		let config = match self.ambient.as_ref() {
+			Some(v) => v,
+			None => return Ok(None)
+		};
+
+		if ![config.reference, config.gain, config.min, config.max].iter().all(|v| v.is_finite()) || config.min > config.max {
+			return Err(format!("ambient for {} needs finite values with min at or below max", self.name))
+		}
+
+		// Checked against the failsafe by `build_controls`, which may only learn it from BMC thresholds
+		Ok(Some(AmbientCompensation {
+			selector: SensorSelector::new(&config.name, config.sensor.as_deref(), config.entity.as_deref())?,
+			reference: config.reference,
+			gain: config.gain,
+			min: config.min,
+			max: config.max
+		}))
+	}
+
+	pub fn feed_forward(&self) -> Result<Option<FeedForward>, String> {
+		let config = match self.feed_forward.as_ref() {
+			Some(v) => v,
+			None => return Ok(None)
+		};
+
+		let mapping = match (config.gain, config.curve.as_ref()) {
+			(Some(gain), None) if gain.is_finite() && gain >= 0.0 => Mapping::Gain(gain),
+			(Some(gain), None) => return Err(format!("feed_forward gain of {} for {} must be 0 or above", gain, self.name)),
+			(None, Some(curve)) => {
+				if curve.is_empty() || curve.iter().any(|(load, duty)| !(0.0..=1.0).contains(load) || !(0.0..=1.0).contains(duty)) {
+					return Err(format!("feed_forward curve for {} needs points with load and duty from 0.0 to 1.0", self.name))
+				}
+
+				if curve.windows(2).any(|v| v[1].0 <= v[0].0) {
+					return Err(format!("feed_forward curve for {} must be sorted by load", self.name))
+				}
+
+				Mapping::Curve(curve.clone())
+			},
+			_ => return Err(format!("feed_forward for {} needs exactly one of gain or curve", self.name))
+		};
+
+		let lead = config.lead_secs.unwrap_or(DEFAULT_FF_LEAD_SECS);
+		let decay = config.decay_secs.unwrap_or(DEFAULT_FF_DECAY_SECS);
+		if !(lead.is_finite() && lead >= 0.0 && decay.is_finite() && decay >= 0.0) {
+			return Err(format!("feed_forward lead_secs and decay_secs for {} must be 0 or above", self.name))
+		}
+
+		Ok(Some(FeedForward::new(mapping, lead, decay)))
+	}
+}
+
+impl AppConfig {
+	fn empty() -> AppConfig {
+		AppConfig {
+			metrics: None,
+			mqtt: None,
+			socket: None,
+			web: None,
+			ipmi: None,
+			fans: None,
+			pid: None,
+			schedule: None,
+			drives: None,
+			command_sensors: None,
+			virtual_sensors: None,
+			controls: None
+		}
+	}
+
+	/// Configured controls, or the R710 defaults if none are listed.
+	pub fn controls(&self) -> Vec<AppControlConfig> {
+		if let Some(controls) = self.controls.as_ref() {
+			controls.clone()
+		} else {
+			vec!(
+				AppControlConfig {
+					name: "Exhaust Temp".to_string(),
+					sensor: None,
+					entity: None,
+					setpoint: 40.0,
+					failsafe: Some(60.0),
+					feed_forward: None,
+					weight: None,
+					emergency: None,
+					ambient: None
+				},
+				AppControlConfig {
+					name: "Temp".to_string(),
+					sensor: None,
+					entity: Some("3.1".to_string()),
+					setpoint: 55.0,
+					failsafe: Some(65.0),
+					feed_forward: None,
+					weight: None,
+					emergency: None,
+					ambient: None
+				},
+				AppControlConfig {
+					name: "Temp".to_string(),
+					sensor: None,
+					entity: Some("3.2".to_string()),
+					setpoint: 55.0,
+					failsafe: Some(65.0),
+					feed_forward: None,
+					weight: None,
+					emergency: None,
+					ambient: None
+				}
+			)
+		}
+	}
+
+	pub fn pid_settings(&self) -> (f32,f32,f32) {
+		self.pid.as_ref()
+			.map(|v| (v.k_factor, v.i_factor, v.d_factor))
+			.unwrap_or((0.05, 0.000001, 0.0))
+	}
+
+	pub fn aggregation(&self) -> Result<Aggregation, String> {
+		match self.pid.as_ref().and_then(|v| v.aggregation.as_ref()) {
+			Some(name) => Aggregation::parse(name),
+			None => Ok(Aggregation::Max)
+		}
+	}
+
+	pub fn filter_points(&self) -> usize {
+		self.pid.as_ref().and_then(|v| v.filter_points).unwrap_or(5)
+	}
+
+	pub fn ipmi_policy(&self) -> ipmitool::Policy {
+		let ms = |v: Option<u64>, default| v.map(Duration::from_millis).unwrap_or(default);
+
+		match self.ipmi.as_ref() {
+			Some(ipmi) => ipmitool::Policy {
+				persistent: ipmi.session.unwrap_or(false),
+				read_timeout: ms(ipmi.read_timeout_ms, ipmitool::DEFAULT_READ_TIMEOUT),
+				write_timeout: ms(ipmi.write_timeout_ms, ipmitool::DEFAULT_WRITE_TIMEOUT),
+				retries: ipmi.retries.unwrap_or(ipmitool::DEFAULT_RETRIES),
+				retry_delay: ms(ipmi.retry_delay_ms, ipmitool::DEFAULT_RETRY_DELAY)
+			},
+			None => ipmitool::DEFAULT_POLICY
+		}
+	}
+
+	/// Longest a single pass of the main loop can spend on IPMI commands if every attempt times out.
+	///
+	/// The slowest pass reads sensors, takes manual control and sets the duty, has the verifier re-assert both and
+	/// then restores BMC control when that fails. One that fails to read restores BMC control, and again when that fails.
+	/// Reloads add nothing, they reuse the BMC thresholds read at startup and check controls against the last readings.
+	pub fn worst_case_pass(&self) -> Result<Duration, String> {
+		let policy = self.ipmi_policy();
+		let read = policy.worst_case(ipmitool::Op::ReadSensors);
+		let mode = policy.worst_case(ipmitool::Op::FanMode);
+		let speed = policy.worst_case(ipmitool::Op::FanSpeed);
+		let asserts = if self.verify_settings(None).is_some() { 2 } else { 1 };
+
+		// A detected profile is one of the built-in ones
+		let profiles = match self.fan_profile()? {
+			Some(profile) => vec!(profile),
+			None => vec!(FanProfile::dell(), FanProfile::supermicro())
+		};
+
+		Ok(profiles.iter()
+			.map(|profile| {
+				let enable = mode * profile.manual.len() as u32 + speed * profile.speed.len() as u32;
+				let restore = mode * profile.automatic.len() as u32;
+
+				(read + enable * asserts + restore).max(read + restore * 2)
+			})
+			.max()
+			.unwrap_or(read))
+	}
+
+	/// Configured fan profile, None if it should be detected from the BMC.
+	pub fn fan_profile(&self) -> Result<Option<FanProfile>, String> {
+		let fans = match self.fans.as_ref() {
+			Some(v) => v,
+			None => return Ok(None)
+		};
+
+		let has_commands = fans.manual.is_some() || fans.automatic.is_some() || fans.speed.is_some();
+		let profile = fans.profile.as_deref().unwrap_or(if has_commands { "custom" } else { "auto" });
+
+		if has_commands && profile != "custom" {
+			return Err(format!("raw commands in [fans] are only used with profile = \"custom\", not \"{}\"", profile))
+		}
+
+		match profile {
+			"auto" => Ok(None),
+			"custom" => {
+				let empty = vec!();
+				FanProfile::custom(
+					fans.manual.as_ref().unwrap_or(&empty),
+					fans.automatic.as_ref().unwrap_or(&empty),
+					fans.speed.as_ref().unwrap_or(&empty),
+					fans.duty_max.unwrap_or(100)).map(Some)
+			},
+			name => FanProfile::builtin(name)
+				.map(Some)
+				.ok_or_else(|| format!("unknown fan profile \"{}\", expected auto, dell, supermicro or custom", name))
+		}
+	}
+
+	pub fn calibration_path(&self) -> String {
+		self.fans.as_ref()
+			.and_then(|v| v.calibration.clone())
+			.unwrap_or_else(|| calibration::DEFAULT_PATH.to_string())
+	}
+
+	/// Fan verification settings, None if disabled. A configured RPM range takes precedence over calibrated curves.
+	pub fn verify_settings(&self, calibration: Option<&Calibration>) -> Option<VerifySettings> {
+		let fans = self.fans.as_ref();
+
+		if !fans.and_then(|v| v.verify).unwrap_or(true) {
+			return None
+		}
+
+		let interval = Duration::from_secs(fans.and_then(|v| v.verify_interval_secs).unwrap_or(30));
+
+		Some(VerifySettings {
+			interval,
+			settle: interval / 2,
+			curve: fans.and_then(|v| v.rpm_min.zip(v.rpm_max))
+				.map(|(min, max)| vec!(CurvePoint { duty: 0.0, rpm: min }, CurvePoint { duty: 1.0, rpm: max }))
+				.or_else(|| calibration.map(|v| v.average_curve()).filter(|v| !v.is_empty())),
+			tolerance: fans.and_then(|v| v.rpm_tolerance).unwrap_or(0.25)
+		})
+	}
+
+	pub fn min_speed(&self) -> f32 {
+		self.pid.as_ref().and_then(|v| v.min).unwrap_or(0) as f32 / 100.0
+	}
+
+	pub fn max_speed(&self) -> f32 {
+		self.pid.as_ref().and_then(|v| v.max).unwrap_or(100) as f32 / 100.0
+	}
+
+	pub fn acoustic_budget(&self) -> bool {
+		self.pid.as_ref().and_then(|v| v.acoustic_budget).unwrap_or(false)
+	}
+
+	pub fn schedule_ramp(&self) -> Duration {
+		Duration::from_secs(self.schedule.as_ref().and_then(|v| v.ramp_secs).unwrap_or(DEFAULT_SCHEDULE_RAMP_SECS))
+	}
+
+	pub fn schedule_periods(&self) -> Result<Vec<Period>, String> {
+		let periods = match self.schedule.as_ref().and_then(|v| v.periods.as_ref()) {
+			Some(v) => v,
+			None => return Ok(vec!())
+		};
+
+		periods.iter()
+			.map(|period| {
+				let days = period.days.iter()
+					.flatten()
+					.map(|v| schedule::parse_day(v))
+					.collect::<Result<Vec<_>, _>>()
+					.map_err(|e| format!("{} in schedule {}", e, period.name))?;
+
+				let mut setpoints = period.setpoints.iter()
+					.flatten()
+					.map(|(label, setpoint)| (label.clone(), *setpoint))
+					.collect::<Vec<_>>();
+				setpoints.sort_by(|a, b| a.0.cmp(&b.0));
+
+				Ok(Period {
+					name: period.name.clone(),
+					days,
+					start: schedule::parse_time(&period.start).map_err(|e| format!("{} in schedule {}", e, period.name))?,
+					end: schedule::parse_time(&period.end).map_err(|e| format!("{} in schedule {}", e, period.name))?,
+					setpoints,
+					min: period.min.map(|v| v as f32 / 100.0),
+					max: period.max.map(|v| v as f32 / 100.0)
+				})
+			})
+			.collect()
+	}
+
+	fn validate_schedule(&self) -> Result<(), String> {
+		let periods = self.schedule_periods()?;
+		let controls = self.controls();
+
+		for (idx, period) in periods.iter().enumerate() {
+			if periods[..idx].iter().any(|v| v.name == period.name) {
+				return Err(format!("more than one schedule is named {}", period.name))
+			}
+
+			let min = period.min.unwrap_or_else(|| self.min_speed());
+			let max = period.max.unwrap_or_else(|| self.max_speed());
+			if max > 1.0 || min > max {
+				return Err(format!("schedule {} needs min and max from 0 to 100 with min at or below max", period.name))
+			}
+
+			for (label, setpoint) in period.setpoints.iter() {
+				let control = controls.iter()
+					.find(|v| v.selector().map(|v| &v.label() == label).unwrap_or(false))
+					.ok_or_else(|| format!("schedule {} has a setpoint for {} which isn't a control", period.name, label))?;
+
+				if !setpoint.is_finite() || control.failsafe.map(|v| *setpoint >= v).unwrap_or(false) {
+					return Err(format!("schedule {} setpoint of {} for {} must be below its failsafe", period.name, setpoint, label))
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn drives(&self) -> Result<Option<DriveConfig>, String> {
+		let config = match self.drives.as_ref() {
+			Some(v) => v,
+			None => return Ok(None)
+		};
+
+		let mut groups: Vec<DriveGroup> = vec!();
+
+		for group in config.groups.iter().flatten() {
+			if groups.iter().any(|v| v.name == group.name) {
+				return Err(format!("more than one drive group is named {}", group.name))
+			}
+
+			let aggregate = match group.aggregate.as_deref() {
+				None | Some("max") => GroupAggregate::Max,
+				Some("avg") => GroupAggregate::Avg,
+				Some(v) => return Err(format!("unknown aggregate \"{}\" for drive group {}, expected max or avg", v, group.name))
+			};
+
+			groups.push(DriveGroup {
+				name: group.name.clone(),
+				drives: group.drives.clone().unwrap_or_default(),
+				aggregate
+			});
+		}
+
+		let interval = match config.smartctl_interval_secs {
+			Some(0) => return Err("drives smartctl_interval_secs must be above 0".to_string()),
+			Some(v) => Duration::from_secs(v),
+			None => drives::DEFAULT_SMARTCTL_INTERVAL
+		};
+
+		Ok(Some(DriveConfig {
+			smartctl: config.smartctl.clone().unwrap_or_default(),
+			smartctl_interval: interval,
+			groups
+		}))
+	}
+
+	pub fn command_sensors(&self) -> Result<Vec<CommandConfig>, String> {
+		let mut sensors: Vec<CommandConfig> = vec!();
+
+		for config in self.command_sensors.iter().flatten() {
+			if sensors.iter().any(|v| v.name == config.name) {
+				return Err(format!("more than one command sensor is named {}", config.name))
+			}
+
+			let (program, args) = config.command.split_first()
+				.ok_or_else(|| format!("command sensor {} needs a command", config.name))?;
+
+			let interval = config.interval_secs.map(Duration::from_secs).unwrap_or(command_sensor::DEFAULT_INTERVAL);
+			let timeout = config.timeout_ms.map(Duration::from_millis).unwrap_or(command_sensor::DEFAULT_TIMEOUT);
+			let stale = config.stale_secs.map(Duration::from_secs).unwrap_or(interval * command_sensor::DEFAULT_STALE_INTERVALS);
+
+			if interval.as_secs() == 0 || timeout.as_millis() == 0 {
+				return Err(format!("command sensor {} needs an interval and timeout above 0", config.name))
+			}
+
+			if stale <= interval {
+				return Err(format!("stale_secs for command sensor {} must be longer than its interval", config.name))
+			}
+
+			if let Some(field) = config.field.as_ref() {
+				if !field.starts_with('/') {
+					return Err(format!("field \"{}\" for command sensor {} must be a JSON pointer like \"/temp\"", field, config.name))
+				}
+			}
+
+			sensors.push(CommandConfig {
+				name: config.name.clone(),
+				program: program.clone(),
+				args: args.to_vec(),
+				interval,
+				timeout,
+				stale,
+				field: config.field.clone()
+			});
+		}
+
+		Ok(sensors)
+	}
+
+	pub fn virtual_sensors(&self) -> Result<Vec<VirtualSensor>, String> {
+		let configs = match self.virtual_sensors.as_ref() {
+			Some(v) => v,
+			None => return Ok(vec!())
+		};
+
+		let mut sensors: Vec<VirtualSensor> = vec!();
+
+		for config in configs {
+			if sensors.iter().any(|v| v.name == config.name) {
+				return Err(format!("more than one virtual sensor is named {}", config.name))
+			}
+
+			let expr = Expr::parse(&config.expr).map_err(|e| format!("invalid expr for {}: {}", config.name, e))?;
+
+			let mut inputs = vec!();
+			for var in expr.vars() {
+				let input = config.inputs.get(var)
+					.ok_or_else(|| format!("expr for {} uses {} which isn't listed in its inputs", config.name, var))?;
+
+				inputs.push((var.to_string(), SensorSelector::new(&input.name, input.sensor.as_deref(), input.entity.as_deref())?));
+			}
+
+			sensors.push(VirtualSensor {
+				name: config.name.clone(),
+				expr,
+				inputs
+			});
+		}
+
+		Ok(sensors)
+	}
+
+	/// Checks for values that parse but would make the control loop misbehave.
+	pub fn validate(&self) -> Result<(), String> {
+		let (k, i, d) = self.pid_settings();
+		if !k.is_finite() || !i.is_finite() || !d.is_finite() {
+			return Err("pid factors must be finite".to_string())
+		}
+
+		if let Some(min) = self.pid.as_ref().and_then(|v| v.min) {
+			if min > 100 {
+				return Err(format!("pid min of {} is above 100", min))
+			}
+		}
+
+		if let Some(max) = self.pid.as_ref().and_then(|v| v.max) {
+			if max > 100 || self.min_speed() > self.max_speed() {
+				return Err(format!("pid max of {} must be at most 100 and not below min", max))
+			}
+		}
+
+		if self.acoustic_budget() && self.pid.as_ref().and_then(|v| v.max).is_none() {
+			return Err("acoustic_budget needs a pid max to budget against".to_string())
+		}
+
+		self.aggregation()?;
+		self.fan_profile()?;
+		self.validate_schedule()?;
+		self.drives()?;
+		self.command_sensors()?;
+		self.virtual_sensors()?;
+
+		if let Some(web) = self.web.as_ref() {
+			web.validate()?;
+		}
+
+		if let Some(fans) = self.fans.as_ref() {
+			match (fans.rpm_min, fans.rpm_max) {
+				(Some(min), Some(max)) if !(min.is_finite() && max.is_finite() && min >= 0.0 && min < max) => {
+					return Err(format!("fan rpm_min of {} must be below rpm_max of {}", min, max))
+				},
+				(Some(_), None) | (None, Some(_)) => return Err("fan rpm_min and rpm_max must be set together".to_string()),
+				_ => ()
+			}
+
+			if let Some(tolerance) = fans.rpm_tolerance {
+				if !(tolerance > 0.0 && tolerance.is_finite()) {
+					return Err(format!("fan rpm_tolerance of {} must be above 0", tolerance))
+				}
+			}
+
+			if fans.verify_interval_secs == Some(0) {
+				return Err("fan verify_interval_secs must be above 0".to_string())
+			}
+		}
+
+		let policy = self.ipmi_policy();
+		if policy.read_timeout.as_millis() == 0 || policy.write_timeout.as_millis() == 0 {
+			return Err("ipmi timeouts must be above 0".to_string())
+		}
+
+		let worst_case = self.worst_case_pass()?;
+		if worst_case >= ipmitool::WATCHDOG {
+			return Err(format!("ipmi timeouts and retries allow a single pass to take {}s, which would trip the {}s systemd watchdog", worst_case.as_secs_f32(), ipmitool::WATCHDOG.as_secs()))
+		}
+
+		if self.controls.as_ref().map(|v| v.is_empty()).unwrap_or(false) {
+			return Err("at least one control is required".to_string())
+		}
+
+		let controls = self.controls();
+
+		for (idx, control) in controls.iter().enumerate() {
+			let selector = control.selector()?;
+
+			if controls[..idx].iter().any(|v| v.selector().ok() == Some(selector.clone())) {
+				return Err(format!("more than one control watches {}, add \"sensor\" or \"entity\" from \"ipmitool sdr elist\" to tell them apart", selector.label()))
+			}
+
+			if !control.setpoint.is_finite() || !control.failsafe.map(|v| v.is_finite()).unwrap_or(true) {
+				return Err(format!("setpoint and failsafe of {} must be finite", selector.label()))
+			}
+
+			control.feed_forward()?;
+			control.ambient()?;
+
+			if let Some(emergency) = control.emergency {
+				if !(emergency.is_finite() && emergency > control.setpoint && control.failsafe.map(|v| emergency < v).unwrap_or(true)) {
+					return Err(format!("emergency of {} for {} must be above its setpoint and below its failsafe", emergency, selector.label()))
+				}
+			}
+
+			if let Some(weight) = control.weight {
+				if !(weight.is_finite() && weight >= 0.0) {
+					return Err(format!("weight of {} for {} must be 0 or above", weight, selector.label()))
+				}
+			}
+
+			if let Some(failsafe) = control.failsafe {
+				if control.setpoint >= failsafe {
+					return Err(format!("setpoint of {} is {} which is not below its failsafe of {}", selector.label(), control.setpoint, failsafe))
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+pub fn load_config(path: &str) -> Result<AppConfig, String> {
+	::std::fs::File::open(path)
+		.map_err(|e| format!("Unable to open config file: {:?}", e))
+		.and_then(|v| {
+				let mut content = String::new();
+				let mut buf = ::std::io::BufReader::new(v);
+				use ::std::io::Read;
+				buf.read_to_string(&mut content)
+					.map_err(|e| format!("Unable to read config file: {:?}", e))?;
+				Ok(content)
+			})
+		.and_then(|v| parse_toml(v.as_str()))
+}
+
+pub fn parse_toml(content: &str) -> Result<AppConfig, String> {
+	toml::from_str(content)
+		.map_err(|e| format!("Unable to parse toml: {:?}", e))
+}
+
+/// Replaces the config file through a rename so a crash never leaves it half written.
+///
+/// `content` is generated rather than hand written, so comments in the file are lost. The previous file is kept
+/// as `<path>.bak` and a note at the top of the new one points to it.
+pub fn save_config(path: &str, content: &str) -> Result<(), String> {
+	use ::std::io::Write;
+	use ::std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+	let temp = format!("{}.tmp", path);
+	let backup = format!("{}.bak", path);
+
+	// The file may hold passwords, so it's created with the mode of the original rather than the umask default
+	let mode = ::std::fs::metadata(path)
+		.map(|v| v.permissions().mode() & 0o777)
+		.unwrap_or(0o600);
+
+	let header = format!("# Written by the Thermal Watchdog web API, comments were not kept. The previous file is {}\n\n", backup);
+
+	::std::fs::remove_file(&temp)
+		.or_else(|e| if e.kind() == ::std::io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+		.and_then(|_| ::std::fs::OpenOptions::new().write(true).create_new(true).mode(mode).open(&temp))
+		.and_then(|mut file| {
+			file.write_all(header.as_bytes())?;
+			file.write_all(content.as_bytes())?;
+			file.sync_all()
+		})
+		.and_then(|_| match ::std::fs::copy(path, &backup) {
+			Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => Ok(()),
+			v => v.map(|_| ())
+		})
+		.and_then(|_| ::std::fs::rename(&temp, path))
+		.map_err(|e| format!("Unable to save config file: {:?}", e))
+}
+
+pub fn parse_config(path: &str) -> AppConfig {
+	info!("Loading config file at {}", path);
+
+	match load_config(path) {
+		Ok(v) => v,
+		Err(e) => {
+			info!("{}", e);
+			AppConfig::empty()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(content: &str) -> AppConfig {
+		toml::from_str(content).unwrap()
+	}
+
+	#[test]
+	fn defaults_are_valid() {
+		assert!(AppConfig::empty().validate().is_ok());
+	}
+
+	#[test]
+	fn rejects_setpoint_at_failsafe() {
+		let config = parse(r#"
+			[[controls]]
+			name = "Exhaust Temp"
+			setpoint = 60.0
+			failsafe = 60.0
+		"#);
+
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn rejects_duplicate_sensors() {
+		let config = parse(r#"
+			[[controls]]
+			name = "Temp"
+			setpoint = 55.0
+			failsafe = 65.0
+
+			[[controls]]
+			name = "Temp"
+			setpoint = 55.0
+			failsafe = 65.0
+		"#);
+
+		assert!(config.validate().is_err());
+
+		let config = parse(r#"
+			[[controls]]
+			name = "Temp"
+			entity = "3.1"
+			setpoint = 55.0
+			failsafe = 65.0
+
+			[[controls]]
+			name = "Temp"
+			sensor = "0Fh"
+			setpoint = 55.0
+			failsafe = 65.0
+		"#);
+
+		assert!(config.validate().is_ok());
+	}
+
+	#[test]
+	fn failsafe_is_optional() {
+		let config = parse(r#"
+			[[controls]]
+			name = "Exhaust Temp"
+			setpoint = 40.0
+		"#);
+
+		assert!(config.validate().is_ok());
+		assert_eq!(config.controls()[0].failsafe, None);
+	}
+
+	#[test]
+	fn rejects_ipmi_retries_beyond_watchdog() {
+		let config = parse(r#"
+			[ipmi]
+			read_timeout_ms = 3000
+			retries = 0
+		"#);
+		assert!(config.validate().is_ok());
+
+		let policy = config.ipmi_policy();
+		assert!(!policy.persistent);
+		assert_eq!(policy.read_timeout, Duration::from_millis(3000));
+		assert_eq!(policy.write_timeout, ipmitool::DEFAULT_WRITE_TIMEOUT);
+
+		let config = parse(r#"
+			[ipmi]
+			read_timeout_ms = 3000
+			retries = 3
+		"#);
+		assert!(config.validate().is_err());
+
+		// Every fan command of the slowest pass counts, I.E. both Supermicro zones set twice
+		assert_eq!(parse("").worst_case_pass(), Ok(Duration::from_millis(4250 + (2250 + 2 * 2250) * 2 + 2250)));
+		assert_eq!(parse("[fans]\nprofile = \"dell\"\nverify = false").worst_case_pass(), Ok(Duration::from_millis(4250 + 2 * 2250 + 2250)));
+
+		// The same timeouts fit with a single fan zone but not four
+		let custom = |speed: &str| parse(&format!("[ipmi]\nwrite_timeout_ms = 1500\n[fans]\nmanual = [\"0x30 0x30 0x01 0x00\"]\nautomatic = [\"0x30 0x30 0x01 0x01\"]\nspeed = [{}]", speed)).validate();
+		assert!(custom(r#""0x30 0x70 0x66 0x01 0x00 {duty}""#).is_ok());
+		assert!(custom(r#""0x30 0x70 0x66 0x01 0x00 {duty}", "0x30 0x70 0x66 0x01 0x01 {duty}", "0x30 0x70 0x66 0x01 0x02 {duty}", "0x30 0x70 0x66 0x01 0x03 {duty}""#).is_err());
+	}
+
+	#[test]
+	fn selects_fan_profile() {
+		assert_eq!(parse("").fan_profile(), Ok(None));
+		assert_eq!(parse("[fans]\nprofile = \"supermicro\"").fan_profile(), Ok(Some(FanProfile::supermicro())));
+
+		let custom = parse(r#"
+			[fans]
+			manual = ["0x30 0x30 0x01 0x00"]
+			automatic = ["0x30 0x30 0x01 0x01"]
+			speed = ["0x30 0x30 0x02 0xff {duty}"]
+		"#).fan_profile().unwrap().unwrap();
+		assert_eq!(custom.speed_commands(0.5), FanProfile::dell().speed_commands(0.5));
+
+		assert!(parse("[fans]\nprofile = \"hp\"").validate().is_err());
+		assert!(parse("[fans]\nprofile = \"dell\"\nmanual = [\"0x30 0x30\"]").validate().is_err());
+		assert!(parse("[fans]\nspeed = [\"0x30 0x30 0x02 0xff\"]").validate().is_err());
+	}
+
+	#[test]
+	fn fan_verification() {
+		let settings = parse("").verify_settings(None).unwrap();
+		assert_eq!(settings.interval, Duration::from_secs(30));
+		assert_eq!(settings.curve, None);
+
+		let calibration = Calibration {
+			timestamp: 0,
+			profile: "dell".to_string(),
+			fans: vec!(calibration::FanCurve {
+				name: "FAN 1".to_string(),
+				points: vec!(CurvePoint { duty: 0.1, rpm: 2400.0 }, CurvePoint { duty: 1.0, rpm: 9000.0 })
+			})
+		};
+		assert_eq!(parse("").verify_settings(Some(&calibration)).unwrap().curve, Some(calibration.fans[0].points.clone()));
+
+		let config = parse("[fans]\nrpm_min = 2000.0\nrpm_max = 12000.0");
+		assert!(config.validate().is_ok());
+		assert_eq!(config.verify_settings(Some(&calibration)).unwrap().curve, Some(vec!(CurvePoint { duty: 0.0, rpm: 2000.0 }, CurvePoint { duty: 1.0, rpm: 12000.0 })));
+
+		assert!(parse("[fans]\nverify = false").verify_settings(None).is_none());
+		assert!(parse("[fans]\nrpm_min = 2000.0").validate().is_err());
+		assert!(parse("[fans]\nrpm_min = 2000.0\nrpm_max = 1000.0").validate().is_err());
+	}
+
+	#[test]
+	fn feed_forward() {
+		let config = parse(r#"
+			[[controls]]
+			name = "Temp"
+			setpoint = 55.0
+			feed_forward = { gain = 0.3 }
+
+			[[controls]]
+			name = "Exhaust Temp"
+			setpoint = 40.0
+			feed_forward = { curve = [[0.2, 0.0], [1.0, 0.25]], lead_secs = 0.0 }
+		"#);
+		assert!(config.validate().is_ok());
+
+		let mut gain = config.controls()[0].feed_forward().unwrap().unwrap();
+		assert_eq!(gain.update(Some(0.5), 1.0), 0.15);
+
+		let mut curve = config.controls()[1].feed_forward().unwrap().unwrap();
+		assert!((curve.update(Some(0.6), 1.0) - 0.125).abs() < 1e-6);
+
+		assert!(parse("[[controls]]\nname = \"Temp\"\nsetpoint = 55.0\nfeed_forward = { gain = 0.3, curve = [[0.0, 0.0]] }").validate().is_err());
+		assert!(parse("[[controls]]\nname = \"Temp\"\nsetpoint = 55.0\nfeed_forward = { lead_secs = 5.0 }").validate().is_err());
+		assert!(parse("[[controls]]\nname = \"Temp\"\nsetpoint = 55.0\nfeed_forward = { curve = [[0.5, 0.1], [0.2, 0.2]] }").validate().is_err());
+		assert!(parse("[[controls]]\nname = \"Temp\"\nsetpoint = 55.0\nfeed_forward = { gain = -0.1 }").validate().is_err());
+	}
+
+	#[test]
+	fn aggregation() {
+		assert_eq!(parse("").aggregation(), Ok(Aggregation::Max));
+
+		let config = parse(r#"
+			[pid]
+			k_factor = 0.025
+			i_factor = 0.000001
+			d_factor = 0.0
+			aggregation = "weighted_sum"
+
+			[[controls]]
+			name = "Exhaust Temp"
+			setpoint = 40.0
+			weight = 0.5
+		"#);
+		assert!(config.validate().is_ok());
+		assert_eq!(config.aggregation(), Ok(Aggregation::WeightedSum));
+
+		assert!(parse("[pid]\nk_factor = 0.025\ni_factor = 0.0\nd_factor = 0.0\naggregation = \"average\"").validate().is_err());
+		assert!(parse("[[controls]]\nname = \"Temp\"\nsetpoint = 55.0\nweight = -1.0").validate().is_err());
+	}
+
+	#[test]
+	fn schedule() {
+		let config = parse(r#"
+			[schedule]
+			ramp_secs = 600
+
+			[[schedule.periods]]
+			name = "quiet"
+			days = ["sat", "sun"]
+			start = "22:00"
+			end = "07:00"
+			setpoints = { "Exhaust Temp" = 45.0, "Temp 3.1" = 62.0 }
+			min = 0
+			max = 60
+
+			[[schedule.periods]]
+			name = "workday"
+			start = "09:00"
+			end = "17:00"
+			min = 30
+		"#);
+		assert!(config.validate().is_ok());
+		assert_eq!(config.schedule_ramp(), Duration::from_secs(600));
+
+		let periods = config.schedule_periods().unwrap();
+		assert_eq!(periods[0].days, vec!(5, 6));
+		assert_eq!((periods[0].start, periods[0].end), (22 * 60, 7 * 60));
+		assert_eq!(periods[0].setpoints, vec!(("Exhaust Temp".to_string(), 45.0), ("Temp 3.1".to_string(), 62.0)));
+		assert_eq!((periods[0].min, periods[0].max), (Some(0.0), Some(0.6)));
+		assert!(periods[1].days.is_empty());
+
+		let period = |body: &str| parse(&format!("[[schedule.periods]]\nname = \"quiet\"\nstart = \"22:00\"\nend = \"07:00\"\n{}", body)).validate();
+		assert!(period("").is_ok());
+		assert!(period("days = [\"someday\"]").is_err());
+		assert!(period("min = 70\nmax = 60").is_err());
+		assert!(period("max = 120").is_err());
+		assert!(period("setpoints = { \"Missing Temp\" = 40.0 }").is_err());
+		assert!(period("setpoints = { \"Exhaust Temp\" = 60.0 }").is_err());
+		assert!(parse("[[schedule.periods]]\nname = \"quiet\"\nstart = \"22\"\nend = \"07:00\"").validate().is_err());
+	}
+
+	#[test]
+	fn acoustic_budget() {
+		let pid = "[pid]\nk_factor = 0.025\ni_factor = 0.000001\nd_factor = 0.0\n";
+		let config = parse(&format!(r#"{}
+			min = 10
+			max = 60
+			acoustic_budget = true
+
+			[[controls]]
+			name = "Exhaust Temp"
+			setpoint = 40.0
+			emergency = 50.0
+			failsafe = 60.0
+		"#, pid));
+		assert!(config.validate().is_ok());
+		assert_eq!((config.max_speed(), config.acoustic_budget()), (0.6, true));
+		assert_eq!(parse("").max_speed(), 1.0);
+
+		assert!(parse(&format!("{}acoustic_budget = true", pid)).validate().is_err());
+		assert!(parse(&format!("{}min = 50\nmax = 40", pid)).validate().is_err());
+		assert!(parse(&format!("{}max = 101", pid)).validate().is_err());
+		assert!(parse("[[controls]]\nname = \"Temp\"\nsetpoint = 55.0\nemergency = 50.0").validate().is_err());
+		assert!(parse("[[controls]]\nname = \"Temp\"\nsetpoint = 55.0\nfailsafe = 65.0\nemergency = 65.0").validate().is_err());
+	}
+
+	#[test]
+	fn virtual_sensors() {
+		let config = parse(r#"
+			[[virtual_sensors]]
+			name = "Delta T"
+			expr = "exhaust - inlet"
+			inputs = { exhaust = { name = "Exhaust Temp" }, inlet = { name = "Inlet Temp" } }
+
+			[[virtual_sensors]]
+			name = "CPU Max"
+			expr = "max(cpu1, cpu2)"
+			inputs = { cpu1 = { name = "Temp", entity = "3.1" }, cpu2 = { name = "Temp", entity = "3.2" } }
+
+			[[controls]]
+			name = "Delta T"
+			setpoint = 15.0
+			failsafe = 25.0
+		"#);
+		assert!(config.validate().is_ok());
+
+		let sensors = config.virtual_sensors().unwrap();
+		assert_eq!(sensors[0].inputs.iter().map(|(v, s)| (v.as_str(), s.label())).collect::<Vec<_>>(), vec!(("exhaust", "Exhaust Temp".to_string()), ("inlet", "Inlet Temp".to_string())));
+		assert_eq!(sensors[1].inputs[1].1.label(), "Temp 3.2");
+
+		let sensor = |body: &str| parse(&format!("[[virtual_sensors]]\nname = \"Delta T\"\n{}", body)).validate();
+		assert!(sensor("expr = \"exhaust - inlet\"\ninputs = { exhaust = { name = \"Exhaust Temp\" } }").is_err());
+		assert!(sensor("expr = \"exhaust -\"\ninputs = { exhaust = { name = \"Exhaust Temp\" } }").is_err());
+		assert!(sensor("expr = \"cpu\"\ninputs = { cpu = { name = \"Temp\", entity = \"3\" } }").is_err());
+	}
+
+	#[test]
+	fn ambient_compensation() {
+		let config = parse(r#"
+			[[controls]]
+			name = "Exhaust Temp"
+			setpoint = 40.0
+			failsafe = 60.0
+			ambient = { name = "Inlet Temp", reference = 20.0, gain = 0.8, min = 35.0, max = 50.0 }
+		"#);
+		assert!(config.validate().is_ok());
+
+		let ambient = config.controls()[0].ambient().unwrap().unwrap();
+		assert_eq!(ambient.selector.label(), "Inlet Temp");
+		assert_eq!(ambient.setpoint(40.0, Some(20.0)), 40.0);
+		assert_eq!(ambient.setpoint(40.0, Some(30.0)), 48.0);
+		assert_eq!(ambient.setpoint(40.0, Some(40.0)), 50.0);
+		assert_eq!(ambient.setpoint(40.0, Some(5.0)), 35.0);
+		assert_eq!(ambient.setpoint(40.0, None), 40.0);
+
+		let control = |ambient: &str| parse(&format!("[[controls]]\nname = \"Exhaust Temp\"\nsetpoint = 40.0\nfailsafe = 60.0\nambient = {}", ambient)).validate();
+		assert!(control("{ name = \"Inlet Temp\", reference = 20.0, gain = 0.8, min = 50.0, max = 45.0 }").is_err());
+		assert!(control("{ name = \"Inlet Temp\", reference = 20.0, gain = 0.8, min = 35.0, max = 45.0, entity = \"7\" }").is_err());
+	}
+
+	#[test]
+	fn drives() {
+		assert!(parse("").drives().unwrap().is_none());
+
+		let config = parse(r#"
+			[drives]
+			smartctl = ["/dev/sda"]
+
+			[[drives.groups]]
+			name = "Drive Bay"
+			drives = ["sda", "sdb"]
+
+			[[drives.groups]]
+			name = "NVMe"
+			drives = ["nvme0", "nvme1"]
+			aggregate = "avg"
+
+			[[controls]]
+			name = "Drive Bay"
+			setpoint = 40.0
+			failsafe = 50.0
+		"#);
+		assert!(config.validate().is_ok());
+
+		let source = config.drives().unwrap().unwrap();
+		assert_eq!(source.smartctl, vec!("/dev/sda".to_string()));
+		assert_eq!(source.smartctl_interval, drives::DEFAULT_SMARTCTL_INTERVAL);
+		assert_eq!(source.groups[0], DriveGroup { name: "Drive Bay".to_string(), drives: vec!("sda".to_string(), "sdb".to_string()), aggregate: GroupAggregate::Max });
+		assert_eq!(source.groups[1].aggregate, GroupAggregate::Avg);
+
+		assert!(parse("[[drives.groups]]\nname = \"NVMe\"\naggregate = \"sum\"").validate().is_err());
+		assert!(parse("[drives]\nsmartctl_interval_secs = 0").validate().is_err());
+	}
+
+	#[test]
+	fn command_sensors() {
+		let config = parse(r#"
+			[[command_sensors]]
+			name = "GPU"
+			command = ["/usr/local/bin/gpu-temp", "--json"]
+			interval_secs = 5
+			field = "/gpu/temp"
+
+			[[controls]]
+			name = "GPU"
+			setpoint = 70.0
+			failsafe = 85.0
+		"#);
+		assert!(config.validate().is_ok());
+
+		let sensors = config.command_sensors().unwrap();
+		assert_eq!(sensors[0].program, "/usr/local/bin/gpu-temp");
+		assert_eq!(sensors[0].args, vec!("--json".to_string()));
+		assert_eq!((sensors[0].interval, sensors[0].timeout, sensors[0].stale), (Duration::from_secs(5), command_sensor::DEFAULT_TIMEOUT, Duration::from_secs(15)));
+
+		let sensor = |body: &str| parse(&format!("[[command_sensors]]\nname = \"GPU\"\n{}", body)).validate();
+		assert!(sensor("command = []").is_err());
+		assert!(sensor("command = [\"gpu-temp\"]\ninterval_secs = 10\nstale_secs = 5").is_err());
+		assert!(sensor("command = [\"gpu-temp\"]\nfield = \"temp\"").is_err());
+	}
+
+	#[test]
+	fn rejects_min_above_100() {
+		let config = parse(r#"
+			[pid]
+			k_factor = 0.025
+			i_factor = 0.000001
+			d_factor = 0.0
+			min = 120
+		"#);
+
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn saves_config_privately() {
+		use ::std::os::unix::fs::PermissionsExt;
+
+		let dir = ::std::env::temp_dir().join(format!("twd-save-{}", ::std::process::id()));
+		::std::fs::remove_dir_all(&dir).unwrap_or(());
+		::std::fs::create_dir_all(&dir).unwrap();
+
+		let path = dir.join("thermal_watchdog.toml");
+		let path = path.to_str().unwrap();
+		::std::fs::write(path, "# Hand written\n[pid]\nk_factor = 0.1\n").unwrap();
+		::std::fs::set_permissions(path, ::std::fs::Permissions::from_mode(0o600)).unwrap();
+
+		save_config(path, "[socket]\nenabled = false\n").unwrap();
+
+		let saved = ::std::fs::read_to_string(path).unwrap();
+		assert!(saved.starts_with("# Written by the Thermal Watchdog web API"));
+		assert!(parse_toml(&saved).is_ok());
+		assert_eq!(::std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+		assert!(::std::fs::read_to_string(format!("{}.bak", path)).unwrap().starts_with("# Hand written"));
+		assert_eq!(::std::fs::metadata(format!("{}.bak", path)).unwrap().permissions().mode() & 0o777, 0o600);
+
+		::std::fs::remove_dir_all(&dir).unwrap_or(());
+	}
+}
@@ -1,61 +1,617 @@
 use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
 
 use crate::pid::*;
 use crate::ipmi::*;
 use crate::metrics;
+use crate::status::ControlStatus;
+use crate::feedforward::FeedForward;
+use crate::aggregate::{self, Aggregation};
+use crate::virtual_sensors::{self, VirtualSensor};
+
+/// Moves a control's setpoint with another sensor, typically inlet temperature.
+///
+/// The setpoint becomes `base + gain * (ambient - reference)` clamped to `min`..`max`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AmbientCompensation {
+	pub selector: SensorSelector,
+	pub reference: f32,
+	pub gain: f32,
+	pub min: f32,
+	/// Hard limit kept below the control's failsafe.
+	pub max: f32
+}
+
+impl AmbientCompensation {
+	pub fn setpoint(&self, base: f32, ambient: Option<f32>) -> f32 {
+		let setpoint = match ambient {
+			Some(ambient) => base + self.gain * (ambient - self.reference),
+			None => base
+		};
+
+		setpoint.clamp(self.min, self.max)
+	}
+}
+
+/// Everything needed to build a control, besides the sensor it watches.
+pub struct ControlSettings {
+	pub setpoint: f32,
+	pub failsafe: f32,
+	pub tuning: (f32,f32,f32),
+	pub filter_points: usize,
+	pub thresholds: Thresholds,
+	pub feed_forward: Option<FeedForward>,
+	/// Scale applied to the output by the `weighted_sum` aggregation.
+	pub weight: f32,
+	/// Temperature at which the duty cap may be exceeded in acoustic budget mode.
+	pub emergency: Option<f32>,
+	pub ambient: Option<AmbientCompensation>
+}
+
+struct Control {
+	pid: PID,
+	label: String,
+	setpoint: f32,
+	failsafe: f32,
+	thresholds: Thresholds,
+	feed_forward: Option<FeedForward>,
+	weight: f32,
+	emergency: Option<f32>,
+	ambient: Option<AmbientCompensation>,
+	/// Share of the fan duty from this control as of the last step.
+	contribution: f32,
+	/// Setpoint from the active schedule, replacing the configured setpoint.
+	scheduled: Option<f32>,
+	setpoint_override: Option<(f32, Option<Instant>)>
+}
+
+impl Control {
+	/// Setpoint in effect when not overridden.
+	fn base_setpoint(&self) -> f32 {
+		self.scheduled.unwrap_or(self.setpoint)
+	}
+}
 
 pub struct ControlLoop {
-	pids: Vec<(PID, f32)>,
-	pvs: Vec<IPMIRequest>
+	aggregation: Aggregation,
+	controls: Vec<Control>,
+	/// Label of the control driving the fans as of the last step.
+	winner: Option<String>,
+	pvs: Vec<IPMIRequest>,
+	/// Read alongside the SDR on every pass.
+	sources: Vec<Box<dyn SensorSource>>,
+	/// Computed after every read, in order, so controls can watch them like any other sensor.
+	virtual_sensors: Vec<VirtualSensor>,
+	/// Every sensor as of the last read, including virtual sensors.
+	entries: Vec<SdrEntry>,
+	/// Entries read from the SDR and sources at the start of `entries`, None if the last read failed.
+	readings: Option<usize>,
+	/// Every fan's RPM as of the last read.
+	fans: Vec<(String, u32)>
 }
 
 impl ControlLoop {
-	pub fn new() -> ControlLoop {
+	pub fn new(aggregation: Aggregation) -> ControlLoop {
 		ControlLoop {
-			pids: vec!(),
-			pvs: vec!()
+			aggregation,
+			controls: vec!(),
+			winner: None,
+			pvs: vec!(),
+			sources: vec!(),
+			virtual_sensors: vec!(),
+			entries: vec!(),
+			readings: None,
+			fans: vec!()
+		}
+	}
+
+	pub fn add_control(&mut self, selector: SensorSelector, settings: ControlSettings) {
+		self.controls.push(Control {
+			pid: PID::new(settings.setpoint, settings.tuning, settings.filter_points),
+			label: selector.label(),
+			setpoint: settings.setpoint,
+			failsafe: settings.failsafe,
+			thresholds: settings.thresholds,
+			feed_forward: settings.feed_forward,
+			weight: settings.weight,
+			emergency: settings.emergency,
+			ambient: settings.ambient,
+			contribution: 0.0,
+			scheduled: None,
+			setpoint_override: None
+		});
+		self.pvs.push(IPMIRequest { selector, status: IPMIValue::Unknown });
+	}
+
+	pub fn add_source(&mut self, source: Box<dyn SensorSource>) {
+		self.sources.push(source);
+	}
+
+	/// Gives sources read in the background the readings of the loop this one replaces, so a reload never sees them missing.
+	pub fn seed_sources(&mut self, previous: &[SdrEntry]) {
+		for source in self.sources.iter_mut() {
+			source.seed(previous);
+		}
+	}
+
+	/// Waits up to `timeout` for sources read in the background to take their first readings, used at startup.
+	pub fn wait_for_sources(&self, timeout: Duration) {
+		let deadline = Instant::now() + timeout;
+
+		while !self.sources.iter().all(|v| v.ready()) {
+			if Instant::now() >= deadline {
+				warn!("Sensor sources still have no readings after {}s", timeout.as_secs());
+				return
+			}
+
+			::std::thread::sleep(Duration::from_millis(50));
+		}
+	}
+
+	pub fn add_virtual_sensor(&mut self, sensor: VirtualSensor) {
+		self.virtual_sensors.push(sensor);
+	}
+
+	/// Reads sensors once and refuses selectors that match more than one SDR entry, or the same entry as another control.
+	///
+	/// Missing sensors are only warned about since they are already handled as errors by `step`.
+	pub fn check_sensors(&mut self) -> ::std::result::Result<(), String> {
+		if let Err(e) = self.refresh() {
+			warn!("Unable to read sensors to check controls: {}", e);
+			return Ok(())
+		}
+
+		self.check_resolved()
+	}
+
+	/// Same as `check_sensors` against sensors read elsewhere, I.E. by the loop this one replaces.
+	pub fn check_sensors_from(&mut self, entries: Vec<SdrEntry>) -> ::std::result::Result<(), String> {
+		self.load(entries);
+		self.check_resolved()
+	}
+
+	fn check_resolved(&self) -> ::std::result::Result<(), String> {
+		let mut watched: Vec<(usize, String)> = vec!();
+
+		for pv in self.pvs.iter() {
+			match pv.status {
+				IPMIValue::Ambiguous(count) => return Err(format!("{} matches {} sensors, add \"sensor\" or \"entity\" from \"ipmitool sdr elist\" to pick one", pv.selector.label(), count)),
+				IPMIValue::Unknown => warn!("No sensor found for {}", pv.selector.label()),
+				_ => ()
+			}
+
+			// Selectors can name the same sensor differently, I.E. by entity and by sensor number
+			if let Some(idx) = self.entries.iter().position(|v| pv.selector.matches(v)) {
+				if let Some((_, other)) = watched.iter().find(|(v, _)| *v == idx) {
+					return Err(format!("{} and {} both watch the same sensor", other, pv.selector.label()))
+				}
+
+				watched.push((idx, pv.selector.label()));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Replaces every control with those from `next`, keeping PID state and unexpired
+	/// setpoint overrides for controls that watch the same sensor as before.
+	pub fn reconfigure(&mut self, mut next: ControlLoop) {
+		for control in next.controls.iter_mut() {
+			if let Some(previous) = self.controls.iter().find(|v| v.label == control.label) {
+				trace!("Keeping PID state for {}", control.label);
+				control.pid.inherit(&previous.pid);
+
+				if let (Some(ff), Some(previous)) = (control.feed_forward.as_mut(), previous.feed_forward.as_ref()) {
+					ff.inherit(previous);
+				}
+
+				if let Some((setpoint, until)) = previous.setpoint_override {
+					if setpoint < control.failsafe {
+						control.pid.set_setpoint(setpoint);
+						control.setpoint_override = Some((setpoint, until));
+					} else {
+						warn!("Dropping setpoint override of {} for {}, it is not below the new failsafe", setpoint, control.label);
+					}
+				}
+			} else {
+				info!("Adding control {}", control.label);
+			}
+		}
+
+		for previous in self.controls.iter() {
+			if !next.controls.iter().any(|v| v.label == previous.label) {
+				info!("Removing control {}", previous.label);
+			}
+		}
+
+		*self = next;
+	}
+
+	/// Reads sensors without updating any controller, used while control is paused.
+	pub fn refresh(&mut self) -> Result<()> {
+		self.readings = None;
+
+		let mut entries = get_ipmi_values(&mut self.pvs)?;
+
+		for source in self.sources.iter_mut() {
+			entries.extend(source.read());
 		}
+
+		self.load(entries);
+
+		Ok(())
+	}
+
+	/// Resolves controls against sensors read elsewhere, I.E. from a recorded trace.
+	pub fn load(&mut self, mut entries: Vec<SdrEntry>) {
+		self.readings = Some(entries.len());
+
+		if !self.virtual_sensors.is_empty() {
+			entries.extend(virtual_sensors::evaluate(&self.virtual_sensors, &entries));
+		}
+
+		resolve_ipmi_values(&mut self.pvs, &entries);
+
+		self.fans = entries.iter()
+			.filter_map(|v| match v.value {
+				IPMIValue::RPM(rpm) => Some((v.name.clone(), rpm)),
+				_ => None
+			})
+			.collect();
+		self.entries = entries;
 	}
 
-	pub fn add_control(&mut self, name: String, setpoint: f32, tuning: (f32,f32,f32), filter_points: usize, failsafe: f32) {
-		self.pids.push((PID::new(setpoint, tuning, filter_points), failsafe));
-		self.pvs.push(IPMIRequest { name, status: IPMIValue::Unknown });
+	/// Sensors as read from the SDR and sources by the last refresh, without virtual sensors.
+	pub fn readings(&self) -> Option<&[SdrEntry]> {
+		self.readings.map(|v| &self.entries[..v])
 	}
 
-	pub fn step(&mut self, elapsed: f32, metrics: &metrics::MetricSender) -> Result<f32> {
+	pub fn fans(&self) -> &[(String, u32)] {
+		&self.fans
+	}
+
+	/// Runs every controller, `load` is CPU utilization from 0.0 to 1.0 for feed-forward terms.
+	pub fn step(&mut self, elapsed: f32, load: Option<f32>, metrics: &metrics::MetricSender) -> Result<f32> {
 		trace!("Step {}", elapsed);
 
-		get_ipmi_values(&mut self.pvs)?;
+		self.refresh()?;
+		self.update(elapsed, load, metrics)
+	}
 
-		let mut max = 0.0;
+	/// Same as `step` with sensors read elsewhere, used to replay recorded traces.
+	pub fn step_from(&mut self, entries: Vec<SdrEntry>, elapsed: f32, load: Option<f32>, metrics: &metrics::MetricSender) -> Result<f32> {
+		trace!("Step {} from {} recorded sensors", elapsed, entries.len());
+
+		self.load(entries);
+		self.update(elapsed, load, metrics)
+	}
 
-		let mut idx = 0;
-		for ((pid, failsafe), pv) in self.pids.iter_mut().zip(self.pvs.iter()) {
+	fn update(&mut self, elapsed: f32, load: Option<f32>, metrics: &metrics::MetricSender) -> Result<f32> {
+		let mut inputs = vec!();
+
+		for (control, pv) in self.controls.iter_mut().zip(self.pvs.iter()) {
+			if let (Some(ambient), None) = (control.ambient.as_ref(), control.setpoint_override) {
+				let reading = read_temp(&self.entries, &ambient.selector);
+				if reading.is_none() {
+					warn!("Unable to read {} for {}, using its base setpoint", ambient.selector.label(), control.label);
+				}
+
+				control.pid.set_setpoint(ambient.setpoint(control.base_setpoint(), reading));
+			}
+
+			let failsafe = control.failsafe;
 			let output = match pv.status {
-				IPMIValue::Invalid => Err(Error::new(ErrorKind::InvalidData, format!("{} is invalid", pv.name))),
-				IPMIValue::Unknown => Err(Error::new(ErrorKind::InvalidData, format!("{} is not set", pv.name))),
+				IPMIValue::Invalid => Err(Error::new(ErrorKind::InvalidData, format!("{} is invalid", control.label))),
+				IPMIValue::Unknown => Err(Error::new(ErrorKind::InvalidData, format!("{} is not set", control.label))),
+				IPMIValue::Ambiguous(count) => Err(Error::new(ErrorKind::InvalidData, format!("{} matches {} sensors", control.label, count))),
 				IPMIValue::Temp(temp) => {
-					metrics::report_metric(&[("temp".to_string(), temp as f32)], &[("sensor".to_string(), format!("{}({})", pv.name, idx))], metrics);
+					let t = &control.thresholds;
+					let fields = [("temp", Some(temp as f32)), ("setpoint", Some(control.pid.setpoint())), ("emergency", control.emergency), ("upper_non_critical", t.upper_non_critical), ("upper_critical", t.upper_critical), ("upper_non_recoverable", t.upper_non_recoverable)]
+						.iter()
+						.filter_map(|(n,v)| v.map(|v| (n.to_string(), v)))
+						.collect::<Vec<_>>();
+					metrics::report_metric(&fields, &[("sensor".to_string(), control.label.clone())], metrics);
 
-					if temp as f32 >= *failsafe {
+					if temp as f32 >= failsafe {
 						Err(Error::new(ErrorKind::InvalidData, format!("failsafe of {} exceeded: {}", failsafe, temp)))
 					} else {
 						let temp = temp as f32;
-						Ok(pid.update(temp, elapsed, (format!("{}({})", pv.name, idx), metrics)))
+						let output = control.pid.update(temp, elapsed, (control.label.clone(), metrics));
+
+						let ff = match control.feed_forward.as_mut() {
+							Some(ff) => {
+								let term = ff.update(load, elapsed / 1000.0);
+								metrics::report_metric(&[("ff".to_string(), term)], &[("pid".to_string(), control.label.clone())], metrics);
+								term
+							},
+							None => 0.0
+						};
+
+						Ok(output + ff)
 					}
 				},
-				IPMIValue::RPM(_rpm) => Err(Error::new(ErrorKind::InvalidData, format!("cannot watch RPM value for {}", pv.name)))
+				IPMIValue::RPM(_rpm) => Err(Error::new(ErrorKind::InvalidData, format!("cannot watch RPM value for {}", control.label)))
 			}?;
 
-			idx += 1;
+			debug!("Output for {} is {}", control.label, output);
+
+			inputs.push(aggregate::Input {
+				output,
+				weight: control.weight,
+				above_setpoint: match pv.status {
+					IPMIValue::Temp(temp) => temp as f32 > control.pid.setpoint(),
+					_ => false
+				}
+			});
+		}
+
+		let combined = aggregate::combine(self.aggregation, &inputs);
+
+		for (control, contribution) in self.controls.iter_mut().zip(combined.contributions.iter()) {
+			control.contribution = *contribution;
+			metrics::report_metric(&[("contribution".to_string(), *contribution)], &[("pid".to_string(), control.label.clone())], metrics);
+		}
+
+		self.winner = combined.winner.map(|idx| self.controls[idx].label.clone());
+
+		let mut fields = vec!(("aggregate".to_string(), metrics::FieldValue::from(combined.output)));
+		if let Some(winner) = self.winner.as_ref() {
+			fields.push(("winner".to_string(), metrics::FieldValue::from(winner.clone())));
+		}
+		metrics::report_point(metrics::Point::new("thermal_watchdog", vec!(("aggregation".to_string(), self.aggregation.as_str().to_string())), fields), metrics);
+
+		trace!("{} of outputs is {}, driven by {:?}", self.aggregation.as_str(), combined.output, self.winner);
+
+		Ok(combined.output)
+	}
+
+	/// Label of the first control at or above its emergency threshold as of the last read.
+	pub fn emergency(&self) -> Option<&str> {
+		self.controls.iter()
+			.zip(self.pvs.iter())
+			.find(|(control, pv)| match (control.emergency, &pv.status) {
+				(Some(emergency), IPMIValue::Temp(temp)) => *temp as f32 >= emergency,
+				_ => false
+			})
+			.map(|(control, _)| control.label.as_str())
+	}
+
+	/// Label of the control that contributed the most to the last step's output.
+	pub fn winner(&self) -> Option<&str> {
+		self.winner.as_deref()
+	}
+
+	/// Temporarily replaces the setpoint of the control with the given label until `until` or `clear_overrides`.
+	pub fn override_setpoint(&mut self, label: &str, setpoint: f32, until: Option<Instant>) -> ::std::result::Result<(), String> {
+		let control = self.controls.iter_mut()
+			.find(|v| v.label == label)
+			.ok_or_else(|| format!("no control named {}", label))?;
+
+		if setpoint >= control.failsafe {
+			return Err(format!("setpoint {} must be below the failsafe of {}", setpoint, control.failsafe))
+		}
+
+		info!("Overriding setpoint of {} to {}", label, setpoint);
+		control.pid.set_setpoint(setpoint);
+		control.setpoint_override = Some((setpoint, until));
+
+		Ok(())
+	}
+
+	/// Configured setpoint of every control, by label.
+	pub fn setpoints(&self) -> Vec<(String, f32)> {
+		self.controls.iter().map(|v| (v.label.clone(), v.setpoint)).collect()
+	}
+
+	pub fn failsafe(&self, label: &str) -> Option<f32> {
+		self.controls.iter().find(|v| v.label == label).map(|v| v.failsafe)
+	}
+
+	/// Replaces configured setpoints with those from the active schedule, overrides still take precedence.
+	pub fn schedule_setpoints(&mut self, setpoints: &[(String, f32)]) {
+		for control in self.controls.iter_mut() {
+			control.scheduled = setpoints.iter()
+				.find(|(label, _)| *label == control.label)
+				.map(|(_, v)| *v)
+				.filter(|v| *v != control.setpoint && *v < control.failsafe);
 
-			debug!("Output for {} is {}", pv.name, output);
+			if control.setpoint_override.is_none() {
+				control.pid.set_setpoint(control.base_setpoint());
+			}
+		}
+	}
+
+	pub fn clear_overrides(&mut self) {
+		for control in self.controls.iter_mut() {
+			if control.setpoint_override.take().is_some() {
+				info!("Restoring setpoint of {} to {}", control.label, control.base_setpoint());
+				control.pid.set_setpoint(control.base_setpoint());
+			}
+		}
+	}
 
-			max = output.max(max);
+	pub fn expire_overrides(&mut self, now: Instant) {
+		for control in self.controls.iter_mut() {
+			if let Some((_, Some(until))) = control.setpoint_override {
+				if now >= until {
+					info!("Setpoint override for {} expired, restoring {}", control.label, control.base_setpoint());
+					control.setpoint_override = None;
+					control.pid.set_setpoint(control.base_setpoint());
+				}
+			}
 		}
+	}
+
+	/// Latest reading, limits and PID terms for each control, as of the last step.
+	pub fn status(&self) -> Vec<ControlStatus> {
+		self.controls.iter().zip(self.pvs.iter())
+			.map(|(control, pv)| {
+				let (p, i, d) = control.pid.terms();
+				let ff = control.feed_forward.as_ref().map(|v| v.term()).unwrap_or(0.0);
+
+				ControlStatus {
+					name: pv.selector.name.clone(),
+					label: control.label.clone(),
+					temp: match pv.status {
+						IPMIValue::Temp(temp) => Some(temp as f32),
+						_ => None
+					},
+					setpoint: control.pid.setpoint(),
+					setpoint_override: control.setpoint_override.is_some(),
+					failsafe: control.failsafe,
+					p,
+					i,
+					d,
+					ff,
+					v: p + i + d + ff,
+					contribution: control.contribution
+				}
+			})
+			.collect()
+	}
+}
+
+/// Temperature of the single entry matching `selector`.
+fn read_temp(entries: &[SdrEntry], selector: &SensorSelector) -> Option<f32> {
+	let mut matches = entries.iter().filter(|v| selector.matches(v));
+
+	match (matches.next(), matches.next()) {
+		(Some(SdrEntry { value: IPMIValue::Temp(temp), .. }), None) => Some(*temp as f32),
+		_ => None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::mpsc;
+	use crate::feedforward::Mapping;
+
+	fn settings(setpoint: f32, failsafe: f32) -> ControlSettings {
+		ControlSettings {
+			setpoint,
+			failsafe,
+			tuning: (0.1, 0.01, 0.0),
+			filter_points: 1,
+			thresholds: Thresholds::default(),
+			feed_forward: Some(FeedForward::new(Mapping::Gain(0.5), 0.0, 1.0)),
+			weight: 1.0,
+			emergency: None,
+			ambient: None
+		}
+	}
+
+	fn selector(name: &str) -> SensorSelector {
+		SensorSelector::new(name, None, None).unwrap()
+	}
+
+	fn temp(name: &str, temp: i32) -> SdrEntry {
+		SdrEntry { name: name.to_string(), number: None, entity: None, value: IPMIValue::Temp(temp) }
+	}
+
+	fn control_loop(controls: &[(&str, f32, f32)]) -> ControlLoop {
+		let mut control_loop = ControlLoop::new(Aggregation::Max);
+		for (name, setpoint, failsafe) in controls {
+			control_loop.add_control(selector(name), settings(*setpoint, *failsafe));
+		}
+		control_loop
+	}
+
+	#[test]
+	fn keeps_state_across_reconfigure() {
+		let (metrics, _recv) = mpsc::channel();
+		let mut current = control_loop(&[("CPU", 60.0, 80.0), ("GPU", 60.0, 80.0), ("Inlet", 30.0, 45.0)]);
+		for _ in 0..5 {
+			current.step_from(vec!(temp("CPU", 70), temp("GPU", 65), temp("Inlet", 25)), 1000.0, Some(0.8), &metrics).unwrap();
+		}
+
+		let until = Instant::now() + Duration::from_secs(600);
+		current.override_setpoint("GPU", 55.0, Some(until)).unwrap();
+		current.override_setpoint("Inlet", 40.0, None).unwrap();
+		let before = current.status();
+		assert!(before[0].i != 0.0 && before[0].ff != 0.0);
+
+		// CPU gets a new setpoint, the GPU override stays below the new failsafe, the Inlet one doesn't and Disk is new
+		current.reconfigure(control_loop(&[("CPU", 65.0, 80.0), ("GPU", 60.0, 70.0), ("Inlet", 30.0, 35.0), ("Disk", 40.0, 50.0)]));
+		let after = current.status();
+
+		assert_eq!(after.iter().map(|v| v.label.as_str()).collect::<Vec<_>>(), vec!("CPU", "GPU", "Inlet", "Disk"));
+		assert_eq!((after[0].p, after[0].i, after[0].ff), (before[0].p, before[0].i, before[0].ff));
+		assert_eq!(after[0].setpoint, 65.0);
+		assert!(!after[0].setpoint_override);
+
+		assert_eq!((after[1].i, after[1].ff), (before[1].i, before[1].ff));
+		assert_eq!(after[1].setpoint, 55.0);
+		assert!(after[1].setpoint_override);
+
+		assert_eq!(after[2].setpoint, 30.0);
+		assert!(!after[2].setpoint_override);
+
+		assert_eq!((after[3].i, after[3].ff), (0.0, 0.0));
+
+		// The kept override still expires when it was meant to
+		current.expire_overrides(until);
+		assert_eq!(current.status()[1].setpoint, 60.0);
+
+		// The integrator carries on from where it was instead of starting over
+		let mut fresh = control_loop(&[("CPU", 65.0, 80.0)]);
+		let entries = vec!(temp("CPU", 70), temp("GPU", 65), temp("Inlet", 25), temp("Disk", 35));
+		current.step_from(entries.clone(), 1000.0, Some(0.8), &metrics).unwrap();
+		fresh.step_from(entries, 1000.0, Some(0.8), &metrics).unwrap();
+		assert!(current.status()[0].i > fresh.status()[0].i);
+	}
+
+	#[test]
+	fn compensates_for_ambient() {
+		let (metrics, _recv) = mpsc::channel();
+		let mut control_loop = ControlLoop::new(Aggregation::Max);
+		control_loop.add_control(selector("Exhaust Temp"), ControlSettings {
+			ambient: Some(AmbientCompensation { selector: selector("Inlet Temp"), reference: 20.0, gain: 0.8, min: 35.0, max: 50.0 }),
+			..settings(40.0, 60.0)
+		});
+
+		// 45 is above the base setpoint of 40 but below the compensated one
+		control_loop.step_from(vec!(temp("Exhaust Temp", 45), temp("Inlet Temp", 30)), 1000.0, None, &metrics).unwrap();
+		assert_eq!(control_loop.status()[0].setpoint, 48.0);
+		assert!(control_loop.status()[0].p < 0.0);
+
+		control_loop.step_from(vec!(temp("Exhaust Temp", 45), temp("Inlet Temp", 45)), 1000.0, None, &metrics).unwrap();
+		assert_eq!(control_loop.status()[0].setpoint, 50.0);
+
+		// Falls back to the base setpoint without an ambient reading
+		control_loop.step_from(vec!(temp("Exhaust Temp", 45)), 1000.0, None, &metrics).unwrap();
+		assert_eq!(control_loop.status()[0].setpoint, 40.0);
+		assert!(control_loop.status()[0].p > 0.0);
+
+		// Scheduled setpoints are compensated too
+		control_loop.schedule_setpoints(&[("Exhaust Temp".to_string(), 38.0)]);
+		control_loop.step_from(vec!(temp("Exhaust Temp", 45), temp("Inlet Temp", 25)), 1000.0, None, &metrics).unwrap();
+		assert_eq!(control_loop.status()[0].setpoint, 42.0);
+
+		// Overrides are used as is
+		control_loop.override_setpoint("Exhaust Temp", 55.0, None).unwrap();
+		control_loop.step_from(vec!(temp("Exhaust Temp", 45), temp("Inlet Temp", 30)), 1000.0, None, &metrics).unwrap();
+		assert_eq!(control_loop.status()[0].setpoint, 55.0);
+
+		control_loop.clear_overrides();
+		control_loop.step_from(vec!(temp("Exhaust Temp", 45), temp("Inlet Temp", 30)), 1000.0, None, &metrics).unwrap();
+		assert_eq!(control_loop.status()[0].setpoint, 46.0);
+	}
 
-		trace!("Max is {}", max);
+	#[test]
+	fn refuses_overlapping_selectors() {
+		let entry = |name: &str, number: u8, entity: (u8, u8), value: i32| SdrEntry { name: name.to_string(), number: Some(number), entity: Some(entity), value: IPMIValue::Temp(value) };
+		let entries = vec!(entry("Temp", 0x0E, (3, 1), 45), entry("Temp", 0x0F, (3, 2), 41));
+		let check = |selectors: Vec<SensorSelector>| {
+			let mut control_loop = ControlLoop::new(Aggregation::Max);
+			for selector in selectors {
+				control_loop.add_control(selector, settings(55.0, 65.0));
+			}
+			control_loop.check_sensors_from(entries.clone())
+		};
 
-		Ok(max)
+		assert!(check(vec!(SensorSelector::new("Temp", None, Some("3.1")).unwrap(), SensorSelector::new("Temp", Some("0Fh"), None).unwrap())).is_ok());
+		assert!(check(vec!(SensorSelector::new("Temp", None, Some("3.1")).unwrap(), SensorSelector::new("Temp", Some("0Eh"), None).unwrap())).is_err());
+		assert!(check(vec!(selector("Temp"))).is_err());
 	}
 }
@@ -0,0 +1,290 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+use crate::ipmi::*;
+
+/// Distance between the generated failsafe and setpoint.
+const SETPOINT_MARGIN: f32 = 15.0;
+
+/// Prints every sensor the BMC reports and writes a starter config to `output`, or stdout if None.
+pub fn discover(output: Option<&str>) -> Result<()> {
+	let sensors = get_sensor_thresholds()?;
+
+	for (kind, title) in [(SensorKind::Temp, "Temperature"), (SensorKind::Fan, "Fan"), (SensorKind::Power, "Power"), (SensorKind::Other, "Other")].iter() {
+		let group = sensors.iter().filter(|v| v.kind == *kind).collect::<Vec<_>>();
+
+		if group.is_empty() {
+			continue
+		}
+
+		println!("{} sensors", title);
+		println!("  {:<20} {:>14} {:<8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}", "Name", "Value", "Status", "LNR", "LCR", "LNC", "UNC", "UCR", "UNR");
+
+		for sensor in group {
+			let value = match sensor.value {
+				Some(v) if sensor.kind != SensorKind::Other => format!("{} {}", v, short_unit(&sensor.unit)),
+				_ if sensor.reading != "na" => sensor.reading.clone(),
+				_ => "no reading".to_string()
+			};
+			let t = &sensor.thresholds;
+
+			println!("  {:<20} {:>14} {:<8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}", selector(sensor).label(), value, sensor.status,
+				threshold(t.lower_non_recoverable), threshold(t.lower_critical), threshold(t.lower_non_critical),
+				threshold(t.upper_non_critical), threshold(t.upper_critical), threshold(t.upper_non_recoverable));
+		}
+
+		println!();
+	}
+
+	let config = generate_config(&sensors);
+
+	match output {
+		Some(path) => {
+			if Path::new(path).exists() {
+				return Err(Error::new(ErrorKind::AlreadyExists, format!("{} already exists, not overwriting it", path)))
+			}
+
+			::std::fs::File::create(path)?.write_all(config.as_bytes())?;
+			println!("Wrote starter config to {}", path);
+		},
+		None => print!("{}", config)
+	}
+
+	Ok(())
+}
+
+fn short_unit(unit: &str) -> &str {
+	match unit {
+		"degrees C" => "C",
+		"Watts" => "W",
+		"Amps" => "A",
+		"Volts" => "V",
+		"discrete" | "unspecified" => "",
+		v => v
+	}
+}
+
+fn threshold(v: Option<f32>) -> String {
+	v.map(|v| format!("{}", v)).unwrap_or_else(|| "na".to_string())
+}
+
+/// Selector for a sensor, narrowed by entity(or sensor number if entities collide) when its name isn't unique.
+fn selector(sensor: &SensorInfo) -> SensorSelector {
+	SensorSelector {
+		name: sensor.name.clone(),
+		number: None,
+		entity: sensor.entity
+	}
+}
+
+fn disambiguate(sensor: &SensorInfo, sensors: &[SensorInfo]) -> SensorSelector {
+	let same_name = sensors.iter().filter(|v| v.name == sensor.name).collect::<Vec<_>>();
+
+	if same_name.len() < 2 {
+		return SensorSelector { name: sensor.name.clone(), number: None, entity: None }
+	}
+
+	if sensor.entity.is_some() && same_name.iter().filter(|v| v.entity == sensor.entity).count() == 1 {
+		return selector(sensor)
+	}
+
+	SensorSelector {
+		name: sensor.name.clone(),
+		number: sensor.number,
+		entity: None
+	}
+}
+
+/// Basic TOML string, sensor names are free form on some BMCs.
+fn toml_string(v: &str) -> String {
+	let mut quoted = String::from("\"");
+
+	for c in v.chars() {
+		match c {
+			'"' => quoted += "\\\"",
+			'\\' => quoted += "\\\\",
+			c if c.is_control() => quoted += format!("\\u{:04X}", c as u32).as_str(),
+			c => quoted.push(c)
+		}
+	}
+
+	quoted + "\""
+}
+
+fn selector_toml(prefix: &str, selector: &SensorSelector) -> String {
+	let mut toml = format!("{}name = {}\n", prefix, toml_string(&selector.name));
+
+	if let Some((id, instance)) = selector.entity {
+		toml += format!("{}entity = \"{}.{}\"\n", prefix, id, instance).as_str();
+	}
+
+	if let Some(number) = selector.number {
+		toml += format!("{}sensor = \"{:02X}h\"\n", prefix, number).as_str();
+	}
+
+	toml
+}
+
+/// Ambient sensors track room temperature and fans can't pull them down, so they make poor controls.
+fn is_ambient(sensor: &SensorInfo) -> bool {
+	let name = sensor.name.to_lowercase();
+	name.contains("inlet") || name.contains("ambient")
+}
+
+/// Setpoint and failsafe derived from the upper threshold the BMC reports.
+fn suggest(sensor: &SensorInfo) -> Option<(f32, f32)> {
+	let failsafe = sensor.thresholds.default_failsafe()?;
+
+	Some((failsafe - SETPOINT_MARGIN, failsafe))
+}
+
+pub fn generate_config(sensors: &[SensorInfo]) -> String {
+	let hostname = crate::metrics::get_hostname().unwrap_or_else(|_| "this server".to_string());
+
+	let mut controls = String::new();
+	let mut enabled = 0;
+
+	let temps = sensors.iter().filter(|v| v.kind == SensorKind::Temp);
+
+	for sensor in temps {
+		let current = sensor.value
+			.map(|v| format!("currently {} C", v))
+			.unwrap_or_else(|| "no reading".to_string());
+		let selector = disambiguate(sensor, sensors);
+		let duplicate_note = if selector.entity.is_none() && selector.number.is_none() && sensors.iter().filter(|v| v.name == sensor.name).count() > 1 {
+			", shares its name with another sensor and needs \"entity\" or \"sensor\" from \"ipmitool sdr elist\""
+		} else {
+			""
+		};
+
+		controls += "\n";
+
+		match suggest(sensor) {
+			Some((setpoint, failsafe)) => {
+				let limit = sensor.thresholds.upper_limit().unwrap_or(failsafe);
+				controls += format!("# {}: {}, BMC upper limit {} C{}\n", sensor.name, current, limit, duplicate_note).as_str();
+
+				let prefix = if is_ambient(sensor) {
+					controls += "# Ambient sensor, fans can't cool it below room temperature so it is disabled by default\n";
+					"#"
+				} else {
+					enabled += 1;
+					""
+				};
+
+				controls += format!("{}[[controls]]\n{}{}setpoint = {:.1}\n{}failsafe = {:.1}\n",
+					prefix, selector_toml(prefix, &selector), prefix, setpoint, prefix, failsafe).as_str();
+			},
+			None => {
+				controls += format!("# {}: {}, the BMC reports no upper thresholds{}\n", sensor.name, current, duplicate_note).as_str();
+				controls += "# Set failsafe ~5 degrees below T-CASE max from the datasheet before enabling\n";
+				controls += format!("#[[controls]]\n{}#setpoint = 55.0\n#failsafe = 65.0\n", selector_toml("#", &selector)).as_str();
+			}
+		}
+	}
+
+	// Without any controls the built in R710 defaults would be used, which are wrong for every other board
+	let no_controls = if enabled == 0 {
+		warn!("No temperature sensor outside of ambient ones has BMC thresholds, the generated config has no controls until one is enabled by hand");
+		r#"
+# WARNING: no temperature sensor outside of ambient ones has BMC thresholds, so no
+# control could be generated.
+# Enable at least one of the commented out controls below and remove this line, the
+# config is refused until then.
+controls = []
+"#
+	} else {
+		""
+	};
+
+	let mut config = format!(
+r#"# Generated by "thermal_watchdog discover" on {}.
+# Setpoints and failsafes are derived from BMC thresholds, review every value
+# against your hardware's datasheets before running with --live.
+{}
+#[metrics]
+#influx_addr="http://localhost:8086"
+#influx_db="twd"
+
+[pid]
+k_factor = 0.025
+i_factor = 0.000001
+d_factor = 0.0
+min = 5
+"#, hostname, no_controls);
+
+	config += controls.as_str();
+
+	config
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::AppConfig;
+
+	fn temp(name: &str, value: f32, upper_critical: Option<f32>) -> SensorInfo {
+		SensorInfo {
+			name: name.to_string(),
+			number: None,
+			entity: None,
+			kind: SensorKind::Temp,
+			value: Some(value),
+			reading: format!("{}", value),
+			unit: "degrees C".to_string(),
+			status: "ok".to_string(),
+			thresholds: Thresholds {
+				upper_critical,
+				..Thresholds::default()
+			}
+		}
+	}
+
+	#[test]
+	fn generates_valid_config() {
+		let sensors = vec!(
+			temp("Inlet Temp", 23.0, Some(47.0)),
+			temp("Exhaust Temp", 38.0, Some(70.0)),
+			SensorInfo { entity: Some((3, 1)), number: Some(0x0E), ..temp("Temp", 45.0, Some(90.0)) },
+			SensorInfo { entity: Some((3, 2)), number: Some(0x0F), ..temp("Temp", 41.0, None) }
+		);
+
+		let generated = generate_config(&sensors);
+		let config: AppConfig = toml::from_str(&generated).unwrap();
+
+		assert!(config.validate().is_ok());
+
+		let controls = config.controls.unwrap();
+		assert_eq!(controls.len(), 2);
+		assert_eq!(controls[0].name, "Exhaust Temp");
+		assert_eq!(controls[0].failsafe, Some(65.0));
+		assert_eq!(controls[0].setpoint, 50.0);
+		assert_eq!(controls[1].name, "Temp");
+		assert_eq!(controls[1].entity.as_deref(), Some("3.1"));
+		assert!(generated.contains("#entity = \"3.2\""));
+	}
+
+	#[test]
+	fn escapes_names() {
+		let generated = generate_config(&[temp("CPU \"A\" C:\\", 40.0, Some(90.0))]);
+		let config: AppConfig = toml::from_str(&generated).unwrap();
+
+		assert_eq!(config.controls.unwrap()[0].name, "CPU \"A\" C:\\");
+	}
+
+	#[test]
+	fn refuses_config_without_controls() {
+		let generated = generate_config(&[temp("Inlet Temp", 23.0, Some(47.0)), temp("CPU Temp", 40.0, None)]);
+		let config: AppConfig = toml::from_str(&generated).unwrap();
+
+		assert!(generated.contains("# WARNING"));
+		assert!(config.validate().is_err());
+
+		// Enabling one of the suggestions as instructed gives a working config
+		let enabled = generated.replace("controls = []\n", "")
+			.replace("#[[controls]]\n#name = \"CPU Temp\"\n#setpoint", "[[controls]]\nname = \"CPU Temp\"\nsetpoint")
+			.replace("#failsafe = 65.0", "failsafe = 65.0");
+		let config: AppConfig = toml::from_str(&enabled).unwrap();
+		assert!(config.validate().is_ok());
+	}
+}
@@ -0,0 +1,369 @@
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::ipmi::{IPMIValue, SdrEntry, SensorSource};
+use crate::ipmitool;
+
+pub const HWMON_ROOT: &str = "/sys/class/hwmon";
+pub const DEFAULT_SMARTCTL_INTERVAL: Duration = Duration::from_secs(60);
+const SMARTCTL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How a group's drive temperatures are combined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupAggregate {
+	Max,
+	Avg
+}
+
+/// Drives combined into a single sensor that controls can watch by `name`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriveGroup {
+	pub name: String,
+	/// Drive names(I.E. "nvme0" or "sda"), every drive found if empty.
+	pub drives: Vec<String>,
+	pub aggregate: GroupAggregate
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriveConfig {
+	/// Device paths, I.E. "/dev/sda".
+	pub smartctl: Vec<String>,
+	pub smartctl_interval: Duration,
+	pub groups: Vec<DriveGroup>
+}
+
+/// Temperature of each drive read with smartctl, None once a read fails. Drives are missing until their first read.
+type SmartctlReadings = Arc<Mutex<Vec<(String, Option<i32>)>>>;
+
+/// Reads NVMe and SATA/SAS drive temperatures, exposing each drive and group as a sensor.
+///
+/// NVMe composite temperatures come from the nvme hwmon driver and SATA/SAS temperatures from drivetemp.
+/// Drives listed for smartctl are read from its JSON output instead, every `smartctl_interval` on a thread
+/// of their own so a hung drive never holds up the control loop.
+pub struct DriveSource {
+	pub hwmon_root: PathBuf,
+	/// Device paths, I.E. "/dev/sda".
+	pub smartctl: Vec<String>,
+	pub groups: Vec<DriveGroup>,
+	smartctl_readings: SmartctlReadings,
+	/// Dropped along with the source, which stops its thread.
+	_stop: Option<mpsc::Sender<()>>
+}
+
+impl DriveSource {
+	/// Starts reading smartctl drives, taking their first readings on its thread.
+	pub fn start(config: DriveConfig) -> DriveSource {
+		let DriveConfig { smartctl, smartctl_interval, groups } = config;
+		let readings: SmartctlReadings = Arc::new(Mutex::new(vec!()));
+
+		let stop = if smartctl.is_empty() {
+			None
+		} else {
+			let (stop, stopped) = mpsc::channel::<()>();
+			let readings = readings.clone();
+			let smartctl = smartctl.clone();
+
+			thread::spawn(move || {
+				loop {
+					for device in smartctl.iter() {
+						let name = drive_name(device);
+
+						// Sleeping drives are only woken until they have a temperature, after that they keep it until they spin up
+						let standby = readings.lock().unwrap_or_else(|e| e.into_inner()).iter().any(|(v, temp)| *v == name && temp.is_some());
+
+						let temp = match smartctl_temp(device, standby) {
+							Ok(Some(temp)) => Some(temp),
+							Ok(None) => {
+								trace!("{} is in standby, keeping its last temperature", device);
+								continue
+							},
+							Err(e) => {
+								warn!("Unable to read temperature of {}: {}", device, e);
+								None
+							}
+						};
+
+						update(&readings, name, temp);
+					}
+
+					if let Err(mpsc::RecvTimeoutError::Disconnected) = stopped.recv_timeout(smartctl_interval) {
+						break
+					}
+				}
+
+				trace!("Stopped reading smartctl");
+			});
+
+			Some(stop)
+		};
+
+		DriveSource {
+			hwmon_root: PathBuf::from(HWMON_ROOT),
+			smartctl,
+			groups,
+			smartctl_readings: readings,
+			_stop: stop
+		}
+	}
+}
+
+fn update(readings: &Mutex<Vec<(String, Option<i32>)>>, name: String, temp: Option<i32>) {
+	let mut readings = readings.lock().unwrap_or_else(|e| e.into_inner());
+
+	match readings.iter_mut().find(|(v, _)| *v == name) {
+		Some(reading) => reading.1 = temp,
+		None => readings.push((name, temp))
+	}
+}
+
+impl SensorSource for DriveSource {
+	fn read(&mut self) -> Vec<SdrEntry> {
+		let mut drives = self.smartctl_readings.lock().unwrap_or_else(|e| e.into_inner()).clone();
+
+		for (name, temp) in hwmon_temps(&self.hwmon_root) {
+			if !drives.iter().any(|(v, _)| *v == name) {
+				drives.push((name, Some(temp)));
+			}
+		}
+
+		let mut entries = drives.iter()
+			.map(|(name, temp)| entry(name, temp.map(IPMIValue::Temp).unwrap_or(IPMIValue::Invalid)))
+			.collect::<Vec<_>>();
+
+		for group in self.groups.iter() {
+			entries.push(entry(&group.name, group_value(group, &drives)));
+		}
+
+		entries
+	}
+
+	fn ready(&self) -> bool {
+		let readings = self.smartctl_readings.lock().unwrap_or_else(|e| e.into_inner());
+
+		self.smartctl.iter().all(|device| readings.iter().any(|(v, _)| *v == drive_name(device)))
+	}
+
+	fn seed(&mut self, previous: &[SdrEntry]) {
+		let mut readings = self.smartctl_readings.lock().unwrap_or_else(|e| e.into_inner());
+
+		for name in self.smartctl.iter().map(|v| drive_name(v)) {
+			if readings.iter().any(|(v, _)| *v == name) {
+				continue
+			}
+
+			if let Some(IPMIValue::Temp(temp)) = previous.iter().find(|v| v.name == name).map(|v| &v.value) {
+				readings.push((name, Some(*temp)));
+			}
+		}
+	}
+}
+
+fn entry(name: &str, value: IPMIValue) -> SdrEntry {
+	SdrEntry {
+		name: name.to_string(),
+		number: None,
+		entity: None,
+		value
+	}
+}
+
+fn group_value(group: &DriveGroup, drives: &[(String, Option<i32>)]) -> IPMIValue {
+	let members = if group.drives.is_empty() {
+		drives.iter().collect::<Vec<_>>()
+	} else {
+		let found = drives.iter().filter(|(v, _)| group.drives.contains(v)).collect::<Vec<_>>();
+
+		// A listed drive that disappeared could be the one overheating
+		if let Some(missing) = group.drives.iter().find(|v| !found.iter().any(|(name, _)| name == *v)) {
+			warn!("Drive {} in {} was not found", missing, group.name);
+			return IPMIValue::Invalid
+		}
+
+		found
+	};
+
+	if members.is_empty() {
+		return IPMIValue::Unknown
+	}
+
+	let temps = match members.iter().map(|(_, v)| *v).collect::<Option<Vec<_>>>() {
+		Some(v) => v,
+		None => return IPMIValue::Invalid
+	};
+
+	match group.aggregate {
+		GroupAggregate::Max => IPMIValue::Temp(*temps.iter().max().unwrap_or(&0)),
+		GroupAggregate::Avg => IPMIValue::Temp((temps.iter().sum::<i32>() as f32 / temps.len() as f32).round() as i32)
+	}
+}
+
+/// "/dev/sda" -> "sda"
+fn drive_name(device: &str) -> String {
+	Path::new(device).file_name()
+		.map(|v| v.to_string_lossy().into_owned())
+		.unwrap_or_else(|| device.to_string())
+}
+
+/// Composite temperature of every drive exposed through the nvme and drivetemp hwmon drivers.
+pub fn hwmon_temps(root: &Path) -> Vec<(String, i32)> {
+	let dirs = match ::std::fs::read_dir(root) {
+		Ok(v) => v,
+		Err(e) => {
+			trace!("Unable to list {}: {}", root.display(), e);
+			return vec!()
+		}
+	};
+
+	let mut temps = dirs.filter_map(|v| v.ok())
+		.filter_map(|dir| {
+			let path = dir.path();
+			let driver = ::std::fs::read_to_string(path.join("name")).ok()?;
+
+			let name = match driver.trim() {
+				// The hwmon device is the controller, I.E. nvme0
+				"nvme" => ::std::fs::read_link(path.join("device")).ok()?.file_name()?.to_string_lossy().into_owned(),
+				// The hwmon device is the SCSI device, its block device gives the familiar name
+				"drivetemp" => ::std::fs::read_dir(path.join("device").join("block")).ok()?
+					.filter_map(|v| v.ok())
+					.map(|v| v.file_name().to_string_lossy().into_owned())
+					.next()?,
+				_ => return None
+			};
+
+			let millidegrees = ::std::fs::read_to_string(path.join("temp1_input")).ok()?.trim().parse::<i32>().ok()?;
+
+			Some((name, (millidegrees as f32 / 1000.0).round() as i32))
+		})
+		.collect::<Vec<_>>();
+
+	temps.sort();
+	temps
+}
+
+/// None if `standby` is set and the drive is asleep, smartctl leaves it asleep rather than reading it.
+fn smartctl_temp(device: &str, standby: bool) -> Result<Option<i32>, String> {
+	let args: &[&str] = if standby {
+		&["--json", "-n", "standby", "-A", device]
+	} else {
+		&["--json", "-A", device]
+	};
+
+	let output = ipmitool::run_process("smartctl", args, SMARTCTL_TIMEOUT)
+		.map_err(|e| e.to_string())?;
+
+	// smartctl sets bits in its exit status for drive health as well as failures, so only the JSON is checked
+	parse_smartctl(&output.stdout)
+}
+
+fn parse_smartctl(json: &str) -> Result<Option<i32>, String> {
+	let value: serde_json::Value = serde_json::from_str(json).map_err(|e| format!("invalid smartctl output, {}", e))?;
+
+	if let Some(temp) = value.pointer("/temperature/current").and_then(|v| v.as_i64()) {
+		return Ok(Some(temp as i32))
+	}
+
+	// I.E. "Device is in STANDBY mode, exit(2)"
+	let asleep = value.pointer("/smartctl/messages")
+		.and_then(|v| v.as_array())
+		.map(|messages| messages.iter().filter_map(|v| v.get("string").and_then(|v| v.as_str())).any(|v| v.contains("STANDBY") || v.contains("SLEEP")))
+		.unwrap_or(false);
+
+	if asleep {
+		Ok(None)
+	} else {
+		Err("no temperature in smartctl output".to_string())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(smartctl: Vec<String>, groups: Vec<DriveGroup>) -> DriveConfig {
+		DriveConfig {
+			smartctl,
+			smartctl_interval: Duration::from_secs(3600),
+			groups
+		}
+	}
+
+	fn hwmon(root: &Path, idx: usize, driver: &str, device: &Path, millidegrees: &str) {
+		let dir = root.join(format!("hwmon{}", idx));
+		::std::fs::create_dir_all(&dir).unwrap();
+		::std::fs::write(dir.join("name"), format!("{}\n", driver)).unwrap();
+		::std::fs::write(dir.join("temp1_input"), format!("{}\n", millidegrees)).unwrap();
+		::std::os::unix::fs::symlink(device, dir.join("device")).unwrap();
+	}
+
+	#[test]
+	fn reads_hwmon_drives() {
+		let root = ::std::env::temp_dir().join(format!("twd-hwmon-{}", ::std::process::id()));
+		::std::fs::remove_dir_all(&root).unwrap_or(());
+
+		let devices = root.join("devices");
+		::std::fs::create_dir_all(devices.join("nvme1")).unwrap();
+		::std::fs::create_dir_all(devices.join("0:0:0:0").join("block").join("sda")).unwrap();
+
+		let class = root.join("hwmon");
+		hwmon(&class, 0, "coretemp", &devices, "45000");
+		hwmon(&class, 1, "nvme", &devices.join("nvme1"), "41850");
+		hwmon(&class, 2, "drivetemp", &devices.join("0:0:0:0"), "36000");
+
+		assert_eq!(hwmon_temps(&class), vec!(("nvme1".to_string(), 42), ("sda".to_string(), 36)));
+
+		let mut source = DriveSource::start(config(vec!(), vec!(
+			DriveGroup { name: "Drives".to_string(), drives: vec!(), aggregate: GroupAggregate::Avg },
+			DriveGroup { name: "Bay".to_string(), drives: vec!("sda".to_string(), "sdb".to_string()), aggregate: GroupAggregate::Max }
+		)));
+		source.hwmon_root = class;
+
+		let values = source.read().into_iter().map(|v| (v.name, v.value)).collect::<Vec<_>>();
+		assert_eq!(values, vec!(
+			("nvme1".to_string(), IPMIValue::Temp(42)),
+			("sda".to_string(), IPMIValue::Temp(36)),
+			("Drives".to_string(), IPMIValue::Temp(39)),
+			("Bay".to_string(), IPMIValue::Invalid)
+		));
+
+		::std::fs::remove_dir_all(&root).unwrap_or(());
+	}
+
+	#[test]
+	fn reads_smartctl_in_background() {
+		// The device can't be read whether or not smartctl is installed, so the drive reads as invalid once tried
+		let mut source = DriveSource::start(config(vec!("/dev/twd-missing".to_string()), vec!()));
+		source.hwmon_root = PathBuf::from("/nonexistent");
+
+		let started = ::std::time::Instant::now();
+		while !source.ready() && started.elapsed() < Duration::from_secs(10) {
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert!(source.ready());
+		assert_eq!(source.read(), vec!(entry("twd-missing", IPMIValue::Invalid)));
+
+		// A reloaded source starts from the readings of the one it replaces until its own first read
+		let mut source = DriveSource::start(config(vec!(), vec!()));
+		source.hwmon_root = PathBuf::from("/nonexistent");
+		source.smartctl = vec!("/dev/sdb".to_string());
+		assert!(!source.ready());
+
+		source.seed(&[entry("Inlet Temp", IPMIValue::Temp(21)), entry("sdb", IPMIValue::Temp(33))]);
+		assert!(source.ready());
+		assert_eq!(source.read(), vec!(entry("sdb", IPMIValue::Temp(33))));
+	}
+
+	#[test]
+	fn parses_smartctl_json() {
+		let json = r#"{"json_format_version":[1,0],"device":{"name":"/dev/sda","type":"sat"},"temperature":{"current":34,"drive_trip":60}}"#;
+
+		assert_eq!(parse_smartctl(json), Ok(Some(34)));
+		assert!(parse_smartctl(r#"{"device":{"name":"/dev/sda"}}"#).is_err());
+
+		let standby = r#"{"json_format_version":[1,0],"smartctl":{"exit_status":2,"messages":[{"string":"Device is in STANDBY mode, exit(2)","severity":"information"}]},"device":{"name":"/dev/sdb","type":"sat"}}"#;
+		assert_eq!(parse_smartctl(standby), Ok(None));
+		assert!(parse_smartctl("smartctl: command not found").is_err());
+		assert_eq!(drive_name("/dev/sda"), "sda");
+	}
+}
@@ -0,0 +1,292 @@
+/// Arithmetic over named values, I.E. "exhaust - inlet" or "max(cpu1, cpu2) - 10".
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+	Num(f32),
+	Var(String),
+	Neg(Box<Expr>),
+	Bin(BinOp, Box<Expr>, Box<Expr>),
+	Call(Func, Vec<Expr>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+	Add,
+	Sub,
+	Mul,
+	Div
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Func {
+	Max,
+	Min,
+	Avg
+}
+
+impl Expr {
+	pub fn parse(source: &str) -> Result<Expr, String> {
+		let tokens = tokenize(source)?;
+		let mut parser = Parser { tokens: &tokens, pos: 0 };
+
+		let expr = parser.expr()?;
+
+		match parser.peek() {
+			None => Ok(expr),
+			Some(token) => Err(format!("unexpected {:?} in \"{}\"", token, source))
+		}
+	}
+
+	/// Every variable referenced, in order of first use.
+	pub fn vars(&self) -> Vec<&str> {
+		let mut vars = vec!();
+		self.collect_vars(&mut vars);
+
+		vars
+	}
+
+	fn collect_vars<'a>(&'a self, vars: &mut Vec<&'a str>) {
+		match self {
+			Expr::Num(_) => (),
+			Expr::Var(name) => if !vars.contains(&name.as_str()) {
+				vars.push(name)
+			},
+			Expr::Neg(v) => v.collect_vars(vars),
+			Expr::Bin(_, a, b) => {
+				a.collect_vars(vars);
+				b.collect_vars(vars);
+			},
+			Expr::Call(_, args) => for arg in args {
+				arg.collect_vars(vars);
+			}
+		}
+	}
+
+	pub fn eval(&self, var: &dyn Fn(&str) -> Option<f32>) -> Result<f32, String> {
+		let value = match self {
+			Expr::Num(v) => *v,
+			Expr::Var(name) => var(name).ok_or_else(|| format!("no value for {}", name))?,
+			Expr::Neg(v) => -v.eval(var)?,
+			Expr::Bin(op, a, b) => {
+				let (a, b) = (a.eval(var)?, b.eval(var)?);
+
+				match op {
+					BinOp::Add => a + b,
+					BinOp::Sub => a - b,
+					BinOp::Mul => a * b,
+					BinOp::Div if b == 0.0 => return Err("division by zero".to_string()),
+					BinOp::Div => a / b
+				}
+			},
+			Expr::Call(func, args) => {
+				let values = args.iter().map(|v| v.eval(var)).collect::<Result<Vec<_>, _>>()?;
+
+				match func {
+					Func::Max => values.iter().cloned().fold(f32::MIN, f32::max),
+					Func::Min => values.iter().cloned().fold(f32::MAX, f32::min),
+					Func::Avg => values.iter().sum::<f32>() / values.len() as f32
+				}
+			}
+		};
+
+		Ok(value)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	Num(f32),
+	Ident(String),
+	Op(char),
+	Open,
+	Close,
+	Comma
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+	let mut tokens = vec!();
+	let mut chars = source.chars().peekable();
+
+	while let Some(c) = chars.peek().cloned() {
+		match c {
+			' ' | '\t' => {
+				chars.next();
+			},
+			'+' | '-' | '*' | '/' => {
+				chars.next();
+				tokens.push(Token::Op(c));
+			},
+			'(' => {
+				chars.next();
+				tokens.push(Token::Open);
+			},
+			')' => {
+				chars.next();
+				tokens.push(Token::Close);
+			},
+			',' => {
+				chars.next();
+				tokens.push(Token::Comma);
+			},
+			'0'..='9' | '.' => {
+				let mut num = String::new();
+				while let Some(c) = chars.peek().cloned().filter(|v| v.is_ascii_digit() || *v == '.') {
+					num.push(c);
+					chars.next();
+				}
+
+				tokens.push(Token::Num(num.parse().map_err(|_| format!("invalid number \"{}\" in \"{}\"", num, source))?));
+			},
+			c if c.is_ascii_alphabetic() || c == '_' => {
+				let mut ident = String::new();
+				while let Some(c) = chars.peek().cloned().filter(|v| v.is_ascii_alphanumeric() || *v == '_') {
+					ident.push(c);
+					chars.next();
+				}
+
+				tokens.push(Token::Ident(ident));
+			},
+			_ => return Err(format!("unexpected \"{}\" in \"{}\"", c, source))
+		}
+	}
+
+	Ok(tokens)
+}
+
+/// Recursive descent over expr := term (+|- term)*, term := unary (*|/ unary)*, unary := -unary | atom.
+struct Parser<'a> {
+	tokens: &'a [Token],
+	pos: usize
+}
+
+impl<'a> Parser<'a> {
+	fn peek(&self) -> Option<&'a Token> {
+		self.tokens.get(self.pos)
+	}
+
+	fn next(&mut self) -> Option<&'a Token> {
+		let token = self.tokens.get(self.pos);
+		self.pos += 1;
+
+		token
+	}
+
+	fn expr(&mut self) -> Result<Expr, String> {
+		let mut expr = self.term()?;
+
+		while let Some(Token::Op(op)) = self.peek() {
+			let op = match op {
+				'+' => BinOp::Add,
+				'-' => BinOp::Sub,
+				_ => break
+			};
+			self.next();
+
+			expr = Expr::Bin(op, Box::new(expr), Box::new(self.term()?));
+		}
+
+		Ok(expr)
+	}
+
+	fn term(&mut self) -> Result<Expr, String> {
+		let mut expr = self.unary()?;
+
+		while let Some(Token::Op(op)) = self.peek() {
+			let op = match op {
+				'*' => BinOp::Mul,
+				'/' => BinOp::Div,
+				_ => break
+			};
+			self.next();
+
+			expr = Expr::Bin(op, Box::new(expr), Box::new(self.unary()?));
+		}
+
+		Ok(expr)
+	}
+
+	fn unary(&mut self) -> Result<Expr, String> {
+		if let Some(Token::Op('-')) = self.peek() {
+			self.next();
+			return Ok(Expr::Neg(Box::new(self.unary()?)))
+		}
+
+		self.atom()
+	}
+
+	fn atom(&mut self) -> Result<Expr, String> {
+		match self.next() {
+			Some(Token::Num(v)) => Ok(Expr::Num(*v)),
+			Some(Token::Open) => {
+				let expr = self.expr()?;
+
+				match self.next() {
+					Some(Token::Close) => Ok(expr),
+					_ => Err("missing \")\"".to_string())
+				}
+			},
+			Some(Token::Ident(name)) if self.peek() == Some(&Token::Open) => {
+				let func = match name.as_str() {
+					"max" => Func::Max,
+					"min" => Func::Min,
+					"avg" => Func::Avg,
+					_ => return Err(format!("unknown function \"{}\", expected max, min or avg", name))
+				};
+				self.next();
+
+				let mut args = vec!(self.expr()?);
+				loop {
+					match self.next() {
+						Some(Token::Comma) => args.push(self.expr()?),
+						Some(Token::Close) => break,
+						_ => return Err(format!("missing \")\" after arguments to {}", name))
+					}
+				}
+
+				Ok(Expr::Call(func, args))
+			},
+			Some(Token::Ident(name)) => Ok(Expr::Var(name.clone())),
+			Some(token) => Err(format!("unexpected {:?}", token)),
+			None => Err("unexpected end of expression".to_string())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn eval(source: &str) -> Result<f32, String> {
+		let vars = |name: &str| match name {
+			"exhaust" => Some(42.0),
+			"inlet" => Some(30.0),
+			"cpu1" => Some(61.0),
+			"cpu2" => Some(55.0),
+			_ => None
+		};
+
+		Expr::parse(source)?.eval(&vars)
+	}
+
+	#[test]
+	fn evaluates_expressions() {
+		assert_eq!(eval("exhaust - inlet"), Ok(12.0));
+		assert_eq!(eval("max(cpu1, cpu2)"), Ok(61.0));
+		assert_eq!(eval("avg(cpu1, cpu2, exhaust)"), Ok(52.666668));
+		assert_eq!(eval("min(cpu1, cpu2) - inlet * 2 / 4"), Ok(40.0));
+		assert_eq!(eval("-(exhaust - inlet) + 1.5"), Ok(-10.5));
+
+		assert!(eval("exhaust - outlet").is_err());
+		assert!(eval("exhaust / (inlet - 30)").is_err());
+	}
+
+	#[test]
+	fn rejects_invalid_expressions() {
+		assert!(Expr::parse("exhaust -").is_err());
+		assert!(Expr::parse("max(cpu1, cpu2").is_err());
+		assert!(Expr::parse("median(cpu1)").is_err());
+		assert!(Expr::parse("cpu1 cpu2").is_err());
+		assert!(Expr::parse("cpu1 % 2").is_err());
+
+		assert_eq!(Expr::parse("max(cpu1, cpu2) - cpu1").unwrap().vars(), vec!("cpu1", "cpu2"));
+	}
+}
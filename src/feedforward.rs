@@ -0,0 +1,172 @@
+/// How CPU load maps to a duty contribution.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mapping {
+	/// Duty added per unit of load(0.0-1.0).
+	Gain(f32),
+	/// (load, duty) points, interpolated and clamped to the ends.
+	Curve(Vec<(f32, f32)>)
+}
+
+impl Mapping {
+	pub fn duty(&self, load: f32) -> f32 {
+		match self {
+			Mapping::Gain(gain) => gain * load,
+			Mapping::Curve(points) => interpolate(points, load)
+		}
+	}
+}
+
+fn interpolate(points: &[(f32, f32)], x: f32) -> f32 {
+	let (first, last) = match (points.first(), points.last()) {
+		(Some(first), Some(last)) => (first, last),
+		_ => return 0.0
+	};
+
+	if x <= first.0 {
+		return first.1
+	}
+
+	if x >= last.0 {
+		return last.1
+	}
+
+	points.windows(2)
+		.find(|v| x >= v[0].0 && x <= v[1].0)
+		.map(|v| {
+			let span = v[1].0 - v[0].0;
+
+			if span <= 0.0 {
+				v[0].1
+			} else {
+				v[0].1 + (v[1].1 - v[0].1) * (x - v[0].0) / span
+			}
+		})
+		.unwrap_or(last.1)
+}
+
+/// Shapes load so fans react as soon as it rises but back off slowly once it falls.
+///
+/// Rising load is boosted by `lead` seconds worth of its rate of change and passed through immediately,
+/// falling load decays towards the current value with a time constant of `decay` seconds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeadFilter {
+	pub lead: f32,
+	pub decay: f32,
+	last_input: Option<f32>,
+	output: f32
+}
+
+impl LeadFilter {
+	pub fn new(lead: f32, decay: f32) -> LeadFilter {
+		LeadFilter {
+			lead,
+			decay,
+			last_input: None,
+			output: 0.0
+		}
+	}
+
+	/// `elapsed` is in seconds.
+	pub fn update(&mut self, input: f32, elapsed: f32) -> f32 {
+		let rate = match self.last_input {
+			Some(last) if elapsed > 0.0 => (input - last) / elapsed,
+			_ => 0.0
+		};
+		self.last_input = Some(input);
+
+		let target = (input + self.lead * rate.max(0.0)).clamp(0.0, 1.0);
+
+		if target >= self.output || self.decay <= 0.0 {
+			self.output = target;
+		} else {
+			self.output += (target - self.output) * (1.0 - (-elapsed / self.decay).exp());
+		}
+
+		self.output
+	}
+}
+
+/// Feed-forward term for a single control, anticipating temperature rises from CPU load.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedForward {
+	mapping: Mapping,
+	filter: LeadFilter,
+	last: f32
+}
+
+impl FeedForward {
+	pub fn new(mapping: Mapping, lead: f32, decay: f32) -> FeedForward {
+		FeedForward {
+			mapping,
+			filter: LeadFilter::new(lead, decay),
+			last: 0.0
+		}
+	}
+
+	/// Carries over filter state from the feed-forward this one replaces.
+	pub fn inherit(&mut self, previous: &FeedForward) {
+		self.filter.last_input = previous.filter.last_input;
+		self.filter.output = previous.filter.output;
+		self.last = previous.last;
+	}
+
+	/// Duty to add to the PID output for the current load, 0.0 if load is unknown. `elapsed` is in seconds.
+	pub fn update(&mut self, load: Option<f32>, elapsed: f32) -> f32 {
+		self.last = match load {
+			Some(load) => self.mapping.duty(self.filter.update(load, elapsed)),
+			None => 0.0
+		};
+
+		self.last
+	}
+
+	/// Term from the most recent update.
+	pub fn term(&self) -> f32 {
+		self.last
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn maps_load() {
+		assert_eq!(Mapping::Gain(0.4).duty(0.5), 0.2);
+
+		let curve = Mapping::Curve(vec!((0.2, 0.0), (0.6, 0.2), (1.0, 0.3)));
+		assert_eq!(curve.duty(0.0), 0.0);
+		assert_eq!(curve.duty(0.4), 0.1);
+		assert_eq!(curve.duty(0.8), 0.25);
+		assert_eq!(curve.duty(1.0), 0.3);
+	}
+
+	#[test]
+	fn leads_rising_load_and_decays_falling_load() {
+		let mut filter = LeadFilter::new(2.0, 10.0);
+
+		assert_eq!(filter.update(0.1, 1.0), 0.1);
+		// 0.2/s rise with a 2s lead
+		assert!((filter.update(0.3, 1.0) - 0.7).abs() < 1e-6);
+		// Steady load, decays back towards it rather than dropping
+		let decayed = filter.update(0.3, 1.0);
+		assert!(decayed < 0.7 && decayed > 0.6);
+
+		for _ in 0..100 {
+			filter.update(0.3, 1.0);
+		}
+		assert!((filter.update(0.3, 1.0) - 0.3).abs() < 1e-3);
+
+		// Boost is clamped to full load
+		assert_eq!(filter.update(1.0, 1.0), 1.0);
+	}
+
+	#[test]
+	fn unknown_load_contributes_nothing() {
+		let mut ff = FeedForward::new(Mapping::Gain(0.5), 0.0, 0.0);
+
+		assert_eq!(ff.update(Some(0.5), 1.0), 0.25);
+		assert_eq!(ff.update(None, 1.0), 0.0);
+		assert_eq!(ff.term(), 0.0);
+	}
+}
@@ -1,75 +1,211 @@
-use std::process::Command;
 use std::io::{Error, ErrorKind, Result};
 
-#[derive(Debug)]
+use crate::ipmitool::{self, Op, Runner};
+use crate::vendor::{format_raw, FanProfile};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum IPMIValue {
 	Unknown,
 	Invalid,
+	/// More than one SDR entry matched the selector, holds the number of matches.
+	Ambiguous(usize),
 	Temp(i32),
+	// Serialized by name in recorded traces
+	#[allow(clippy::upper_case_acronyms)]
 	RPM(u32)
 }
 
-pub struct IPMIRequest {
+/// Identifies a sensor by name, optionally narrowed by sensor number and entity as shown by `ipmitool sdr elist`.
+///
+/// Names alone aren't unique on many boards(I.E. "Temp" for each CPU) and SDR order can change across
+/// firmware updates, so sensor number or entity id/instance are used to pin a specific sensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorSelector {
 	pub name: String,
+	pub number: Option<u8>,
+	pub entity: Option<(u8, u8)>
+}
+
+impl SensorSelector {
+	pub fn new(name: &str, number: Option<&str>, entity: Option<&str>) -> ::std::result::Result<SensorSelector, String> {
+		let number = number.map(|v| parse_sensor_number(v)
+				.ok_or_else(|| format!("invalid sensor number \"{}\" for {}, expected a value like \"0Eh\"", v, name)))
+			.transpose()?;
+		let entity = entity.map(|v| parse_entity(v)
+				.ok_or_else(|| format!("invalid entity \"{}\" for {}, expected a value like \"3.1\"", v, name)))
+			.transpose()?;
+
+		Ok(SensorSelector {
+			name: name.to_string(),
+			number,
+			entity
+		})
+	}
+
+	pub fn matches(&self, entry: &SdrEntry) -> bool {
+		self.matches_parts(&entry.name, entry.number, entry.entity)
+	}
+
+	pub fn matches_sensor(&self, sensor: &SensorInfo) -> bool {
+		self.matches_parts(&sensor.name, sensor.number, sensor.entity)
+	}
+
+	fn matches_parts(&self, name: &str, number: Option<u8>, entity: Option<(u8, u8)>) -> bool {
+		name == self.name
+			&& self.number.map(|v| number == Some(v)).unwrap_or(true)
+			&& self.entity.map(|v| entity == Some(v)).unwrap_or(true)
+	}
+
+	/// Human readable name that stays the same regardless of SDR order, used for metric tags and status.
+	pub fn label(&self) -> String {
+		let mut label = self.name.clone();
+
+		if let Some((id, instance)) = self.entity {
+			label += format!(" {}.{}", id, instance).as_str();
+		}
+
+		if let Some(number) = self.number {
+			label += format!(" {:02X}h", number).as_str();
+		}
+
+		label
+	}
+}
+
+fn parse_sensor_number(v: &str) -> Option<u8> {
+	let v = v.trim();
+
+	if let Some(hex) = v.strip_suffix('h').or_else(|| v.strip_suffix('H')) {
+		u8::from_str_radix(hex, 16).ok()
+	} else if let Some(hex) = v.strip_prefix("0x").or_else(|| v.strip_prefix("0X")) {
+		u8::from_str_radix(hex, 16).ok()
+	} else {
+		v.parse().ok()
+	}
+}
+
+fn parse_entity(v: &str) -> Option<(u8, u8)> {
+	let mut parts = v.trim().splitn(2, '.');
+	let id = parts.next()?.trim().parse().ok()?;
+	let instance = parts.next()?.trim().parse().ok()?;
+
+	Some((id, instance))
+}
+
+/// A single line from `ipmitool sdr elist full`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SdrEntry {
+	pub name: String,
+	pub number: Option<u8>,
+	pub entity: Option<(u8, u8)>,
+	pub value: IPMIValue
+}
+
+/// Sensors read from somewhere other than the BMC, merged with SDR entries so controls can watch them.
+pub trait SensorSource {
+	fn read(&mut self) -> Vec<SdrEntry>;
+
+	/// False until a source read in the background has tried to take its first readings.
+	fn ready(&self) -> bool {
+		true
+	}
+
+	/// Fills readings that haven't been taken yet from the last pass of the loop being replaced on reload.
+	fn seed(&mut self, _previous: &[SdrEntry]) {}
+}
+
+pub struct IPMIRequest {
+	pub selector: SensorSelector,
 	pub status: IPMIValue
 }
 
-pub fn get_ipmi_values(values: &mut Vec<IPMIRequest>) -> Result<()> {
-	for value in values.iter_mut() {
-		value.status = IPMIValue::Unknown;
+pub fn get_sdr_entries() -> Result<Vec<SdrEntry>> {
+	read_sdr_entries(&ipmitool::run)
+}
+
+pub fn read_sdr_entries(run: &Runner) -> Result<Vec<SdrEntry>> {
+	let cmd = run(Op::ReadSensors, &["sdr", "elist", "full"])?;
+
+	let entries = cmd.stdout.lines().filter_map(parse_sdr_line).collect();
+
+	if !cmd.success {
+		return Err(Error::new(ErrorKind::InvalidData, format!("Reading sensors failed, {}", cmd.stderr.trim())));
 	}
 
-	let cmd = Command::new("ipmitool")
-		.args(&["sdr", "list", "full"])
-		.output()?;
+	Ok(entries)
+}
 
-	let output = ::std::str::from_utf8(&cmd.stdout[..])
-		.map_err(|_| Error::new(ErrorKind::InvalidData, "Unable to parse command output, invalid utf-8"))?;
+/// Reads every sensor and resolves `values` against them, returning all entries read.
+pub fn get_ipmi_values(values: &mut [IPMIRequest]) -> Result<Vec<SdrEntry>> {
+	read_ipmi_values(values, &ipmitool::run)
+}
 
-	let lines = output.lines();
+pub fn read_ipmi_values(values: &mut [IPMIRequest], run: &Runner) -> Result<Vec<SdrEntry>> {
+	for value in values.iter_mut() {
+		value.status = IPMIValue::Unknown;
+	}
 
-	for line in lines {
-		let mut cols = line.split("|");
+	let entries = read_sdr_entries(run)?;
+	resolve_ipmi_values(values, &entries);
 
-		let name = cols.next();
-		let value = cols.next();
+	Ok(entries)
+}
 
-		let name = name.map_or(Err(()), |v| Ok(v.trim()));
-		let status = value.map_or(Err(()), |v| Ok(v.trim()));
+pub fn resolve_ipmi_values(values: &mut [IPMIRequest], entries: &[SdrEntry]) {
+	for value in values.iter_mut() {
+		let mut matches = entries.iter().filter(|v| value.selector.matches(v));
 
-		if let Ok((name, status)) = name.and_then(|n| status.map(|v| (n,parse_ipmi_value(n,v)))) {
-			for value in values.iter_mut() {
-				if let IPMIValue::Unknown = value.status {
-					if value.name == name {
-						trace!("Read {:?} for {}", status, name);
-						value.status = status;
-						break;
-					}
-				}
-			}
-		}
+		value.status = match (matches.next(), matches.count()) {
+			(None, _) => IPMIValue::Unknown,
+			(Some(entry), 0) => {
+				trace!("Read {:?} for {}", entry.value, value.selector.label());
+				entry.value.clone()
+			},
+			(Some(_), others) => IPMIValue::Ambiguous(others + 1)
+		};
 	}
+}
+
+/// Parses `sdr elist` lines(name | number | status | entity | reading), falling back to `sdr list` lines(name | reading | status).
+fn parse_sdr_line(line: &str) -> Option<SdrEntry> {
+	let cols = line.split('|').map(|v| v.trim()).collect::<Vec<_>>();
 
-	if !cmd.status.success() {
-		return Err(Error::new(ErrorKind::InvalidData, "Command returned non-zero error code"));
+	if cols[0].is_empty() {
+		return None
 	}
 
-	Ok(())
+	if cols.len() >= 5 {
+		Some(SdrEntry {
+			name: cols[0].to_string(),
+			number: parse_sensor_number(cols[1]),
+			entity: parse_entity(cols[3]),
+			value: parse_ipmi_value(cols[0], cols[4])
+		})
+	} else if cols.len() >= 2 {
+		Some(SdrEntry {
+			name: cols[0].to_string(),
+			number: None,
+			entity: None,
+			value: parse_ipmi_value(cols[0], cols[1])
+		})
+	} else {
+		None
+	}
 }
 
 fn parse_ipmi_value(name: &str, value: &str) -> IPMIValue {
-	let first_ws = value.find(" ");
+	let first_ws = value.find(' ');
 
 	if let Some(first_ws) = first_ws {
 		let (data, label) = value.split_at(first_ws);
 
 		let parsed = match label {
-			" RPM" => u32::from_str_radix(data, 10)
+			" RPM" => data.parse::<u32>()
 						.map_err(|e| format!("Unable to parse {} for {}, {:?}", data, name, e))
-						.map(|v| IPMIValue::RPM(v)),
-			" degrees C" => i32::from_str_radix(data, 10)
+						.map(IPMIValue::RPM),
+			" degrees C" => data.parse::<i32>()
 						.map_err(|e| format!("Unable to parse {} for {}, {:?}", data, name, e))
-						.map(|v| IPMIValue::Temp(v)),
+						.map(IPMIValue::Temp),
 			_ => Ok(IPMIValue::Unknown)
 		};
 
@@ -85,43 +221,346 @@ fn parse_ipmi_value(name: &str, value: &str) -> IPMIValue {
 	}
 }
 
-pub fn ipmi_set_fan_manual(manual: bool) -> Result<()> {
+pub fn ipmi_set_fan_manual(profile: &FanProfile, manual: bool) -> Result<()> {
 	info!("Setting fan manual control: {}", manual);
 
-	let enabled = if manual {
-		"0x00"
-	} else {
-		"0x01"
-	};
-
-	let cmd = Command::new("ipmitool")
-		.args(&["raw", "0x30", "0x30", "0x01", enabled])
-		.output()?;
-
-	if !cmd.status.success() {
-		let output = ::std::str::from_utf8(&cmd.stdout[..]).unwrap_or("Invalid UTF-8");
-		return Err(Error::new(ErrorKind::InvalidData, format!("Manual fan control failed, returned {}, {}", cmd.status.code().unwrap_or(-1), output)))
+	for command in profile.mode_commands(manual) {
+		run_raw(Op::FanMode, &command)
+			.map_err(|e| Error::new(e.kind(), format!("Manual fan control failed, {}", e)))?;
 	}
 
 	Ok(())
 }
 
-pub fn ipmi_set_fan_speed(speed: f32) -> Result<()> {
+pub fn ipmi_set_fan_speed(profile: &FanProfile, speed: f32) -> Result<()> {
 	info!("Setting fan speed to {}", speed);
 
-	let scale = (speed * 100.0).ceil().min(100.0).max(0.0) as usize;
-	let hex = format!("0x{:02x}", scale);
+	for command in profile.speed_commands(speed) {
+		run_raw(Op::FanSpeed, &command)
+			.map_err(|e| Error::new(e.kind(), format!("Fan control speed failed, {}", e)))?;
+	}
+
+	Ok(())
+}
+
+fn run_raw(op: Op, bytes: &[u8]) -> Result<()> {
+	let formatted = format_raw(bytes);
+	trace!("Sending raw {}", formatted.join(" "));
 
-	info!("Setting fan speed to {} {}", hex, scale);
+	let args = ::std::iter::once("raw")
+		.chain(formatted.iter().map(|v| v.as_str()))
+		.collect::<Vec<_>>();
 
-	let cmd = Command::new("ipmitool")
-		.args(&["raw", "0x30", "0x30", "0x02", "0xff", hex.as_str()])
-		.output()?;
+	let cmd = ipmitool::run(op, &args)?;
 
-	if !cmd.status.success() {
-		let output = ::std::str::from_utf8(&cmd.stdout[..]).unwrap_or("Invalid UTF-8");
-		return Err(Error::new(ErrorKind::InvalidData, format!("Fan control speed failed, returned {}, {}", cmd.status.code().unwrap_or(-1), output)))
+	if !cmd.success {
+		return Err(Error::new(ErrorKind::InvalidData, format!("{} {}", cmd.stderr.trim(), cmd.stdout.trim())))
 	}
 
 	Ok(())
 }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorKind {
+	Temp,
+	Fan,
+	Power,
+	Other
+}
+
+impl SensorKind {
+	fn from_unit(unit: &str) -> SensorKind {
+		match unit {
+			"degrees C" => SensorKind::Temp,
+			"RPM" => SensorKind::Fan,
+			"Watts" | "Amps" | "Volts" => SensorKind::Power,
+			_ => SensorKind::Other
+		}
+	}
+}
+
+/// Margin kept between the BMC's upper limit for a sensor and a failsafe derived from it.
+pub const FAILSAFE_MARGIN: f32 = 5.0;
+
+/// BMC thresholds for a sensor, None where the BMC reports "na".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Thresholds {
+	pub lower_non_recoverable: Option<f32>,
+	pub lower_critical: Option<f32>,
+	pub lower_non_critical: Option<f32>,
+	pub upper_non_critical: Option<f32>,
+	pub upper_critical: Option<f32>,
+	pub upper_non_recoverable: Option<f32>
+}
+
+impl Thresholds {
+	/// Value at which the BMC considers the sensor critical.
+	pub fn critical(&self) -> Option<f32> {
+		self.upper_critical.or(self.upper_non_recoverable)
+	}
+
+	/// Most meaningful upper limit the BMC reports, falling back to non-critical if there is no critical threshold.
+	pub fn upper_limit(&self) -> Option<f32> {
+		self.critical().or(self.upper_non_critical)
+	}
+
+	pub fn default_failsafe(&self) -> Option<f32> {
+		self.upper_limit().map(|v| v - FAILSAFE_MARGIN)
+	}
+}
+
+/// A single entry from `ipmitool sensor list`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorInfo {
+	pub name: String,
+	/// Sensor number and entity aren't part of `sensor list`, see `join_sdr_entries`.
+	pub number: Option<u8>,
+	pub entity: Option<(u8, u8)>,
+	pub kind: SensorKind,
+	pub value: Option<f32>,
+	/// Reading as printed by ipmitool, for discrete sensors that have no numeric value.
+	pub reading: String,
+	pub unit: String,
+	pub status: String,
+	pub thresholds: Thresholds
+}
+
+/// Reads every sensor the BMC knows about along with its thresholds.
+pub fn get_sensor_list() -> Result<Vec<SensorInfo>> {
+	let cmd = ipmitool::run(Op::ReadThresholds, &["sensor", "list"])?;
+
+	if !cmd.success {
+		return Err(Error::new(ErrorKind::InvalidData, format!("Reading sensor thresholds failed, {}", cmd.stderr.trim())));
+	}
+
+	Ok(cmd.stdout.lines().filter_map(parse_sensor_line).collect())
+}
+
+/// Reads every sensor with its thresholds, identified by sensor number and entity where the BMC reports them.
+pub fn get_sensor_thresholds() -> Result<Vec<SensorInfo>> {
+	let mut sensors = get_sensor_list()?;
+
+	match get_sdr_entries() {
+		Ok(entries) => join_sdr_entries(&mut sensors, &entries),
+		Err(e) => warn!("Unable to read sensor numbers and entities: {}", e)
+	}
+
+	Ok(sensors)
+}
+
+/// Fills in sensor number and entity from `sdr elist` entries.
+///
+/// Both commands list sensors in SDR order, so duplicate names are paired up by occurrence.
+pub fn join_sdr_entries(sensors: &mut [SensorInfo], entries: &[SdrEntry]) {
+	for idx in 0..sensors.len() {
+		let occurrence = sensors[..idx].iter().filter(|v| v.name == sensors[idx].name).count();
+
+		if let Some(entry) = entries.iter().filter(|v| v.name == sensors[idx].name).nth(occurrence) {
+			sensors[idx].number = entry.number;
+			sensors[idx].entity = entry.entity;
+		}
+	}
+}
+
+fn parse_sensor_line(line: &str) -> Option<SensorInfo> {
+	let cols = line.split('|').map(|v| v.trim()).collect::<Vec<_>>();
+
+	if cols.len() < 10 || cols[0].is_empty() {
+		return None
+	}
+
+	let number = |v: &str| v.parse::<f32>().ok();
+
+	Some(SensorInfo {
+		name: cols[0].to_string(),
+		number: None,
+		entity: None,
+		kind: SensorKind::from_unit(cols[2]),
+		value: number(cols[1]),
+		reading: cols[1].to_string(),
+		unit: cols[2].to_string(),
+		status: cols[3].to_string(),
+		thresholds: Thresholds {
+			lower_non_recoverable: number(cols[4]),
+			lower_critical: number(cols[5]),
+			lower_non_critical: number(cols[6]),
+			upper_non_critical: number(cols[7]),
+			upper_critical: number(cols[8]),
+			upper_non_recoverable: number(cols[9])
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::path::{Path, PathBuf};
+
+	/// Hand-written `sdr list`/`sdr elist` output in the layout each board's BMC prints, with sample readings, plus
+	/// `malformed_output.txt` for garbage lines. Each has the entries it parses to in a matching hand-checked `.golden` file.
+	const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sdr");
+
+	/// Answers every command with the `sdr list`/`sdr elist` output of a fixture.
+	fn replay_fixture(fixture: &Path) -> impl Fn(Op, &[&str]) -> Result<ipmitool::Output> {
+		let stdout = ::std::fs::read(fixture).unwrap();
+
+		move |op, _| {
+			assert_eq!(op, Op::ReadSensors);
+			Ok(ipmitool::Output::from_bytes(true, &stdout, b""))
+		}
+	}
+
+	fn fixtures() -> Vec<PathBuf> {
+		let mut fixtures = ::std::fs::read_dir(FIXTURES).unwrap()
+			.map(|v| v.unwrap().path())
+			.filter(|v| v.extension().map(|v| v == "txt").unwrap_or(false))
+			.collect::<Vec<_>>();
+		fixtures.sort();
+
+		fixtures
+	}
+
+	fn render(entries: &[SdrEntry]) -> String {
+		entries.iter()
+			.map(|v| format!("{} | {} | {} | {:?}\n",
+				v.name,
+				v.number.map(|v| format!("{:02X}h", v)).unwrap_or_else(|| "-".to_string()),
+				v.entity.map(|(id, instance)| format!("{}.{}", id, instance)).unwrap_or_else(|| "-".to_string()),
+				v.value))
+			.collect()
+	}
+
+	/// Set UPDATE_GOLDEN to rewrite the golden files after an intended parsing change, then check every changed line by hand.
+	#[test]
+	fn parses_sdr_fixtures() {
+		let fixtures = fixtures();
+		assert!(!fixtures.is_empty());
+
+		for fixture in fixtures {
+			let rendered = render(&read_sdr_entries(&replay_fixture(&fixture)).unwrap());
+			let golden = fixture.with_extension("golden");
+
+			if ::std::env::var_os("UPDATE_GOLDEN").is_some() {
+				::std::fs::write(&golden, &rendered).unwrap();
+				continue
+			}
+
+			let expected = ::std::fs::read_to_string(&golden)
+				.unwrap_or_else(|e| panic!("unable to read {}, run with UPDATE_GOLDEN=1 to create it: {}", golden.display(), e));
+			assert_eq!(rendered, expected, "{} no longer parses to {}", fixture.display(), golden.display());
+		}
+	}
+
+	#[test]
+	fn resolves_sdr_fixtures() {
+		let request = |name: &str, entity: Option<&str>| IPMIRequest { selector: SensorSelector::new(name, None, entity).unwrap(), status: IPMIValue::Invalid };
+		let read = |fixture: &str, values: &mut [IPMIRequest]| read_ipmi_values(values, &replay_fixture(&Path::new(FIXTURES).join(fixture))).unwrap();
+
+		let mut values = vec!(request("Temp", None), request("Ambient Temp", None), request("FAN MOD 4B RPM", None), request("Planar Temp", None));
+		read("r710_list.txt", &mut values);
+		assert_eq!(values.iter().map(|v| v.status.clone()).collect::<Vec<_>>(), vec!(IPMIValue::Ambiguous(3), IPMIValue::Temp(24), IPMIValue::RPM(2520), IPMIValue::Unknown));
+
+		let mut values = vec!(request("Temp", Some("3.2")), request("Exhaust Temp", None), request("Fan6", None), request("Current 2", None));
+		read("r720_elist.txt", &mut values);
+		assert_eq!(values.iter().map(|v| v.status.clone()).collect::<Vec<_>>(), vec!(IPMIValue::Temp(37), IPMIValue::Temp(30), IPMIValue::Unknown, IPMIValue::Unknown));
+
+		let mut values = vec!(request("Peripheral Temp", None), request("FAN2", None), request("FAN3", None));
+		read("supermicro_x9_list.txt", &mut values);
+		assert_eq!(values.iter().map(|v| v.status.clone()).collect::<Vec<_>>(), vec!(IPMIValue::Temp(-2), IPMIValue::Unknown, IPMIValue::RPM(1375)));
+
+		let failed = |_: Op, _: &[&str]| Ok(ipmitool::Output::from_bytes(false, b"", b"Error: Unable to establish IPMI v2 / RMCP+ session\n"));
+		assert!(read_ipmi_values(&mut values, &failed).is_err());
+		assert!(values.iter().all(|v| v.status == IPMIValue::Unknown));
+	}
+
+	#[test]
+	fn parses_sensor_list() {
+		let temp = parse_sensor_line("Inlet Temp       | 23.000     | degrees C  | ok    | na        | -7.000    | 3.000     | 42.000    | 47.000    | na        ").unwrap();
+		assert_eq!(temp.kind, SensorKind::Temp);
+		assert_eq!(temp.value, Some(23.0));
+		assert_eq!(temp.thresholds.upper_critical, Some(47.0));
+		assert_eq!(temp.thresholds.upper_non_recoverable, None);
+
+		let fan = parse_sensor_line("FAN 1 RPM        | na         | RPM        | na    | na        | 360.000   | 600.000   | na        | na        | na        ").unwrap();
+		assert_eq!(fan.kind, SensorKind::Fan);
+		assert_eq!(fan.value, None);
+
+		let power = parse_sensor_line("Pwr Consumption  | 154.000    | Watts      | ok    | na        | na        | na        | 896.000   | 980.000   | na        ").unwrap();
+		assert_eq!(power.kind, SensorKind::Power);
+
+		assert_eq!(parse_sensor_line("Presence         | 0x0        | discrete   | 0x0180| na        | na        | na        | na        | na        | na        ").unwrap().kind, SensorKind::Other);
+		assert_eq!(parse_sensor_line("garbage"), None);
+	}
+
+	#[test]
+	fn parses_sdr_elist() {
+		let entry = parse_sdr_line("Temp             | 0Eh | ok  |  3.1 | 40 degrees C").unwrap();
+		assert_eq!(entry, SdrEntry { name: "Temp".to_string(), number: Some(0x0E), entity: Some((3, 1)), value: IPMIValue::Temp(40) });
+
+		let entry = parse_sdr_line("Fan1 RPM         | 3600 RPM          | ok").unwrap();
+		assert_eq!(entry.value, IPMIValue::RPM(3600));
+		assert_eq!(entry.number, None);
+	}
+
+	#[test]
+	fn thresholds_default_failsafe() {
+		let thresholds = Thresholds {
+			upper_non_critical: Some(42.0),
+			upper_critical: Some(47.0),
+			..Thresholds::default()
+		};
+		assert_eq!(thresholds.critical(), Some(47.0));
+		assert_eq!(thresholds.default_failsafe(), Some(42.0));
+
+		let non_critical = Thresholds { upper_non_critical: Some(80.0), ..Thresholds::default() };
+		assert_eq!(non_critical.critical(), None);
+		assert_eq!(non_critical.default_failsafe(), Some(75.0));
+
+		assert_eq!(Thresholds::default().default_failsafe(), None);
+	}
+
+	#[test]
+	fn selectors_match_sensor_list() {
+		let mut sensors = vec!(
+			parse_sensor_line("Temp             | 40.000     | degrees C  | ok    | na        | 3.000     | 8.000     | na        | 90.000    | na        ").unwrap(),
+			parse_sensor_line("Temp             | 45.000     | degrees C  | ok    | na        | 3.000     | 8.000     | na        | 85.000    | na        ").unwrap()
+		);
+		let entries = vec!(
+			parse_sdr_line("Temp             | 0Eh | ok  |  3.1 | 40 degrees C").unwrap(),
+			parse_sdr_line("Temp             | 0Fh | ok  |  3.2 | 45 degrees C").unwrap()
+		);
+		join_sdr_entries(&mut sensors, &entries);
+
+		let selector = SensorSelector::new("Temp", None, Some("3.2")).unwrap();
+		let matched = sensors.iter().filter(|v| selector.matches_sensor(v)).collect::<Vec<_>>();
+
+		assert_eq!(matched.len(), 1);
+		assert_eq!(matched[0].thresholds.default_failsafe(), Some(80.0));
+	}
+
+	#[test]
+	fn selectors_refuse_ambiguous_matches() {
+		let entries = vec!(
+			parse_sdr_line("Temp             | 0Eh | ok  |  3.1 | 40 degrees C").unwrap(),
+			parse_sdr_line("Temp             | 0Fh | ok  |  3.2 | 45 degrees C").unwrap()
+		);
+
+		let mut values = vec!(
+			IPMIRequest { selector: SensorSelector::new("Temp", None, None).unwrap(), status: IPMIValue::Unknown },
+			IPMIRequest { selector: SensorSelector::new("Temp", None, Some("3.2")).unwrap(), status: IPMIValue::Unknown },
+			IPMIRequest { selector: SensorSelector::new("Temp", Some("0x0e"), None).unwrap(), status: IPMIValue::Unknown },
+			IPMIRequest { selector: SensorSelector::new("Temp", Some("10h"), None).unwrap(), status: IPMIValue::Unknown }
+		);
+		resolve_ipmi_values(&mut values, &entries);
+
+		assert_eq!(values[0].status, IPMIValue::Ambiguous(2));
+		assert_eq!(values[1].status, IPMIValue::Temp(45));
+		assert_eq!(values[2].status, IPMIValue::Temp(40));
+		assert_eq!(values[3].status, IPMIValue::Unknown);
+
+		assert_eq!(values[1].selector.label(), "Temp 3.2");
+		assert_eq!(values[2].selector.label(), "Temp 0Eh");
+		assert!(SensorSelector::new("Temp", Some("zz"), None).is_err());
+		assert!(SensorSelector::new("Temp", None, Some("3")).is_err());
+	}
+}
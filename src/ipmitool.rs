@@ -0,0 +1,492 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+pub const PROGRAM: &str = "ipmitool";
+
+/// `WatchdogSec` of the installed systemd unit, every IPMI command of a pass has to fit within it.
+pub const WATCHDOG: Duration = Duration::from_secs(30);
+
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(2000);
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_millis(1000);
+pub const DEFAULT_RETRIES: u32 = 1;
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Deadlines and retries for IPMI commands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+	/// Keep a single `ipmitool shell` running instead of starting ipmitool for every command.
+	pub persistent: bool,
+	pub read_timeout: Duration,
+	pub write_timeout: Duration,
+	/// Extra attempts after a command times out or ipmitool can't be run, BMC errors are not retried.
+	pub retries: u32,
+	pub retry_delay: Duration
+}
+
+impl Policy {
+	/// Longest a single operation can take including every retry.
+	pub fn worst_case(&self, op: Op) -> Duration {
+		op.timeout(self) * (self.retries + 1) + self.retry_delay * self.retries
+	}
+}
+
+/// Used until `configure` is called, I.E. by the one-off subcommands.
+pub const DEFAULT_POLICY: Policy = Policy {
+	persistent: false,
+	read_timeout: DEFAULT_READ_TIMEOUT,
+	write_timeout: DEFAULT_WRITE_TIMEOUT,
+	retries: DEFAULT_RETRIES,
+	retry_delay: DEFAULT_RETRY_DELAY
+};
+
+/// Kind of IPMI operation, picks the deadline and tags the latency metric.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+	ReadSensors,
+	ReadThresholds,
+	McInfo,
+	FanMode,
+	FanSpeed
+}
+
+impl Op {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Op::ReadSensors => "read_sensors",
+			Op::ReadThresholds => "read_thresholds",
+			Op::McInfo => "mc_info",
+			Op::FanMode => "fan_mode",
+			Op::FanSpeed => "fan_speed"
+		}
+	}
+
+	fn timeout(&self, policy: &Policy) -> Duration {
+		match self {
+			Op::ReadSensors | Op::ReadThresholds | Op::McInfo => policy.read_timeout,
+			Op::FanMode | Op::FanSpeed => policy.write_timeout
+		}
+	}
+}
+
+static POLICY: Mutex<Policy> = Mutex::new(DEFAULT_POLICY);
+static METRICS: Mutex<Option<metrics::MetricSender>> = Mutex::new(None);
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+/// Result of a single ipmitool command.
+#[derive(Debug)]
+pub struct Output {
+	pub success: bool,
+	pub stdout: String,
+	pub stderr: String
+}
+
+impl Output {
+	/// ipmitool prints sensor names straight from the BMC, so invalid UTF-8 is replaced rather than refused.
+	pub fn from_bytes(success: bool, stdout: &[u8], stderr: &[u8]) -> Output {
+		Output {
+			success,
+			stdout: String::from_utf8_lossy(stdout).into_owned(),
+			stderr: String::from_utf8_lossy(stderr).into_owned()
+		}
+	}
+}
+
+/// Runs a single ipmitool command, `run` outside of tests which replace it with captured output.
+pub type Runner = dyn Fn(Op, &[&str]) -> Result<Output>;
+
+/// Applies a new policy, switching between a single long-lived `ipmitool shell` and one ipmitool process per command.
+pub fn configure(policy: Policy) {
+	let previous = ::std::mem::replace(&mut *lock(&POLICY), policy);
+
+	if previous.persistent != policy.persistent {
+		info!("Using {} for IPMI commands", if policy.persistent { "a persistent ipmitool shell" } else { "one ipmitool process per command" });
+	}
+
+	if !policy.persistent {
+		*lock(&SESSION) = None;
+	}
+}
+
+/// Reports the latency of every IPMI operation to `sender`.
+pub fn set_metrics(sender: metrics::MetricSender) {
+	*lock(&METRICS) = Some(sender);
+}
+
+/// Runs a single ipmitool command, I.E. `["sdr", "elist", "full"]`, retrying according to the configured policy.
+///
+/// Commands that don't finish in time are killed and fail with `ErrorKind::TimedOut`.
+pub fn run(op: Op, args: &[&str]) -> Result<Output> {
+	let policy = *lock(&POLICY);
+	let timeout = op.timeout(&policy);
+	let mut attempt = 0;
+
+	loop {
+		let started = Instant::now();
+		let result = if policy.persistent {
+			run_session(args, timeout)
+		} else {
+			run_process(PROGRAM, args, timeout)
+		};
+
+		report_latency(op, started.elapsed(), &result);
+
+		match result {
+			Err(e) if attempt < policy.retries => {
+				attempt += 1;
+				warn!("ipmitool {} failed, retrying({}/{}): {}", args.join(" "), attempt, policy.retries, e);
+				thread::sleep(policy.retry_delay);
+			},
+			result => return result
+		}
+	}
+}
+
+fn report_latency(op: Op, elapsed: Duration, result: &Result<Output>) {
+	let outcome = match result {
+		Ok(output) if output.success => "ok",
+		Ok(_) => "failed",
+		Err(e) if e.kind() == ErrorKind::TimedOut => "timeout",
+		Err(_) => "error"
+	};
+
+	if let Some(sender) = lock(&METRICS).as_ref() {
+		metrics::report_metric(
+			&[("ipmi_latency".to_string(), elapsed.as_secs_f32() * 1000.0)],
+			&[("op".to_string(), op.as_str().to_string()), ("result".to_string(), outcome.to_string())],
+			sender);
+	}
+}
+
+fn run_session(args: &[&str], timeout: Duration) -> Result<Output> {
+	let mut session = lock(&SESSION);
+
+	if session.as_mut().map(|v| !v.alive()).unwrap_or(false) {
+		warn!("ipmitool shell exited, restarting it");
+		*session = None;
+	}
+
+	if session.is_none() {
+		debug!("Starting ipmitool shell");
+		*session = Some(Session::spawn(PROGRAM)?);
+	}
+
+	let result = session.as_mut().map(|v| v.run(args, timeout)).expect("session was just started");
+
+	if let Err(e) = result.as_ref() {
+		warn!("ipmitool shell failed, restarting it on the next command: {}", e);
+		*session = None;
+	}
+
+	result
+}
+
+fn lock<T>(mutex: &'static Mutex<T>) -> ::std::sync::MutexGuard<'static, T> {
+	// Nothing guarded here can be left half updated, at worst a session gets restarted
+	mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn run_process(program: &str, args: &[&str], timeout: Duration) -> Result<Output> {
+	let mut child = Command::new(program)
+		.args(args)
+		.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()?;
+
+	// Read on other threads so a chatty child can't block on a full pipe while we wait on it
+	let stdout = read_all(child.stdout.take().expect("stdout is piped"));
+	let stderr = read_all(child.stderr.take().expect("stderr is piped"));
+
+	let deadline = Instant::now() + timeout;
+
+	let status = loop {
+		if let Some(status) = child.try_wait()? {
+			break status
+		}
+
+		if Instant::now() >= deadline {
+			child.kill().unwrap_or(());
+			child.wait().map(|_| ()).unwrap_or(());
+
+			return Err(Error::new(ErrorKind::TimedOut, format!("{} {} did not finish within {}s and was killed", program, args.join(" "), timeout.as_secs_f32())))
+		}
+
+		thread::sleep(Duration::from_millis(10));
+	};
+
+	Ok(Output::from_bytes(status.success(), &stdout.join().unwrap_or_default(), &stderr.join().unwrap_or_default()))
+}
+
+fn read_all<R: Read + Send + 'static>(mut from: R) -> thread::JoinHandle<Vec<u8>> {
+	thread::spawn(move || {
+		let mut buf = vec!();
+		from.read_to_end(&mut buf).map(|_| ()).unwrap_or(());
+		buf
+	})
+}
+
+enum Line {
+	Out(String),
+	Err(String),
+	Closed
+}
+
+/// A long-lived `ipmitool shell` child.
+///
+/// Each command is followed by `echo <marker>` so the end of its output can be found without relying on
+/// prompts. The shell gives no exit status, so anything written to stderr marks the command as failed.
+pub struct Session {
+	child: Child,
+	stdin: ChildStdin,
+	lines: mpsc::Receiver<Line>,
+	sequence: u64
+}
+
+impl Session {
+	pub fn spawn(program: &str) -> Result<Session> {
+		let mut child = Command::new(program)
+			.arg("shell")
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.spawn()?;
+
+		let stdin = child.stdin.take().expect("stdin is piped");
+		let stdout = child.stdout.take().expect("stdout is piped");
+		let stderr = child.stderr.take().expect("stderr is piped");
+
+		let (send, lines) = mpsc::channel();
+
+		{
+			let send = send.clone();
+			thread::spawn(move || {
+				forward_lines(stdout, &send, Line::Out);
+				send.send(Line::Closed).unwrap_or(());
+			});
+		}
+
+		thread::spawn(move || forward_lines(stderr, &send, Line::Err));
+
+		Ok(Session {
+			child,
+			stdin,
+			lines,
+			sequence: 0
+		})
+	}
+
+	pub fn alive(&mut self) -> bool {
+		self.child.try_wait().map(|v| v.is_none()).unwrap_or(false)
+	}
+
+	pub fn run(&mut self, args: &[&str], timeout: Duration) -> Result<Output> {
+		// Anything left over belongs to an earlier command
+		while let Ok(line) = self.lines.try_recv() {
+			match line {
+				Line::Out(line) => trace!("Discarding ipmitool output: {}", line),
+				Line::Err(line) => warn!("ipmitool: {}", line),
+				Line::Closed => return Err(Error::new(ErrorKind::BrokenPipe, "ipmitool shell exited"))
+			}
+		}
+
+		self.sequence += 1;
+		let marker = format!("twd-done-{}", self.sequence);
+
+		writeln!(self.stdin, "{}", args.join(" "))?;
+		writeln!(self.stdin, "echo {}", marker)?;
+		self.stdin.flush()?;
+
+		let deadline = Instant::now() + timeout;
+		let mut stdout = String::new();
+		let mut stderr = String::new();
+
+		loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+
+			match self.lines.recv_timeout(remaining) {
+				Ok(Line::Out(line)) => {
+					let line = strip_prompt(&line);
+
+					if line.trim() == marker {
+						break
+					}
+
+					stdout += line;
+					stdout.push('\n');
+				},
+				Ok(Line::Err(line)) => {
+					stderr += &line;
+					stderr.push('\n');
+				},
+				Ok(Line::Closed) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+					return Err(Error::new(ErrorKind::BrokenPipe, format!("ipmitool shell exited while running \"{}\"", args.join(" "))))
+				},
+				Err(mpsc::RecvTimeoutError::Timeout) => {
+					return Err(Error::new(ErrorKind::TimedOut, format!("ipmitool shell did not finish \"{}\" within {}s", args.join(" "), timeout.as_secs_f32())))
+				}
+			}
+		}
+
+		// stderr is read on its own thread, pick up anything it has already seen
+		while let Ok(Line::Err(line)) = self.lines.try_recv() {
+			stderr += &line;
+			stderr.push('\n');
+		}
+
+		// The shell has no exit codes, so this is only as reliable as ipmitool is quiet on stderr(hence `session` is opt-in)
+		Ok(Output {
+			success: stderr.is_empty(),
+			stdout,
+			stderr
+		})
+	}
+}
+
+impl Drop for Session {
+	fn drop(&mut self) {
+		self.child.kill().unwrap_or(());
+		self.child.wait().map(|_| ()).unwrap_or(());
+	}
+}
+
+fn forward_lines<R: Read>(from: R, to: &mpsc::Sender<Line>, wrap: fn(String) -> Line) {
+	let mut reader = BufReader::new(from);
+	let mut buf = vec!();
+
+	loop {
+		buf.clear();
+
+		match reader.read_until(b'\n', &mut buf) {
+			Ok(0) | Err(_) => return,
+			Ok(_) => {
+				let line = String::from_utf8_lossy(&buf).trim_end_matches(['\r', '\n']).to_string();
+
+				if to.send(wrap(line)).is_err() {
+					return
+				}
+			}
+		}
+	}
+}
+
+/// The shell prints its prompt without a newline, so it ends up in front of the next line of output.
+fn strip_prompt(mut line: &str) -> &str {
+	while let Some(rest) = line.strip_prefix("ipmitool> ") {
+		line = rest;
+	}
+
+	line
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::fs::PermissionsExt;
+
+	const LONG: Duration = Duration::from_secs(5);
+
+	fn fake_ipmitool(name: &str) -> String {
+		let path = ::std::env::temp_dir().join(format!("twd-fake-ipmitool-{}-{}", ::std::process::id(), name));
+
+		::std::fs::write(&path, r#"#!/bin/sh
+respond() {
+	case "$1" in
+		sdr) echo "Exhaust Temp     | 01h | ok  |  7.1 | 38 degrees C" ;;
+		raw) echo "Unable to send RAW command" >&2; sleep 0.2; return 1 ;;
+		hang) sleep 30 ;;
+	esac
+}
+
+[ "$1" = "shell" ] || { respond "$1"; exit $?; }
+
+printf "ipmitool> "
+while read -r cmd rest; do
+	case "$cmd" in
+		echo) echo "$rest" ;;
+		exit) exit 0 ;;
+		*) respond "$cmd" ;;
+	esac
+	printf "ipmitool> "
+done
+"#).unwrap();
+		::std::fs::set_permissions(&path, ::std::fs::Permissions::from_mode(0o755)).unwrap();
+
+		path.to_string_lossy().into_owned()
+	}
+
+	/// Another test forking while a script was open for writing can briefly leave it busy.
+	fn retry_busy<T, F: FnMut() -> Result<T>>(mut f: F) -> Result<T> {
+		for _ in 0..50 {
+			match f() {
+				Err(ref e) if e.kind() == ErrorKind::ExecutableFileBusy => thread::sleep(Duration::from_millis(10)),
+				result => return result
+			}
+		}
+
+		f()
+	}
+
+	#[test]
+	fn session_runs_commands() {
+		let program = fake_ipmitool("runs");
+		let mut session = retry_busy(|| Session::spawn(&program)).unwrap();
+
+		for _ in 0..3 {
+			let output = session.run(&["sdr", "elist", "full"], LONG).unwrap();
+			assert!(output.success);
+			assert_eq!(output.stdout, "Exhaust Temp     | 01h | ok  |  7.1 | 38 degrees C\n");
+		}
+
+		let output = session.run(&["raw", "0x30", "0x30", "0x01", "0x00"], LONG).unwrap();
+		assert!(!output.success);
+		assert_eq!(output.stderr, "Unable to send RAW command\n");
+
+		assert!(session.run(&["sdr"], LONG).unwrap().success);
+	}
+
+	#[test]
+	fn session_detects_hangs_and_exits() {
+		let program = fake_ipmitool("hangs");
+		let mut session = retry_busy(|| Session::spawn(&program)).unwrap();
+
+		let started = Instant::now();
+		let err = session.run(&["hang"], Duration::from_millis(200)).unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::TimedOut);
+		assert!(started.elapsed() < LONG);
+		drop(session);
+
+		let mut session = retry_busy(|| Session::spawn(&program)).unwrap();
+		assert!(session.run(&["sdr"], LONG).unwrap().success);
+
+		assert_eq!(session.run(&["exit"], LONG).unwrap_err().kind(), ErrorKind::BrokenPipe);
+
+		// stdout closes just before the process exits
+		let exited = Instant::now();
+		while session.alive() && exited.elapsed() < LONG {
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert!(!session.alive());
+	}
+
+	#[test]
+	fn process_times_out_and_is_killed() {
+		let program = fake_ipmitool("process");
+
+		let output = retry_busy(|| run_process(&program, &["sdr", "elist", "full"], LONG)).unwrap();
+		assert!(output.success);
+		assert!(output.stdout.starts_with("Exhaust Temp"));
+
+		let output = retry_busy(|| run_process(&program, &["raw"], LONG)).unwrap();
+		assert!(!output.success);
+
+		let started = Instant::now();
+		let err = retry_busy(|| run_process(&program, &["hang"], Duration::from_millis(200))).unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::TimedOut);
+		assert!(started.elapsed() < LONG);
+	}
+}
@@ -6,18 +6,45 @@ extern crate serde_derive;
 
 mod pid;
 mod ipmi;
+mod ipmitool;
 mod control;
 mod metrics;
+mod status;
+mod mqtt;
+mod socket;
+mod config;
+mod discover;
+mod vendor;
+mod verify;
+mod calibration;
+mod feedforward;
+mod aggregate;
+mod schedule;
+mod budget;
+mod expr;
+mod virtual_sensors;
+mod drives;
+mod command_sensor;
+mod replay;
+mod web;
 
 use ipmi::*;
 use control::*;
+use config::*;
+use status::{Command, CommandReply, FanStatus, Mode, Snapshot};
+use calibration::Calibration;
+use vendor::FanProfile;
+use verify::{FanVerifier, Verdict};
 
-use env_logger;
-use clap::{Arg, App, SubCommand};
-use ctrlc;
+use clap::{Arg, ArgGroup, App, SubCommand};
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::io::Result;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Longest startup waits for drives and command sensors to be read, each read has its own shorter timeout.
+const SOURCE_STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn main() {
 	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace"))
@@ -61,134 +88,390 @@ fn main() {
 						.long("influx_db")
 						.takes_value(true)
 						.help("InfluxDB database"))
+					.arg(Arg::with_name("record")
+						.long("record")
+						.takes_value(true)
+						.value_name("FILE")
+						.help("Writes sensor readings, PID terms and commanded duty for every pass to FILE as JSON Lines"))
 					.subcommand(SubCommand::with_name("install")
 						.about("Installs Thermal Watchdog as systemd service"))
+					.subcommand(SubCommand::with_name("discover")
+						.about("Lists BMC sensors and generates a starter configuration")
+						.arg(Arg::with_name("output")
+							.long("output")
+							.short("o")
+							.takes_value(true)
+							.help("Writes the generated configuration to a file instead of stdout")))
+					.subcommand(SubCommand::with_name("status")
+						.about("Shows the live state of a running Thermal Watchdog")
+						.arg(socket_arg()))
+					.subcommand(SubCommand::with_name("override")
+						.about("Temporarily overrides fan control on a running Thermal Watchdog")
+						.arg(socket_arg())
+						.arg(Arg::with_name("duty")
+							.long("duty")
+							.takes_value(true)
+							.help("Holds the fans at a fixed duty(0-100)"))
+						.arg(Arg::with_name("pause")
+							.long("pause")
+							.help("Hands fan control back to the BMC"))
+						.arg(Arg::with_name("setpoint")
+							.long("setpoint")
+							.takes_value(true)
+							.requires("control")
+							.help("Replaces the setpoint of a single control"))
+						.arg(Arg::with_name("control")
+							.long("control")
+							.takes_value(true)
+							.help("Control label to apply --setpoint to, as shown by \"status\""))
+						.arg(Arg::with_name("minutes")
+							.long("minutes")
+							.short("m")
+							.takes_value(true)
+							.help("How long the override lasts, held until \"resume\" if omitted"))
+						.group(ArgGroup::with_name("action")
+							.args(&["duty", "pause", "setpoint"])
+							.required(true)))
+					.subcommand(SubCommand::with_name("calibrate")
+						.about("Steps fans through duties, recording the RPM each fan settles at")
+						.arg(socket_arg())
+						.arg(Arg::with_name("output")
+							.long("output")
+							.short("o")
+							.takes_value(true)
+							.help("Where to save the calibration, defaults to the calibration path in [fans]"))
+						.arg(Arg::with_name("settle")
+							.long("settle")
+							.takes_value(true)
+							.default_value("20")
+							.help("Seconds to hold each duty before recording RPM")))
+					.subcommand(SubCommand::with_name("replay")
+						.about("Feeds a trace written by --record through the controls in the config and diffs the commanded duty")
+						.arg(Arg::with_name("trace")
+							.required(true)
+							.help("Trace written by --record"))
+						.arg(Arg::with_name("tolerance")
+							.long("tolerance")
+							.takes_value(true)
+							.default_value("1")
+							.help("Duty difference(0-100) to ignore")))
+					.subcommand(SubCommand::with_name("restore")
+						.about("Hands fan control back to the BMC using the configured or detected fan profile"))
+					.subcommand(SubCommand::with_name("resume")
+						.about("Clears all overrides on a running Thermal Watchdog")
+						.arg(socket_arg()))
 		.get_matches();
 
-	if let Some(_) = matches.subcommand_matches("install") {
+	if matches.subcommand_matches("install").is_some() {
 		install();
 		return
 	}
 
+	if let Some(matches) = matches.subcommand_matches("discover") {
+		if let Err(e) = discover::discover(matches.value_of("output")) {
+			error!("Discovery failed: {}", e);
+			::std::process::exit(1);
+		}
+		return
+	}
+
+	if let Some(matches) = matches.subcommand_matches("status") {
+		client_status(matches.value_of("socket").unwrap_or(socket::DEFAULT_PATH));
+		return
+	}
+
+	if let Some(matches) = matches.subcommand_matches("override") {
+		client_override(matches);
+		return
+	}
+
+	if let Some(matches) = matches.subcommand_matches("resume") {
+		client_command(matches.value_of("socket").unwrap_or(socket::DEFAULT_PATH), Command::Resume);
+		return
+	}
+
 	let config_file = matches.value_of("config").expect("no config defined");
 
+	if let Some(matches) = matches.subcommand_matches("calibrate") {
+		calibrate(config_file, matches);
+		return
+	}
+
+	if matches.subcommand_matches("restore").is_some() {
+		restore(config_file);
+		return
+	}
+
+	if let Some(matches) = matches.subcommand_matches("replay") {
+		replay(config_file, matches);
+		return
+	}
+
 	let mut config = parse_config(config_file);
 
+	if let Err(e) = config.validate() {
+		error!("Invalid config {}: {}", config_file, e);
+		::std::process::exit(1);
+	}
+
 	let shadow = !matches.is_present("live");
 
-	ctrlc::set_handler(move || {
-		info!("Signal received, aborting and resetting IPMI control");
-		set_fan_manual(false, shadow, None).unwrap_or(());
-		::std::process::exit(1);
-	}).expect("Unable to set signal handler");
+	// Fan commands are only sent through a profile, so shadow mode runs without one
+	let fans = match (select_fan_profile(&config), shadow) {
+		(Ok(profile), false) => {
+			info!("Using {} fan profile", profile.name);
+			Some(profile)
+		},
+		(Ok(profile), true) => {
+			info!("Would use {} fan profile once live", profile.name);
+			None
+		},
+		(Err(e), false) => {
+			error!("Unable to select a fan profile: {}", e);
+			::std::process::exit(1);
+		},
+		(Err(e), true) => {
+			warn!("Unable to select a fan profile, fan control won't work once live: {}", e);
+			None
+		}
+	};
 
-	match (matches.value_of("influx_addr").map(|v| v.to_string()), matches.value_of("influx_db").map(|v| v.to_string())) {
-		(Some(influx_addr),Some(influx_db)) => {
-			trace!("Enabling metrics");
+	{
+		let fans = fans.clone();
+		ctrlc::set_handler(move || {
+			info!("Signal received, aborting and resetting IPMI control");
+			set_fan_manual(false, fans.as_ref(), None).unwrap_or(());
+			::std::process::exit(1);
+		}).expect("Unable to set signal handler");
+	}
 
-			let (influx_user,influx_pw) = if let Some(prev_metrics) = config.metrics {
-				(matches.value_of("influx_user").map(|v| v.to_string()).or(prev_metrics.influx_user),
-					 matches.value_of("influx_pw").map(|v| v.to_string()).or(prev_metrics.influx_pw))
-			} else {
-				(None, None)
-			};
+	if let (Some(influx_addr),Some(influx_db)) = (matches.value_of("influx_addr").map(|v| v.to_string()), matches.value_of("influx_db").map(|v| v.to_string())) {
+		trace!("Enabling metrics");
 
-			config.metrics = Some(AppMetricConfig {
-				influx_addr,
-				influx_db,
-				influx_user,
-				influx_pw
-			});
-		},
-		_ => ()
+		let (influx_user,influx_pw) = if let Some(prev_metrics) = config.metrics {
+			(matches.value_of("influx_user").map(|v| v.to_string()).or(prev_metrics.influx_user),
+				 matches.value_of("influx_pw").map(|v| v.to_string()).or(prev_metrics.influx_pw))
+		} else {
+			(None, None)
+		};
+
+		config.metrics = Some(AppMetricConfig {
+			influx_addr,
+			influx_db,
+			influx_user,
+			influx_pw
+		});
 	}
 
-	main_loop(shadow, config);
-}
+	let recorder = matches.value_of("record").map(|path| match replay::Recorder::create(path) {
+		Ok(v) => {
+			info!("Recording every pass to {}", path);
+			v
+		},
+		Err(e) => {
+			error!("Unable to create {}: {}", path, e);
+			::std::process::exit(1);
+		}
+	});
 
-#[derive(Deserialize)]
-struct AppConfig {
-	metrics: Option<AppMetricConfig>,
-	pid: Option<AppPIDConfig>,
-	controls: Option<Vec<AppControlConfig>>
+	main_loop(shadow, fans, config, config_file, recorder);
 }
 
-#[derive(Deserialize)]
-struct AppMetricConfig {
-	influx_addr: String,
-	influx_db: String,
-	influx_user: Option<String>,
-	influx_pw: Option<String>,
-}
+/// Configured fan profile, or the built-in profile for the BMC's manufacturer.
+fn select_fan_profile(config: &AppConfig) -> ::std::result::Result<FanProfile, String> {
+	if let Some(profile) = config.fan_profile()? {
+		return Ok(profile)
+	}
+
+	let id = vendor::detect_manufacturer()
+		.map_err(|e| format!("Unable to detect BMC manufacturer, set profile in [fans]: {}", e))?;
+	info!("Detected {} BMC(manufacturer id {})", vendor::manufacturer_name(id).unwrap_or("unknown"), id);
 
-#[derive(Deserialize)]
-struct AppPIDConfig {
-	k_factor: f32,
-	i_factor: f32,
-	d_factor: f32,
-	filter_points: Option<usize>,
-	min: Option<usize>
+	FanProfile::for_manufacturer(id)
 }
 
-#[derive(Deserialize,Clone)]
-struct AppControlConfig {
-	name: String,
-	setpoint: f32,
-	failsafe: f32
+/// Steps fans from 10% to 100% duty while watching failsafes and saves the RPM curve of every fan.
+fn calibrate(config_file: &str, matches: &clap::ArgMatches) {
+	let fail = |e: String| -> ! {
+		error!("Calibration failed: {}", e);
+		::std::process::exit(1);
+	};
+
+	let config = load_config(config_file)
+		.and_then(|v| v.validate().map(|_| v))
+		.unwrap_or_else(|e| fail(format!("invalid config {}: {}", config_file, e)));
+
+	let settle = matches.value_of("settle")
+		.and_then(|v| v.parse::<u64>().ok())
+		.map(::std::time::Duration::from_secs)
+		.unwrap_or_else(|| fail("--settle must be a whole number of seconds".to_string()));
+
+	let output = matches.value_of("output")
+		.map(|v| v.to_string())
+		.unwrap_or_else(|| config.calibration_path());
+
+	// The daemon would fight us for the fans
+	let socket = matches.value_of("socket").unwrap_or(socket::DEFAULT_PATH);
+	if socket::request(socket, &socket::Request::Status).is_ok() {
+		fail("Thermal Watchdog is running, stop it before calibrating".to_string());
+	}
+
+	let profile = select_fan_profile(&config).unwrap_or_else(|e| fail(e));
+	ipmitool::configure(config.ipmi_policy());
+
+	let mut control_loop = build_control_loop(&config, &read_thresholds(), None).unwrap_or_else(|e| fail(e));
+	control_loop.check_sensors().unwrap_or_else(|e| fail(e));
+
+	{
+		let profile = profile.clone();
+		ctrlc::set_handler(move || {
+			info!("Signal received, aborting calibration and resetting IPMI control");
+			ipmi_set_fan_manual(&profile, false).unwrap_or(());
+			::std::process::exit(1);
+		}).expect("Unable to set signal handler");
+	}
+
+	let duties = (1..=10).map(|v| v as f32 / 10.0).collect::<Vec<_>>();
+	info!("Calibrating {} duties for {}s each with the {} fan profile", duties.len(), settle.as_secs(), profile.name);
+
+	let calibration = calibration::calibrate(&profile, &mut control_loop, &duties, settle)
+		.unwrap_or_else(|e| fail(e));
+
+	calibration::print_summary(&calibration);
+
+	match calibration.save(&output) {
+		Ok(_) => println!("Saved calibration to {}, reload Thermal Watchdog to use it", output),
+		Err(e) => fail(format!("unable to save {}: {}", output, e))
+	}
 }
 
-fn parse_config(path: &str) -> AppConfig {
-	info!("Loading config file at {}", path);
-
-	let result = ::std::fs::File::open(path)
-		.map_err(|e| format!("Unable to open config file: {:?}", e))
-		.and_then(|v| {
-				let mut content = String::new();
-				let mut buf = ::std::io::BufReader::new(v);
-				use ::std::io::Read;
-				buf.read_to_string(&mut content)
-					.map_err(|e| format!("Unable to read config file: {:?}", e))?;
-				Ok(content)
-			})
-		.and_then(|v| toml::from_str(v.as_str())
-			.map_err(|e| format!("Unable to parse toml: {:?}", e)));
+/// Hands fan control back to the BMC, used by the service's ExecStopPost.
+fn restore(config_file: &str) {
+	let config = parse_config(config_file);
+
+	let result = select_fan_profile(&config)
+		.and_then(|profile| ipmi_set_fan_manual(&profile, false).map_err(|e| e.to_string()));
 
 	match result {
-		Ok(v) => v,
+		Ok(_) => info!("Restored automatic fan control"),
 		Err(e) => {
-			info!("{}", e);
-			AppConfig {
-				metrics: None,
-				pid: None,
-				controls: None
-			}
+			error!("Failed to restore automatic fan control: {}", e);
+			::std::process::exit(1);
 		}
 	}
 }
 
-fn main_loop(shadow: bool, config: AppConfig) {
-	let controls = if let Some(controls) = config.controls {
-		controls.clone()
+/// Replays a recorded trace against the controls in `config_file`, exiting with an error if any commanded duty differs.
+fn replay(config_file: &str, matches: &clap::ArgMatches) {
+	let fail = |e: String| -> ! {
+		error!("Replay failed: {}", e);
+		::std::process::exit(1);
+	};
+
+	let mut config = load_config(config_file)
+		.and_then(|v| v.validate().map(|_| v))
+		.unwrap_or_else(|e| fail(format!("invalid config {}: {}", config_file, e)));
+
+	let tolerance = matches.value_of("tolerance")
+		.and_then(|v| v.parse::<f32>().ok())
+		.filter(|v| *v >= 0.0 && *v <= 100.0)
+		.unwrap_or_else(|| fail("--tolerance must be a duty from 0 to 100".to_string())) / 100.0;
+
+	let path = matches.value_of("trace").expect("no trace defined");
+	let frames = replay::read_trace(path).unwrap_or_else(|e| fail(e));
+
+	// BMC thresholds aren't recorded, controls relying on them use the failsafe they had while recording
+	if let Some(controls) = config.controls.as_mut() {
+		for control in controls.iter_mut().filter(|v| v.failsafe.is_none()) {
+			let label = control.selector().unwrap_or_else(|e| fail(e)).label();
+
+			control.failsafe = frames.iter()
+				.flat_map(|v| v.controls.iter())
+				.find(|v| v.label == label)
+				.map(|v| v.failsafe);
+		}
+	}
+
+	let mut control_loop = build_controls(&config, &[]).unwrap_or_else(|e| fail(format!("invalid controls in {}: {}", config_file, e)));
+	let mut scheduler = schedule::Scheduler::new(config.schedule_periods().unwrap_or_default(), config.schedule_ramp());
+	let limits = replay::Limits {
+		min: config.min_speed(),
+		max: config.max_speed(),
+		acoustic_budget: config.acoustic_budget()
+	};
+
+	let metrics = metrics::init_metric_thread(None, None);
+	let replayed = replay::replay(&frames, &mut control_loop, &mut scheduler, &limits, &metrics);
+	let (compared, differences) = replay::diff(&frames, &replayed, tolerance);
+
+	let duty = |v: Option<f32>| v.map(|v| format!("{:.1}%", v * 100.0)).unwrap_or_else(|| "bmc".to_string());
+	let start = frames.first().map(|v| v.timestamp).unwrap_or(0);
+
+	if !differences.is_empty() {
+		println!("{:>8} {:>10} {:>10} {:>10}", "Frame", "Time", "Recorded", "Replayed");
+
+		for difference in differences.iter() {
+			let frame = &frames[difference.frame];
+
+			println!("{:>8} {:>9.1}s {:>10} {:>10}",
+				difference.frame + 1,
+				frame.timestamp.saturating_sub(start) as f32 / 1000.0,
+				duty(difference.recorded),
+				duty(difference.replayed));
+		}
+
+		println!();
+	}
+
+	let max = differences.iter().map(|v| v.delta()).fold(0.0, f32::max);
+	println!("Replayed {} frames from {}, {} of {} compared frames differ by more than {:.1}% duty(largest difference {:.1}%)",
+		frames.len(), path, differences.len(), compared, tolerance * 100.0, max * 100.0);
+
+	if !differences.is_empty() {
+		::std::process::exit(1);
+	}
+}
+
+fn socket_arg<'a, 'b>() -> Arg<'a, 'b> {
+	Arg::with_name("socket")
+		.long("socket")
+		.short("s")
+		.default_value(socket::DEFAULT_PATH)
+		.help("Path to the control socket of the running daemon")
+}
+
+fn main_loop(shadow: bool, fans: Option<FanProfile>, config: AppConfig, config_file: &str, mut recorder: Option<replay::Recorder>) {
+	let fans = fans.as_ref();
+	ipmitool::configure(config.ipmi_policy());
+
+	let mut min_speed = config.min_speed();
+	let mut max_speed = config.max_speed();
+	let mut budget = if config.acoustic_budget() {
+		Some(budget::AcousticBudget::new())
 	} else {
-		vec!(
-			AppControlConfig {
-				name: "Exhaust Temp".to_string(),
-				setpoint: 40.0,
-				failsafe: 60.0
-			},
-			AppControlConfig {
-				name: "Temp".to_string(),
-				setpoint: 55.0,
-				failsafe: 65.0
-			},
-			AppControlConfig {
-				name: "Temp".to_string(),
-				setpoint: 55.0,
-				failsafe: 65.0
-			}
-		)
+		None
+	};
+	let clock = schedule::SystemClock;
+	let mut scheduler = schedule::Scheduler::new(config.schedule_periods().unwrap_or_default(), config.schedule_ramp());
+	let mut calibration = load_calibration(&config);
+	let mut verifier = config.verify_settings(calibration.as_ref()).map(FanVerifier::new);
+	let mut fan_reasserts = 0;
+	let thresholds = read_thresholds();
+	let mut control_loop = match build_control_loop(&config, &thresholds, None) {
+		Ok(v) => v,
+		Err(e) => {
+			error!("Invalid controls in {}: {}", config_file, e);
+			::std::process::exit(1);
+		}
 	};
 
+	if let Err(e) = control_loop.check_sensors() {
+		error!("Invalid controls in {}: {}", config_file, e);
+		::std::process::exit(1);
+	}
+
+	check_watchdog(&config);
+
 	let metrics_conf = if let Some(metrics) = config.metrics {
 		Some((
 			metrics.influx_addr.clone(),
@@ -200,69 +483,313 @@ fn main_loop(shadow: bool, config: AppConfig) {
 		None
 	};
 
-	let metrics = metrics::init_metric_thread(metrics_conf);
+	// Resolved once, every metric is tagged with it
+	let hostname = metrics::get_hostname().map_err(|e| error!("Unable to include hostname in metrics: {}", e)).ok();
+	let metrics = metrics::init_metric_thread(metrics_conf, hostname);
+	ipmitool::set_metrics(metrics.clone());
 	let metrics = &metrics;
 
-	if shadow {
-		info!("TWD running in Shadow Mode, no IPMI commands will be issued");
+	let mut publisher = status::Publisher::new();
+	let (command_send, commands) = mpsc::channel();
+	let (config_send, config_requests) = mpsc::channel();
+	// TOML of the config currently applied, which may differ from the file until it is reloaded
+	let running_config = Arc::new(Mutex::new(::std::fs::read_to_string(config_file).unwrap_or_default()));
+
+	if let Some(mqtt_config) = config.mqtt {
+		info!("Publishing state to MQTT broker {}", mqtt_config.host);
+		mqtt::init_mqtt_thread(mqtt_config, publisher.subscribe(16), command_send.clone());
 	}
 
-	let filter_points = config.pid.as_ref().and_then(|v| v.filter_points).unwrap_or(5);
-	let pid_settings = config.pid.as_ref()
-		.map(|v| (v.k_factor, v.i_factor, v.d_factor))
-		.unwrap_or((0.05, 0.000001, 0.0));
+	let socket_config = config.socket.unwrap_or(AppSocketConfig { enabled: None, path: None });
+	if socket_config.enabled.unwrap_or(true) {
+		let path = socket_config.path.unwrap_or_else(|| socket::DEFAULT_PATH.to_string());
 
-	let min_speed = config.pid.map(|v| v.min.unwrap_or(0)).unwrap_or(0) as f32 / 100.0;
+		match socket::init_socket_thread(&path, publisher.subscribe(1), command_send.clone()) {
+			Ok(_) => info!("Listening for control commands on {}", path),
+			Err(e) => error!("Unable to open control socket {}: {}", path, e)
+		}
+	}
 
-	let mut control_loop = ControlLoop::new();
+	if let Some(web_config) = config.web {
+		match web::init_web_thread(web_config, publisher.subscribe(16), command_send.clone(), config_send, running_config.clone()) {
+			Ok(addr) => info!("Serving dashboard on http://{}", addr),
+			Err(e) => error!("Unable to start dashboard: {}", e)
+		}
+	}
 
-	for control in controls {
-		control_loop.add_control(control.name.clone(), control.setpoint, pid_settings, filter_points, control.failsafe);
+	if shadow {
+		info!("TWD running in Shadow Mode, no IPMI commands will be issued");
 	}
+
+	let reload = register_reload_signal();
 	
 	use systemd::daemon;
 	daemon::notify(false, [(daemon::STATE_READY,"1")].iter()).unwrap_or(false);
 	
 	let mut manual = false;
+	let mut hold: Option<(Hold, Option<Instant>)> = None;
+	let mut last_error = None;
 	let mut last_update = Instant::now();
 	loop {
+		// Config to apply from SIGHUP or the API, with where to reply and whether to save it over the file
+		let mut change: Option<(::std::result::Result<String, String>, Option<CommandReply>, bool)> = None;
+
+		if reload.swap(false, Ordering::SeqCst) {
+			info!("Reloading config file at {}", config_file);
+			change = Some((::std::fs::read_to_string(config_file).map_err(|e| format!("Unable to read config file: {:?}", e)), None, false));
+		}
+
+		while let Ok(request) = config_requests.try_recv() {
+			info!("Applying config from the web API");
+
+			// Only the latest wins, earlier requests are told they were replaced
+			if let Some((_, Some(reply), _)) = change.take() {
+				reply.send(Err("replaced by a newer config".to_string())).unwrap_or(());
+			}
+
+			change = Some((Ok(request.config), Some(request.reply), true));
+		}
+
+		if let Some((content, reply, save)) = change {
+			let next = content
+				.and_then(|content| parse_toml(&content).map(|v| (v, content)))
+				.and_then(|(v, content)| v.validate().map(|_| (v, content)))
+				.and_then(|(v, content)| {
+					// Nothing here talks to the BMC, the whole reload has to fit in a pass for the systemd watchdog
+					let mut next_loop = build_control_loop(&v, &thresholds, Some(control_loop.readings().unwrap_or(&[])))?;
+					match control_loop.readings() {
+						Some(readings) => next_loop.check_sensors_from(readings.to_vec())?,
+						None => warn!("No sensor readings from the last pass to check controls against")
+					}
+
+					Ok((v, next_loop, content))
+				});
+
+			let result = match next {
+				Ok((next, next_loop, content)) => {
+					min_speed = next.min_speed();
+					max_speed = next.max_speed();
+					budget = match (budget, next.acoustic_budget()) {
+						(Some(budget), true) => Some(budget),
+						(_, true) => Some(budget::AcousticBudget::new()),
+						(_, false) => None
+					};
+					scheduler.reconfigure(next.schedule_periods().unwrap_or_default(), next.schedule_ramp());
+					calibration = load_calibration(&next);
+					verifier = match (verifier, next.verify_settings(calibration.as_ref())) {
+						(Some(mut verifier), Some(settings)) => {
+							verifier.set_settings(settings);
+							Some(verifier)
+						},
+						(_, settings) => settings.map(FanVerifier::new)
+					};
+					ipmitool::configure(next.ipmi_policy());
+					control_loop.reconfigure(next_loop);
+					check_watchdog(&next);
+					info!("Applied config from {}, metrics, mqtt, socket, web and fan profile changes take effect on restart", if save { "the web API" } else { config_file });
+
+					let saved = if save {
+						save_config(config_file, &content)
+					} else {
+						Ok(())
+					};
+					*running_config.lock().unwrap_or_else(|e| e.into_inner()) = content;
+
+					saved.map_err(|e| {
+						error!("Applied config from the web API but {}", e);
+						format!("config was applied but not saved, {}", e)
+					})
+				},
+				Err(e) => {
+					error!("Keeping running config, {} is invalid: {}", if save { "config from the web API" } else { config_file }, e);
+					Err(e)
+				}
+			};
+
+			if let Some(reply) = reply {
+				reply.send(result).unwrap_or(());
+			}
+		}
+
+		while let Ok(request) = commands.try_recv() {
+			let result = apply_command(request.command, &mut hold, &mut control_loop);
+
+			if let Err(e) = result.as_ref() {
+				warn!("Rejected command: {}", e);
+			}
+
+			if let Some(reply) = request.reply {
+				reply.send(result).unwrap_or(());
+			}
+		}
+
 		let now = Instant::now();
+
+		if let Some((_, Some(until))) = hold {
+			if now >= until {
+				info!("Override expired, resuming PID fan control");
+				hold = None;
+			}
+		}
+
+		control_loop.expire_overrides(now);
+
+		let targets = scheduler.update(&clock, &schedule::Targets {
+			setpoints: control_loop.setpoints(),
+			min: min_speed,
+			max: max_speed
+		});
+		control_loop.schedule_setpoints(&targets.setpoints);
+		metrics::report_metric(
+			&[("min_duty".to_string(), targets.min), ("max_duty".to_string(), targets.max), ("schedule_ramp".to_string(), scheduler.progress())],
+			&[("schedule".to_string(), scheduler.active().unwrap_or("default").to_string())],
+			metrics);
+
 		let duration = now.duration_since(last_update);
 		last_update = now;
 
+		let duty_override = match hold {
+			Some((Hold::Duty(duty), _)) => Some(duty),
+			_ => None
+		};
+
+		// Measured before stepping so feed-forward terms see the current load
+		let load = match metrics::get_proc_usage() {
+			Ok(cpu) => {
+				metrics::report_metric(&[("cpu_usage".to_string(), cpu)], &[], metrics);
+				Some(cpu)
+			},
+			Err(e) => {
+				error!("Unable to report cpu usage: {}", e);
+				None
+			}
+		};
+
 		let elapsed = (duration.as_secs() * 1000 + duration.subsec_millis() as u64) as f32;
-		let loop_result = control_loop.step(elapsed, metrics);
+		let loop_result = if let Some((Hold::Pause, _)) = hold {
+			control_loop.refresh().map(|_| None)
+		} else {
+			control_loop.step(elapsed, load, metrics).map(Some)
+		};
+
+		// Only errors of the current failure are reported, so the same error after a recovery is seen again
+		if loop_result.is_ok() {
+			last_error = None;
+		}
+
+		let mut duty = None;
+		let mut over_budget = None;
 
 		let set_result = match loop_result {
-			Ok(control) => {
+			Ok(None) => {
+				if manual {
+					info!("Control paused, returning fans to BMC control");
+					set_fan_manual(false, fans, Some(metrics)).map(|_| {
+						manual = false;
+					})
+				} else {
+					Ok(())
+				}
+			},
+			Ok(Some(control)) => {
 				let enable = if !manual {
 					info!("Enabling manual fan control");
-					set_fan_manual(true, shadow, Some(metrics))
-						.and_then(|_| {
+					set_fan_manual(true, fans, Some(metrics))
+						.map(|_| {
 							manual = true;
-							Ok(())
 						})
 				} else {
 					Ok(())
 				};
 
-				let control = control.max(min_speed);
+				// In acoustic budget mode a control past its emergency threshold lifts the cap
+				let emergency = control_loop.emergency().filter(|_| budget.is_some());
+
+				let control = duty_override.unwrap_or_else(|| targets.duty(control, emergency.is_some()));
+				duty = Some(control);
 
-				enable.and_then(|_| set_fan_speed(control, shadow, metrics))
+				if control > targets.max && duty_override.is_none() {
+					over_budget = emergency.map(|v| v.to_string());
+				}
+
+				enable.and_then(|_| set_fan_speed(control, fans, metrics))
 			},
 			Err(e) => {
 				error!("Unable to run control, resetting to manual: {}", e);
-				set_fan_manual(false, shadow, Some(metrics)).and_then(|_| {
+				last_error = Some(e.to_string());
+				set_fan_manual(false, fans, Some(metrics)).map(|_| {
 					manual = false;
-					Ok(())
 				})
 			}
 		};
 
-		if let Err(_) = set_result {
+		if let Some(budget) = budget.as_mut() {
+			budget.update(over_budget.as_deref(), now);
+
+			let status = budget.status(now);
+			metrics::report_metric(&[
+				("budget_exceeded".to_string(), if status.exceeded { 1.0 } else { 0.0 }),
+				("budget_exceedances".to_string(), status.count as f32),
+				("budget_exceeded_secs".to_string(), status.exceeded_secs as f32)
+			], &[], metrics);
+		}
+
+		let driven_duty = duty.filter(|_| manual && fans.is_some());
+		let fan_status = control_loop.fans().iter()
+			.map(|(name, rpm)| {
+				let curve = calibration.as_ref().and_then(|v| v.fan(name));
+
+				FanStatus {
+					name: name.clone(),
+					rpm: *rpm,
+					expected: curve.zip(driven_duty).and_then(|(curve, duty)| curve.expected(duty)),
+					health: curve.zip(driven_duty).and_then(|(curve, duty)| curve.health(duty, *rpm))
+				}
+			})
+			.collect::<Vec<_>>();
+
+		for fan in fan_status.iter() {
+			let fields = [("fan_rpm", Some(fan.rpm as f32)), ("fan_expected_rpm", fan.expected), ("fan_health", fan.health)]
+				.iter()
+				.filter_map(|(n,v)| v.map(|v| (n.to_string(), v)))
+				.collect::<Vec<_>>();
+			metrics::report_metric(&fields, &[("fan".to_string(), fan.name.clone())], metrics);
+		}
+
+		// Only fans we are actually driving can be checked against the commanded duty
+		let set_result = match (set_result, verifier.as_mut(), duty) {
+			(Ok(_), Some(verifier), Some(duty)) if manual && fans.is_some() => {
+				verifier.commanded(duty, now);
+
+				let rpms = control_loop.fans().iter().map(|(_, rpm)| *rpm).collect::<Vec<_>>();
+
+				match verifier.check(duty, &rpms, now) {
+					Verdict::Mismatch(reason) => {
+						fan_reasserts += 1;
+						warn!("Fans aren't following the commanded duty, {}. Re-asserting manual control({} times since startup)", reason, fan_reasserts);
+						metrics::report_metric(&[("fan_reasserts".to_string(), fan_reasserts as f32)], &[], metrics);
+
+						set_fan_manual(true, fans, Some(metrics))
+							.and_then(|_| set_fan_speed(duty, fans, metrics))
+					},
+					_ => Ok(())
+				}
+			},
+			(set_result, verifier, _) => {
+				if let Some(verifier) = verifier {
+					if !manual {
+						verifier.reset();
+					}
+				}
+
+				set_result
+			}
+		};
+
+		if set_result.is_err() {
 			error!("IPMI control failed, trying to restore automatic fan control and exiting");
 
-			match set_fan_manual(false, shadow, Some(metrics)) {
+			match set_fan_manual(false, fans, Some(metrics)) {
 				Ok(_) => info!("Restored automatic fan control"),
 				Err(e) => error!("Failed to restore automatic fan control: {:?}", e)
 			}
@@ -275,16 +802,375 @@ fn main_loop(shadow: bool, config: AppConfig) {
 			::std::process::exit(1);
 		}
 
-		match metrics::get_proc_usage() {
-			Ok(cpu) => metrics::report_metric(&[("cpu_usage".to_string(), cpu)], &[], metrics),
-			Err(e) => error!("Unable to report cpu usage: {}", e)
+		let controls = control_loop.status();
+		let mode = match (manual, &hold) {
+			(_, Some((Hold::Pause, _))) => Mode::Paused,
+			(false, _) => Mode::Bmc,
+			(true, Some(_)) => Mode::Override,
+			(true, None) => Mode::Pid
+		};
+
+		if let Some(out) = recorder.as_mut() {
+			let (_, time) = schedule::Clock::now(&clock);
+
+			let frame = replay::Frame {
+				timestamp: status::now_ms(),
+				elapsed,
+				weekday: time.weekday,
+				minute: time.minute,
+				load,
+				sensors: control_loop.readings().map(|v| v.to_vec()),
+				controls: controls.clone(),
+				duty,
+				mode
+			};
+
+			if let Err(e) = out.record(&frame) {
+				error!("Unable to record pass, recording stopped: {}", e);
+				recorder = None;
+			}
 		}
 
+		publisher.publish(&Snapshot {
+			timestamp: status::now_ms(),
+			controls,
+			winner: control_loop.winner().map(|v| v.to_string()),
+			schedule: scheduler.active().map(|v| v.to_string()),
+			fans: fan_status,
+			duty,
+			mode,
+			shadow,
+			override_remaining: hold.as_ref()
+				.and_then(|(_, until)| *until)
+				.map(|until| until.saturating_duration_since(now).as_secs()),
+			last_error: last_error.clone(),
+			fan_reasserts,
+			budget: budget.as_ref().map(|v| v.status(now))
+		});
+
+
 		daemon::notify(false, [(daemon::STATE_WATCHDOG,"1")].iter()).unwrap_or(false);
 	}
 }
 
-fn set_fan_manual(manual: bool, shadow: bool, metric_sender: Option<&metrics::MetricSender>) -> Result<()> {
+/// Fan curves saved by the calibrate subcommand, None if the fans haven't been calibrated.
+fn load_calibration(config: &AppConfig) -> Option<Calibration> {
+	let path = config.calibration_path();
+
+	match Calibration::load(&path) {
+		Ok(v) => {
+			info!("Loaded fan calibration from {}", path);
+			Some(v)
+		},
+		Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
+			debug!("No fan calibration at {}", path);
+			None
+		},
+		Err(e) => {
+			warn!("Ignoring fan calibration at {}: {}", path, e);
+			None
+		}
+	}
+}
+
+/// Warns when the unit's watchdog is shorter than the slowest pass, I.E. with a unit installed by an older version.
+fn check_watchdog(config: &AppConfig) {
+	let watchdog = match ::std::env::var("WATCHDOG_USEC").ok().and_then(|v| v.parse::<u64>().ok()) {
+		Some(usec) => Duration::from_micros(usec),
+		None => return
+	};
+
+	if let Ok(worst_case) = config.worst_case_pass() {
+		if worst_case >= watchdog {
+			warn!("A pass can take up to {}s if the BMC stops answering, which would trip WatchdogSec={} of the service, raise it to {}",
+				worst_case.as_secs_f32(), watchdog.as_secs_f32(), ipmitool::WATCHDOG.as_secs());
+		}
+	}
+}
+
+/// BMC thresholds for every sensor, empty if they can't be read. They don't change at runtime so this is only done at startup.
+fn read_thresholds() -> Vec<SensorInfo> {
+	get_sensor_thresholds().unwrap_or_else(|e| {
+		warn!("Unable to read BMC sensor thresholds: {}", e);
+		vec!()
+	})
+}
+
+/// Builds controls and sensor sources from config, see `build_controls`.
+///
+/// On reload `previous` holds the last readings of the running loop, which fill in sources read in the background until
+/// their first readings. Without it this waits for those readings instead, which is only done at startup.
+fn build_control_loop(config: &AppConfig, sensors: &[SensorInfo], previous: Option<&[SdrEntry]>) -> ::std::result::Result<ControlLoop, String> {
+	let mut control_loop = build_controls(config, sensors)?;
+
+	if let Some(drives) = config.drives()? {
+		control_loop.add_source(Box::new(drives::DriveSource::start(drives)));
+	}
+
+	for sensor in config.command_sensors()? {
+		control_loop.add_source(Box::new(command_sensor::CommandSensor::start(sensor)));
+	}
+
+	match previous {
+		Some(previous) => control_loop.seed_sources(previous),
+		None => control_loop.wait_for_sources(SOURCE_STARTUP_TIMEOUT)
+	}
+
+	Ok(control_loop)
+}
+
+/// Builds controls from config, filling in missing failsafes from BMC thresholds and refusing
+/// any failsafe above the BMC's critical threshold.
+fn build_controls(config: &AppConfig, sensors: &[SensorInfo]) -> ::std::result::Result<ControlLoop, String> {
+	let pid_settings = config.pid_settings();
+	let filter_points = config.filter_points();
+
+	let mut control_loop = ControlLoop::new(config.aggregation()?);
+
+	for sensor in config.virtual_sensors()? {
+		control_loop.add_virtual_sensor(sensor);
+	}
+
+	for control in config.controls() {
+		let selector = control.selector()?;
+		let label = selector.label();
+
+		let mut matches = sensors.iter().filter(|v| selector.matches_sensor(v));
+		let thresholds = match (matches.next(), matches.next()) {
+			(Some(sensor), None) => sensor.thresholds.clone(),
+			_ => Thresholds::default()
+		};
+
+		let failsafe = match (control.failsafe, thresholds.default_failsafe()) {
+			(Some(failsafe), _) => failsafe,
+			(None, Some(failsafe)) => {
+				info!("Using failsafe of {} for {} from BMC thresholds", failsafe, label);
+				failsafe
+			},
+			(None, None) => return Err(format!("{} has no failsafe configured and the BMC reports no upper threshold for it", label))
+		};
+
+		match thresholds.critical() {
+			Some(critical) if failsafe > critical => {
+				return Err(format!("failsafe of {} for {} is above the BMC critical threshold of {}", failsafe, label, critical))
+			},
+			Some(_) => (),
+			None => warn!("No BMC critical threshold for {}, unable to sanity check failsafe of {}", label, failsafe)
+		}
+
+		if control.setpoint >= failsafe {
+			return Err(format!("setpoint of {} is {} which is not below its failsafe of {}", label, control.setpoint, failsafe))
+		}
+
+		let ambient = control.ambient()?;
+		match ambient.as_ref() {
+			Some(ambient) if ambient.max >= failsafe => {
+				return Err(format!("ambient max of {} for {} is not below its failsafe of {}", ambient.max, label, failsafe))
+			},
+			_ => ()
+		}
+
+		match control.emergency {
+			Some(emergency) if emergency >= failsafe => {
+				return Err(format!("emergency of {} for {} is not below its failsafe of {}", emergency, label, failsafe))
+			},
+			_ => ()
+		}
+
+		control_loop.add_control(selector, ControlSettings {
+			setpoint: control.setpoint,
+			failsafe,
+			tuning: pid_settings,
+			filter_points,
+			thresholds,
+			feed_forward: control.feed_forward()?,
+			weight: control.weight.unwrap_or(1.0),
+			emergency: control.emergency,
+			ambient
+		});
+	}
+
+	for period in config.schedule_periods()? {
+		for (label, setpoint) in period.setpoints.iter() {
+			match control_loop.failsafe(label) {
+				Some(failsafe) if *setpoint >= failsafe => {
+					return Err(format!("schedule {} setpoint of {} for {} is not below its failsafe of {}", period.name, setpoint, label, failsafe))
+				},
+				_ => ()
+			}
+		}
+	}
+
+	Ok(control_loop)
+}
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+	RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Turns SIGHUP(which would otherwise terminate us) into a reload request picked up by the main loop.
+fn register_reload_signal() -> &'static AtomicBool {
+	// Only touches an atomic so it is async-signal-safe
+	let handler: extern "C" fn(libc::c_int) = on_sighup;
+	unsafe {
+		libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+	}
+
+	&RELOAD_REQUESTED
+}
+
+/// Duty override or pause requested through a command interface.
+enum Hold {
+	Duty(f32),
+	Pause
+}
+
+fn apply_command(command: Command, hold: &mut Option<(Hold, Option<Instant>)>, control_loop: &mut ControlLoop) -> ::std::result::Result<(), String> {
+	let now = Instant::now();
+
+	match command {
+		Command::SetDuty { duty, minutes } => {
+			if !(0.0..=1.0).contains(&duty) {
+				return Err(format!("duty {} is outside of 0.0-1.0", duty))
+			}
+
+			info!("Overriding fan duty to {}", duty);
+			*hold = Some((Hold::Duty(duty), Command::duration(minutes).map(|v| now + v)));
+		},
+		Command::Pause { minutes } => {
+			info!("Pausing fan control");
+			*hold = Some((Hold::Pause, Command::duration(minutes).map(|v| now + v)));
+		},
+		Command::SetSetpoint { control, setpoint, minutes } => {
+			control_loop.override_setpoint(&control, setpoint, Command::duration(minutes).map(|v| now + v))?;
+		},
+		Command::Resume => {
+			info!("Resuming PID fan control");
+			*hold = None;
+			control_loop.clear_overrides();
+		}
+	}
+
+	Ok(())
+}
+
+fn client_command(path: &str, command: Command) {
+	match socket::request(path, &socket::Request::Command(command)) {
+		Ok(socket::Response::Ok) => println!("OK"),
+		Ok(socket::Response::Error(e)) => {
+			eprintln!("Command rejected: {}", e);
+			::std::process::exit(1);
+		},
+		Ok(response) => {
+			eprintln!("Unexpected response: {:?}", response);
+			::std::process::exit(1);
+		},
+		Err(e) => {
+			eprintln!("{}", e);
+			::std::process::exit(1);
+		}
+	}
+}
+
+fn client_override(matches: &clap::ArgMatches) {
+	let path = matches.value_of("socket").unwrap_or(socket::DEFAULT_PATH);
+
+	let parse = |name: &str| -> Option<f32> {
+		matches.value_of(name).map(|v| v.parse::<f32>().unwrap_or_else(|_| {
+			eprintln!("--{} expects a number, got \"{}\"", name, v);
+			::std::process::exit(1);
+		}))
+	};
+	let minutes = parse("minutes");
+
+	let command = if let Some(duty) = parse("duty") {
+		Command::SetDuty { duty: duty / 100.0, minutes }
+	} else if let Some(setpoint) = parse("setpoint") {
+		Command::SetSetpoint {
+			control: matches.value_of("control").unwrap_or_default().to_string(),
+			setpoint,
+			minutes
+		}
+	} else {
+		Command::Pause { minutes }
+	};
+
+	client_command(path, command);
+}
+
+fn client_status(path: &str) {
+	let snapshot = match socket::request(path, &socket::Request::Status) {
+		Ok(socket::Response::Status(Some(snapshot))) => snapshot,
+		Ok(socket::Response::Status(None)) => {
+			println!("Thermal Watchdog has not completed a control loop yet");
+			return
+		},
+		Ok(response) => {
+			eprintln!("Unexpected response: {:?}", response);
+			::std::process::exit(1);
+		},
+		Err(e) => {
+			eprintln!("{}", e);
+			::std::process::exit(1);
+		}
+	};
+
+	let duty = snapshot.duty.map(|v| format!("{:.1}%", v * 100.0)).unwrap_or_else(|| "-".to_string());
+	let shadow = if snapshot.shadow { " (shadow)" } else { "" };
+	println!("Mode: {}{}  Duty: {}", snapshot.mode.as_str(), shadow, duty);
+
+	if let Some(remaining) = snapshot.override_remaining {
+		println!("Override expires in {}m{}s", remaining / 60, remaining % 60);
+	}
+
+	if let Some(budget) = snapshot.budget.as_ref() {
+		let cause = budget.cause.as_ref().map(|v| format!(", most recently by {}", v)).unwrap_or_default();
+		println!("Acoustic budget: {}, exceeded {} times for {}s{}", if budget.exceeded { "exceeded" } else { "ok" }, budget.count, budget.exceeded_secs, cause);
+	}
+
+	if let Some(schedule) = snapshot.schedule.as_ref() {
+		println!("Schedule: {}", schedule);
+	}
+
+	if let Some(winner) = snapshot.winner.as_ref() {
+		println!("Driven by: {}", winner);
+	}
+
+	if let Some(error) = snapshot.last_error.as_ref() {
+		println!("Last pass failed: {}", error);
+	}
+
+	if snapshot.fan_reasserts > 0 {
+		println!("Manual fan control re-asserted {} times since startup", snapshot.fan_reasserts);
+	}
+
+	println!();
+	println!("{:<24} {:>6} {:>9} {:>9} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}", "Control", "Temp", "Setpoint", "Failsafe", "P", "I", "D", "FF", "V", "Share");
+
+	for control in snapshot.controls {
+		let temp = control.temp.map(|v| format!("{:.1}", v)).unwrap_or_else(|| "-".to_string());
+		let setpoint = format!("{:.1}{}", control.setpoint, if control.setpoint_override { "*" } else { "" });
+
+		println!("{:<24} {:>6} {:>9} {:>9.1} {:>8.3} {:>8.3} {:>8.3} {:>8.3} {:>8.3} {:>8.3}",
+			control.label, temp, setpoint, control.failsafe, control.p, control.i, control.d, control.ff, control.v, control.contribution);
+	}
+
+	if !snapshot.fans.is_empty() {
+		println!();
+		println!("{:<24} {:>6} {:>9} {:>7}", "Fan", "RPM", "Expected", "Health");
+
+		for fan in snapshot.fans {
+			let expected = fan.expected.map(|v| format!("{:.0}", v)).unwrap_or_else(|| "-".to_string());
+			let health = fan.health.map(|v| format!("{:.0}%", v * 100.0)).unwrap_or_else(|| "-".to_string());
+
+			println!("{:<24} {:>6} {:>9} {:>7}", fan.name, fan.rpm, expected, health);
+		}
+	}
+}
+
+fn set_fan_manual(manual: bool, fans: Option<&FanProfile>, metric_sender: Option<&metrics::MetricSender>) -> Result<()> {
 	let value = if manual {
 		1.0
 	} else {
@@ -295,22 +1181,24 @@ fn set_fan_manual(manual: bool, shadow: bool, metric_sender: Option<&metrics::Me
 		metrics::report_metric(&[("manual control".to_string(), value)], &[], metric_sender);
 	}
 
-	if shadow {
-		trace!("Shadow: Setting manual fan control to {}", manual);
-		Ok(())
-	} else {
-		ipmi_set_fan_manual(manual)
+	match fans {
+		Some(profile) => ipmi_set_fan_manual(profile, manual),
+		None => {
+			trace!("Shadow: Setting manual fan control to {}", manual);
+			Ok(())
+		}
 	}
 }
 
-fn set_fan_speed(speed: f32, shadow: bool, metric_sender: &metrics::MetricSender) -> Result<()> {
+fn set_fan_speed(speed: f32, fans: Option<&FanProfile>, metric_sender: &metrics::MetricSender) -> Result<()> {
 	metrics::report_metric(&[("fan speed".to_string(), speed)], &[], metric_sender);
 
-	if shadow {
-		trace!("Shadow: Setting fan speed to {}", speed);
-		Ok(())
-	} else {
-		ipmi_set_fan_speed(speed)
+	match fans {
+		Some(profile) => ipmi_set_fan_speed(profile, speed),
+		None => {
+			trace!("Shadow: Setting fan speed to {}", speed);
+			Ok(())
+		}
 	}
 }
 
@@ -335,9 +1223,10 @@ Description=Thermal Watchdog
 [Service]
 Type=notify
 ExecStart=/usr/sbin/thermal_watchdog
-ExecStopPost=/usr/bin/ipmitool raw 0x30 0x30 0x01 0x01
+ExecReload=/bin/kill -HUP $MAINPID
+ExecStopPost=/usr/bin/timeout 10 /usr/sbin/thermal_watchdog restore
 Restart=on-failure
-WatchdogSec=10
+WatchdogSec=30
 
 [Install]
 WantedBy=multi-user.target
@@ -345,7 +1234,7 @@ WantedBy=multi-user.target
 	let conf_path = "/etc/systemd/system/thermal_watchdog.service";
 
 	if !Path::new(conf_path).exists() {
-		let mut service_file = ::std::fs::File::create(conf_path).expect(format!("Unable to open {}, are you running as root?", conf_path).as_str());
+		let mut service_file = ::std::fs::File::create(conf_path).unwrap_or_else(|_| panic!("Unable to open {}, are you running as root?", conf_path));
 		service_file.write_all(service_conf.as_bytes()).expect("Unable to write service file, are you running as root?");
 	} else {
 		info!("Skipped installing {} since it already exists", conf_path);
@@ -358,6 +1247,14 @@ r#"#[metrics]
 #influx_addr="http://localhost:8086"
 #influx_db="twd"
 
+#[mqtt]
+#host="localhost"
+#topic_prefix="thermal_watchdog/server"
+
+# Fan profile is detected from the BMC, set "dell" or "supermicro" to skip detection
+#[fans]
+#profile="auto"
+
 [pid]
 k_factor = 0.025
 i_factor = 0.000001
@@ -369,23 +1266,99 @@ name = "Exhaust Temp"
 setpoint = 40.0
 failsafe = 60.0
 
+# Sensors that share a name are told apart by entity or sensor number from "ipmitool sdr elist"
 [[controls]]
 name = "Temp"
+entity = "3.1"
 setpoint = 55.0
 failsafe = 65.0
 
 [[controls]]
 name = "Temp"
+entity = "3.2"
 setpoint = 55.0
 failsafe = 65.0
 "#;
 	let toml_path = "/etc/thermal_watchdog.toml";
 
 	if !Path::new(&toml_path).exists() {
-		let mut toml_file = ::std::fs::File::create(toml_path).expect(format!("Unable to open {}, are you running as root?", toml_path).as_str());
+		let mut toml_file = ::std::fs::File::create(toml_path).unwrap_or_else(|_| panic!("Unable to open {}, are you running as root?", toml_path));
 		toml_file.write_all(toml_conf.as_bytes()).expect("Unable to write config file, are you running as root?");
 	} else {
 		info!("Skipped installing {} since it already exists", toml_path);
 	}
 
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::aggregate::Aggregation;
+	use crate::control::ControlSettings;
+	use crate::ipmi::{SensorKind, SensorSelector, Thresholds};
+
+	fn control_loop() -> ControlLoop {
+		let mut control_loop = ControlLoop::new(Aggregation::Max);
+		control_loop.add_control(SensorSelector::new("Temp", None, None).unwrap(), ControlSettings {
+			setpoint: 60.0,
+			failsafe: 80.0,
+			tuning: (0.1, 0.0, 0.0),
+			filter_points: 1,
+			thresholds: Thresholds::default(),
+			feed_forward: None,
+			weight: 1.0,
+			emergency: None,
+			ambient: None
+		});
+		control_loop
+	}
+
+	#[test]
+	fn keeps_ambient_max_below_failsafe() {
+		let config = |failsafe: &str| config::parse_toml(&format!("[[controls]]\nname = \"Exhaust Temp\"\nsetpoint = 40.0\n{}\nambient = {{ name = \"Inlet Temp\", reference = 20.0, gain = 0.8, min = 35.0, max = 60.0 }}", failsafe)).unwrap();
+		let sensor = |critical: f32| SensorInfo {
+			name: "Exhaust Temp".to_string(),
+			number: None,
+			entity: None,
+			kind: SensorKind::Temp,
+			value: Some(30.0),
+			reading: "30.000".to_string(),
+			unit: "degrees C".to_string(),
+			status: "ok".to_string(),
+			thresholds: Thresholds { upper_critical: Some(critical), ..Thresholds::default() }
+		};
+
+		assert!(build_controls(&config("failsafe = 65.0"), &[]).is_ok());
+		assert!(build_controls(&config("failsafe = 60.0"), &[]).is_err());
+
+		// Failsafes from BMC thresholds are only known here
+		assert!(build_controls(&config(""), &[sensor(80.0)]).is_ok());
+		assert!(build_controls(&config(""), &[sensor(62.0)]).is_err());
+	}
+
+	#[test]
+	fn applies_commands() {
+		let mut control_loop = control_loop();
+		let mut hold = None;
+
+		assert!(apply_command(Command::SetDuty { duty: 1.5, minutes: None }, &mut hold, &mut control_loop).is_err());
+		assert!(hold.is_none());
+
+		apply_command(Command::SetDuty { duty: 0.4, minutes: Some(5.0) }, &mut hold, &mut control_loop).unwrap();
+		assert!(matches!(hold, Some((Hold::Duty(duty), Some(_))) if duty == 0.4));
+
+		apply_command(Command::Pause { minutes: None }, &mut hold, &mut control_loop).unwrap();
+		assert!(matches!(hold, Some((Hold::Pause, None))));
+
+		assert!(apply_command(Command::SetSetpoint { control: "Fan".to_string(), setpoint: 50.0, minutes: None }, &mut hold, &mut control_loop).is_err());
+		assert!(apply_command(Command::SetSetpoint { control: "Temp".to_string(), setpoint: 85.0, minutes: None }, &mut hold, &mut control_loop).is_err());
+		apply_command(Command::SetSetpoint { control: "Temp".to_string(), setpoint: 50.0, minutes: None }, &mut hold, &mut control_loop).unwrap();
+		assert_eq!(control_loop.status()[0].setpoint, 50.0);
+		assert!(control_loop.status()[0].setpoint_override);
+
+		apply_command(Command::Resume, &mut hold, &mut control_loop).unwrap();
+		assert!(hold.is_none());
+		assert_eq!(control_loop.status()[0].setpoint, 60.0);
+		assert!(!control_loop.status()[0].setpoint_override);
+	}
+}
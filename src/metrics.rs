@@ -7,25 +7,24 @@ use hyper::{Body, Client, Request};
 use futures::future;
 
 pub enum MetricEvent {
-	Metric(String),
+	Point(Point),
 	Exit
 }
 
 pub type MetricSender = mpsc::Sender<MetricEvent>;
 
-pub fn init_metric_thread(config: Option<(String,String,Option<String>,Option<String>)>) -> mpsc::Sender<MetricEvent> {
+/// Points are tagged with `hostname` and encoded here rather than by the main loop.
+pub fn init_metric_thread(config: Option<(String,String,Option<String>,Option<String>)>, hostname: Option<String>) -> mpsc::Sender<MetricEvent> {
 	let (send,recv) = mpsc::channel();
 
 	thread::spawn(move || {
 		loop {
 			match recv.recv() {
-				Ok(MetricEvent::Metric(mut event)) => {
+				Ok(MetricEvent::Point(point)) => {
+					let mut points = vec!(point);
 					loop {
 						match recv.try_recv() {
-							Ok(MetricEvent::Metric(next_event)) => {
-								event += "\n";
-								event += next_event.as_str();
-							},
+							Ok(MetricEvent::Point(next_point)) => points.push(next_point),
 							Ok(MetricEvent::Exit) | Err(mpsc::TryRecvError::Disconnected) => {
 								info!("Shutting down metrics thread");
 								return
@@ -35,7 +34,13 @@ pub fn init_metric_thread(config: Option<(String,String,Option<String>,Option<St
 					}
 
 					if let Some(config) = config.as_ref() {
-						send_metric(config, event);
+						let event = points.into_iter()
+							.filter_map(|point| encode(point, hostname.as_deref()))
+							.collect::<Vec<_>>();
+
+						if !event.is_empty() {
+							send_metric(config, event.join("\n"));
+						}
 					}
 				},
 				Ok(MetricEvent::Exit) | Err(_) => {
@@ -56,7 +61,7 @@ fn send_metric((host,db,user,pw): &(String,String,Option<String>,Option<String>)
 
 	let user = user.as_ref().map(|v| format!("&u={}",v)).unwrap_or(String::new());
 	let pw = pw.as_ref().map(|v| format!("&p={}",v)).unwrap_or(String::new());
-	let req = Request::post(format!("{}/write?db={}{}{}", host, db, user, pw))
+	let req = Request::post(format!("{}/write?db={}&precision=ns{}{}", host, db, user, pw))
 		.body(Body::from(event))
 		.expect("Failed to build request");
 
@@ -82,47 +87,154 @@ fn send_metric((host,db,user,pw): &(String,String,Option<String>,Option<String>)
 	rt::run(fut);
 }
 
-pub fn report_metric(event: &[(String,f32)], tags: &[(String,String)], sender: &mpsc::Sender<MetricEvent>) {
-	let hostname = get_hostname()
-		.map(|v| format!(",hostname={}", v))
-		.unwrap_or_else(|e| {
-			error!("Unable to include hostname: {}", e);
-			String::new()
-		});
+/// A single InfluxDB field value, encoded with the type suffix line protocol expects.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+	Float(f64),
+	Int(i64),
+	Bool(bool),
+	Str(String)
+}
 
-	let fields = event.iter().map(|(n,v)| format!("{}={}", n.replace(" ", "\\ "), v))
-					.fold(String::new(), |acc,v| {
-						if acc.len() > 0 {
-							acc + "," + v.as_str()
-						} else {
-							v
-						}
-					});
-	let tags = tags.iter().fold(hostname, |acc, (n,v)| {
-		acc + "," + n.replace(" ", "\\ ").as_str() + "=" + v.replace(" ", "\\ ").as_str()
-	});
-	let formatted = format!("thermal_watchdog{} {}", tags, fields);
+impl From<f32> for FieldValue {
+	fn from(v: f32) -> FieldValue {
+		FieldValue::Float(f64::from(v))
+	}
+}
+
+impl From<i64> for FieldValue {
+	fn from(v: i64) -> FieldValue {
+		FieldValue::Int(v)
+	}
+}
+
+impl From<bool> for FieldValue {
+	fn from(v: bool) -> FieldValue {
+		FieldValue::Bool(v)
+	}
+}
+
+impl From<String> for FieldValue {
+	fn from(v: String) -> FieldValue {
+		FieldValue::Str(v)
+	}
+}
+
+/// One line protocol point, stamped with the time it was sampled rather than when it reaches the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+	pub measurement: String,
+	pub tags: Vec<(String,String)>,
+	pub fields: Vec<(String,FieldValue)>,
+	pub timestamp: u64
+}
+
+impl Point {
+	pub fn new(measurement: &str, tags: Vec<(String,String)>, fields: Vec<(String,FieldValue)>) -> Point {
+		Point {
+			measurement: measurement.to_string(),
+			tags,
+			fields,
+			timestamp: now_ns()
+		}
+	}
 
-	trace!("Submitting metric: {}", formatted);
+	/// Encodes the point as a single line, returns None if no field has a representable value.
+	pub fn to_line(&self) -> Option<String> {
+		let fields = self.fields.iter()
+			.filter_map(|(n,v)| encode_field_value(v).map(|v| format!("{}={}", escape_key(n), v)))
+			.collect::<Vec<_>>();
 
-	sender.send(MetricEvent::Metric(formatted))
+		if fields.is_empty() {
+			return None
+		}
+
+		let tags = self.tags.iter()
+			.filter(|(_,v)| !v.is_empty())
+			.fold(String::new(), |acc, (n,v)| acc + "," + escape_key(n).as_str() + "=" + escape_key(v).as_str());
+
+		Some(format!("{}{} {} {}", escape_measurement(&self.measurement), tags, fields.join(","), self.timestamp))
+	}
+}
+
+fn now_ns() -> u64 {
+	let since_epoch = ::std::time::SystemTime::now()
+		.duration_since(::std::time::UNIX_EPOCH)
+		.unwrap_or_default();
+
+	since_epoch.as_secs() * 1_000_000_000 + u64::from(since_epoch.subsec_nanos())
+}
+
+fn escape_measurement(v: &str) -> String {
+	v.replace('\\', "\\\\")
+		.replace('\n', " ")
+		.replace(',', "\\,")
+		.replace(' ', "\\ ")
+}
+
+fn escape_key(v: &str) -> String {
+	v.replace('\\', "\\\\")
+		.replace('\n', " ")
+		.replace(',', "\\,")
+		.replace('=', "\\=")
+		.replace(' ', "\\ ")
+}
+
+fn encode_field_value(v: &FieldValue) -> Option<String> {
+	match v {
+		// Line protocol has no representation for NaN or infinity, drop the field instead of the whole batch
+		FieldValue::Float(f) if !f.is_finite() => None,
+		FieldValue::Float(f) => Some(format!("{}", f)),
+		FieldValue::Int(i) => Some(format!("{}i", i)),
+		FieldValue::Bool(b) => Some(format!("{}", b)),
+		FieldValue::Str(s) => Some(format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))),
+	}
+}
+
+pub fn report_metric(event: &[(String,f32)], tags: &[(String,String)], sender: &mpsc::Sender<MetricEvent>) {
+	let fields = event.iter()
+		.map(|(n,v)| (n.clone(), FieldValue::from(*v)))
+		.collect::<Vec<_>>();
+
+	report_point(Point::new("thermal_watchdog", tags.to_vec(), fields), sender);
+}
+
+pub fn report_point(point: Point, sender: &mpsc::Sender<MetricEvent>) {
+	sender.send(MetricEvent::Point(point))
 		.unwrap_or_else(|e| {
 			error!("Unable to write metric to sender: {:?}", e);
 		});
 }
 
-fn get_hostname() -> Result<String,String> {
-	let cmd = ::std::process::Command::new("hostname")
-		.output()
-		.map_err(|e| format!("Unable to run commandL {:?}", e))?;
+fn encode(mut point: Point, hostname: Option<&str>) -> Option<String> {
+	if let Some(hostname) = hostname {
+		point.tags.insert(0, ("hostname".to_string(), hostname.to_string()));
+	}
+
+	match point.to_line() {
+		Some(line) => {
+			trace!("Submitting metric: {}", line);
+			Some(line)
+		},
+		None => {
+			trace!("Skipping metric with no valid fields: {:?}", point);
+			None
+		}
+	}
+}
+
+pub fn get_hostname() -> Result<String,String> {
+	let mut name = [0u8; 256];
 
-	if !cmd.status.success() {
-		return Err("Command returned non-zero exit code".to_string())
+	if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+		return Err(format!("Unable to get hostname: {}", ::std::io::Error::last_os_error()))
 	}
 
-	::std::str::from_utf8(&cmd.stdout[..])
-		.map(|v| v.trim().to_string())
-		.map_err(|e| format!("Unable to get command output: {:?}", e))
+	let len = name.iter().position(|v| *v == 0).unwrap_or(name.len());
+
+	::std::str::from_utf8(&name[..len])
+		.map(|v| v.to_string())
+		.map_err(|e| format!("Unable to read hostname: {:?}", e))
 }
 
 pub fn get_proc_usage() -> Result<f32,String> {
@@ -155,12 +267,188 @@ fn get_cpu_stats() -> Result<(usize,usize,usize),String> {
 
 		let stats = split.iter()
 			.skip(1)
-			.map(|v| usize::from_str_radix(v, 10)
+			.map(|v| v.parse::<usize>()
 					.map_err(|_| format!("Unable to parse \"{}\"", v)))
 			.collect::<Result<Vec<_>,_>>()?;
 
 		Ok((stats[0], stats[2], stats[3]))
 	} else {
-		return Ok((0,0,0))
+		Ok((0,0,0))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Splits on `sep` outside of quotes, honoring backslash escapes
+	fn split_unescaped(line: &str, sep: char) -> Vec<String> {
+		let mut parts = vec!(String::new());
+		let mut escaped = false;
+		let mut quoted = false;
+
+		for c in line.chars() {
+			if escaped {
+				parts.last_mut().unwrap().push('\\');
+				parts.last_mut().unwrap().push(c);
+				escaped = false;
+			} else if c == '\\' {
+				escaped = true;
+			} else if c == '"' {
+				quoted = !quoted;
+				parts.last_mut().unwrap().push(c);
+			} else if c == sep && !quoted {
+				parts.push(String::new());
+			} else {
+				parts.last_mut().unwrap().push(c);
+			}
+		}
+
+		parts
+	}
+
+	fn unescape(v: &str) -> String {
+		let mut out = String::new();
+		let mut chars = v.chars();
+
+		while let Some(c) = chars.next() {
+			if c == '\\' {
+				out.extend(chars.next());
+			} else {
+				out.push(c);
+			}
+		}
+
+		out
+	}
+
+	fn split_pair(v: &str) -> (String, String) {
+		let parts = split_unescaped(v, '=');
+		assert_eq!(parts.len(), 2, "malformed pair {}", v);
+		(unescape(&parts[0]), parts[1].clone())
+	}
+
+	fn parse_value(v: &str) -> FieldValue {
+		if v.starts_with('"') {
+			let inner = &v[1..v.len() - 1];
+			FieldValue::Str(unescape(&inner.replace("\\n", "\n")))
+		} else if let Some(i) = v.strip_suffix('i') {
+			FieldValue::Int(i.parse().unwrap())
+		} else if v == "true" || v == "false" {
+			FieldValue::Bool(v == "true")
+		} else {
+			FieldValue::Float(v.parse().unwrap())
+		}
+	}
+
+	fn parse_line(line: &str) -> Point {
+		let sections = split_unescaped(line, ' ');
+		assert_eq!(sections.len(), 3, "expected measurement, fields and timestamp in {}", line);
+
+		let series = split_unescaped(&sections[0], ',');
+		let tags = series[1..].iter().map(|v| {
+			let (k,v) = split_pair(v);
+			(k, unescape(&v))
+		}).collect();
+		let fields = split_unescaped(&sections[1], ',').iter().map(|v| {
+			let (k,v) = split_pair(v);
+			(k, parse_value(&v))
+		}).collect();
+
+		Point {
+			measurement: unescape(&series[0]),
+			tags,
+			fields,
+			timestamp: sections[2].parse().unwrap()
+		}
+	}
+
+	fn round_trip(point: Point) {
+		let line = point.to_line().expect("point should encode");
+		assert!(!line.contains('\n'));
+		assert_eq!(parse_line(&line), point, "round trip of {}", line);
+	}
+
+	#[test]
+	fn round_trip_field_types() {
+		round_trip(Point {
+			measurement: "thermal_watchdog".to_string(),
+			tags: vec!(("hostname".to_string(), "r710".to_string())),
+			fields: vec!(
+				("temp".to_string(), FieldValue::Float(41.5)),
+				("count".to_string(), FieldValue::Int(-12)),
+				("manual".to_string(), FieldValue::Bool(true)),
+				("mode".to_string(), FieldValue::Str("auto".to_string()))
+			),
+			timestamp: 1_550_000_000_123_456_789
+		});
+	}
+
+	#[test]
+	fn round_trip_escaping() {
+		round_trip(Point {
+			measurement: "thermal watchdog,v2\\".to_string(),
+			tags: vec!(
+				("sensor name".to_string(), "Temp,CPU=1 (0)".to_string()),
+				("a=b".to_string(), "c d".to_string()),
+				("path\\".to_string(), "C:\\Temp\\ dir\\".to_string())
+			),
+			fields: vec!(
+				("fan speed".to_string(), FieldValue::Float(0.25)),
+				("x,y=z".to_string(), FieldValue::Str("say \"hi\", C:\\ bad=1".to_string()))
+			),
+			timestamp: 42
+		});
+	}
+
+	#[test]
+	fn encodes_types_and_timestamp() {
+		let point = Point {
+			measurement: "m".to_string(),
+			tags: vec!(),
+			fields: vec!(
+				("f".to_string(), FieldValue::Float(1.0)),
+				("i".to_string(), FieldValue::Int(3)),
+				("b".to_string(), FieldValue::Bool(false)),
+				("s".to_string(), FieldValue::Str("a\"b".to_string()))
+			),
+			timestamp: 7
+		};
+
+		assert_eq!(point.to_line().unwrap(), "m f=1,i=3i,b=false,s=\"a\\\"b\" 7");
+	}
+
+	#[test]
+	fn drops_unrepresentable_fields() {
+		let mut point = Point::new("m", vec!(("empty".to_string(), String::new())), vec!(
+			("nan".to_string(), FieldValue::Float(f64::NAN)),
+			("ok".to_string(), FieldValue::Int(1))
+		));
+		point.timestamp = 1;
+
+		assert_eq!(point.to_line().unwrap(), "m ok=1i 1");
+
+		point.fields.pop();
+		assert_eq!(point.to_line(), None);
+	}
+
+	#[test]
+	fn points_are_stamped_at_sample_time() {
+		let before = now_ns();
+		let point = Point::new("m", vec!(), vec!(("v".to_string(), FieldValue::Int(1))));
+		let after = now_ns();
+
+		assert!(point.timestamp >= before && point.timestamp <= after);
+	}
+
+	#[test]
+	fn tags_hostname() {
+		let point = Point { measurement: "m".to_string(), tags: vec!(("pid".to_string(), "Temp".to_string())), fields: vec!(("v".to_string(), FieldValue::Int(1))), timestamp: 1 };
+
+		assert_eq!(encode(point.clone(), Some("r710")).unwrap(), "m,hostname=r710,pid=Temp v=1i 1");
+		assert_eq!(encode(point, None).unwrap(), "m,pid=Temp v=1i 1");
+
+		let expected = ::std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+		assert_eq!(get_hostname().unwrap(), expected.trim());
 	}
 }
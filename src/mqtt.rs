@@ -0,0 +1,568 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::status::{Command, CommandRequest, CommandSender, ControlStatus, Snapshot};
+
+const KEEP_ALIVE_SECS: u16 = 60;
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+const CONNACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+
+#[derive(Deserialize, Clone)]
+pub struct MqttConfig {
+	pub host: String,
+	pub port: Option<u16>,
+	pub client_id: Option<String>,
+	pub username: Option<String>,
+	pub password: Option<String>,
+	/// Root for all state and command topics, defaults to "thermal_watchdog/<hostname>".
+	pub topic_prefix: Option<String>,
+	/// Home Assistant discovery prefix, defaults to "homeassistant".
+	pub discovery_prefix: Option<String>,
+	/// Set to false to skip publishing Home Assistant discovery configs.
+	pub discovery: Option<bool>
+}
+
+/// Resolved topic layout for a single daemon instance.
+struct Topics {
+	prefix: String,
+	node_id: String,
+	discovery_prefix: Option<String>
+}
+
+impl Topics {
+	fn new(config: &MqttConfig, hostname: &str) -> Topics {
+		let prefix = config.topic_prefix.clone()
+			.unwrap_or_else(|| format!("thermal_watchdog/{}", hostname));
+		let discovery_prefix = if config.discovery.unwrap_or(true) {
+			Some(config.discovery_prefix.clone().unwrap_or_else(|| "homeassistant".to_string()))
+		} else {
+			None
+		};
+
+		Topics {
+			node_id: slug(&prefix),
+			prefix,
+			discovery_prefix
+		}
+	}
+
+	fn availability(&self) -> String {
+		format!("{}/availability", self.prefix)
+	}
+
+	fn command(&self) -> String {
+		format!("{}/command", self.prefix)
+	}
+
+	fn state(&self, name: &str) -> String {
+		format!("{}/{}", self.prefix, name)
+	}
+
+	fn control(&self, control: &ControlStatus) -> String {
+		format!("{}/control/{}/temperature", self.prefix, slug(&control.label))
+	}
+}
+
+pub fn init_mqtt_thread(config: MqttConfig, snapshots: mpsc::Receiver<Snapshot>, commands: CommandSender) {
+	thread::spawn(move || {
+		let hostname = crate::metrics::get_hostname().unwrap_or_else(|e| {
+			error!("Unable to determine hostname for MQTT topics: {}", e);
+			"unknown".to_string()
+		});
+		let topics = Topics::new(&config, hostname.as_str());
+
+		loop {
+			match run_session(&config, &topics, &hostname, &snapshots, &commands) {
+				Ok(_) => {
+					info!("Shutting down MQTT thread");
+					return
+				},
+				Err(e) => error!("MQTT session to {} failed: {}", config.host, e)
+			}
+
+			// Drain anything queued while disconnected so we resume with fresh state
+			thread::sleep(RECONNECT_DELAY);
+			while snapshots.try_recv().is_ok() {}
+		}
+	});
+}
+
+/// Runs a single broker connection, returns Ok(()) only when the daemon is shutting down.
+fn run_session(config: &MqttConfig, topics: &Topics, hostname: &str, snapshots: &mpsc::Receiver<Snapshot>, commands: &CommandSender) -> Result<()> {
+	let mut stream = connect(config, topics, CONNACK_TIMEOUT)?;
+
+	stream.write_all(&subscribe_packet(1, &topics.command()))?;
+	info!("Connected to MQTT broker {}", config.host);
+
+	let mut reader = stream.try_clone()?;
+	let command_topic = topics.command();
+	let commands = commands.clone();
+	thread::spawn(move || {
+		loop {
+			match read_packet(&mut reader) {
+				Ok((header, body)) if header & 0xF0 == PUBLISH => {
+					match parse_publish(header, &body) {
+						Some((topic, payload)) if topic == command_topic => {
+							match parse_command(&payload) {
+								Ok(command) => {
+									info!("MQTT command: {:?}", command);
+									if commands.send(CommandRequest { command, reply: None }).is_err() {
+										return
+									}
+								},
+								Err(e) => warn!("Ignoring MQTT command: {}", e)
+							}
+						},
+						_ => trace!("Ignoring unexpected MQTT publish")
+					}
+				},
+				Ok((SUBACK, _)) | Ok((PINGRESP, _)) => (),
+				Ok((header, _)) => trace!("Ignoring MQTT packet {:x}", header),
+				Err(e) => {
+					trace!("MQTT reader exiting: {}", e);
+					return
+				}
+			}
+		}
+	});
+
+	let result = publish_loop(&mut stream, topics, hostname, snapshots);
+	stream.shutdown(Shutdown::Both).unwrap_or(());
+
+	result
+}
+
+/// Opens a connection and waits up to `timeout` for the broker to accept it.
+fn connect(config: &MqttConfig, topics: &Topics, timeout: Duration) -> Result<TcpStream> {
+	let mut stream = TcpStream::connect((config.host.as_str(), config.port.unwrap_or(1883)))?;
+	let client_id = config.client_id.clone().unwrap_or_else(|| format!("thermal_watchdog_{}", topics.node_id));
+
+	stream.write_all(&connect_packet(&client_id, config.username.as_deref(), config.password.as_deref(), &topics.availability()))?;
+
+	// A broker that accepts the connection but never answers would otherwise hold the session forever
+	stream.set_read_timeout(Some(timeout))?;
+	let (header, body) = read_packet(&mut stream)?;
+	if header != CONNACK || body.len() < 2 || body[1] != 0 {
+		return Err(Error::new(ErrorKind::ConnectionRefused, format!("Broker refused connection: {:?}", body)))
+	}
+
+	// Commands can be hours apart, the reader thread waits for them without a timeout
+	stream.set_read_timeout(None)?;
+
+	Ok(stream)
+}
+
+fn publish_loop(stream: &mut TcpStream, topics: &Topics, hostname: &str, snapshots: &mpsc::Receiver<Snapshot>) -> Result<()> {
+	stream.write_all(&publish_packet(&topics.availability(), b"online", true))?;
+
+	let mut announced: Option<Vec<String>> = None;
+
+	loop {
+		match snapshots.recv_timeout(Duration::from_secs(u64::from(KEEP_ALIVE_SECS) / 2)) {
+			Ok(snapshot) => {
+				let labels = snapshot.controls.iter().map(|v| v.label.clone()).collect::<Vec<_>>();
+
+				if announced.as_ref() != Some(&labels) {
+					if let Some(discovery_prefix) = topics.discovery_prefix.as_ref() {
+						for (topic, payload) in discovery_configs(topics, discovery_prefix, hostname, &snapshot.controls) {
+							stream.write_all(&publish_packet(&topic, payload.as_bytes(), true))?;
+						}
+					}
+
+					announced = Some(labels);
+				}
+
+				for (topic, payload) in state_messages(topics, &snapshot) {
+					stream.write_all(&publish_packet(&topic, payload.as_bytes(), true))?;
+				}
+			},
+			Err(mpsc::RecvTimeoutError::Timeout) => stream.write_all(&[PINGREQ, 0])?,
+			Err(mpsc::RecvTimeoutError::Disconnected) => {
+				stream.write_all(&publish_packet(&topics.availability(), b"offline", true))?;
+				return Ok(())
+			}
+		}
+	}
+}
+
+fn state_messages(topics: &Topics, snapshot: &Snapshot) -> Vec<(String, String)> {
+	let mut messages = vec!(
+		(topics.state("mode"), snapshot.mode.as_str().to_string()),
+		(topics.state("failsafe"), if snapshot.failsafe() { "ON" } else { "OFF" }.to_string())
+	);
+
+	if let Some(duty) = snapshot.duty {
+		messages.push((topics.state("fan_duty"), format!("{:.1}", duty * 100.0)));
+	}
+
+	for control in snapshot.controls.iter() {
+		if let Some(temp) = control.temp {
+			messages.push((topics.control(control), format!("{}", temp)));
+		}
+	}
+
+	messages
+}
+
+fn discovery_configs(topics: &Topics, discovery_prefix: &str, hostname: &str, controls: &[ControlStatus]) -> Vec<(String, String)> {
+	let device = serde_json::json!({
+		"identifiers": [topics.node_id],
+		"name": format!("Thermal Watchdog {}", hostname),
+		"manufacturer": "Thermal Watchdog"
+	});
+
+	let entity = |component: &str, object_id: &str, name: &str, mut config: serde_json::Value| {
+		config["name"] = serde_json::Value::from(name);
+		config["unique_id"] = serde_json::Value::from(format!("{}_{}", topics.node_id, object_id));
+		config["availability_topic"] = serde_json::Value::from(topics.availability());
+		config["device"] = device.clone();
+
+		(format!("{}/{}/{}/{}/config", discovery_prefix, component, topics.node_id, object_id), config.to_string())
+	};
+
+	let mut configs = vec!(
+		entity("sensor", "fan_duty", "Fan duty", serde_json::json!({
+			"state_topic": topics.state("fan_duty"),
+			"unit_of_measurement": "%",
+			"state_class": "measurement",
+			"icon": "mdi:fan"
+		})),
+		entity("sensor", "mode", "Control mode", serde_json::json!({
+			"state_topic": topics.state("mode"),
+			"icon": "mdi:cog"
+		})),
+		entity("binary_sensor", "failsafe", "Failsafe", serde_json::json!({
+			"state_topic": topics.state("failsafe"),
+			"device_class": "problem",
+			"payload_on": "ON",
+			"payload_off": "OFF"
+		})),
+		entity("number", "fan_override", "Fan duty override", serde_json::json!({
+			"command_topic": topics.command(),
+			"state_topic": topics.state("fan_duty"),
+			"unit_of_measurement": "%",
+			"min": 0,
+			"max": 100,
+			"step": 1,
+			"mode": "slider"
+		})),
+		entity("button", "resume", "Resume automatic control", serde_json::json!({
+			"command_topic": topics.command(),
+			"payload_press": "auto"
+		}))
+	);
+
+	for control in controls {
+		let object_id = format!("{}_temp", slug(&control.label));
+
+		configs.push(entity("sensor", object_id.as_str(), control.label.as_str(), serde_json::json!({
+			"state_topic": topics.control(control),
+			"device_class": "temperature",
+			"unit_of_measurement": "°C",
+			"state_class": "measurement"
+		})));
+	}
+
+	configs
+}
+
+/// Accepts "auto"/"resume" to return to PID control or a duty percentage(0-100) to override it.
+fn parse_command(payload: &[u8]) -> ::std::result::Result<Command, String> {
+	let payload = ::std::str::from_utf8(payload)
+		.map_err(|_| "command is not valid utf-8".to_string())?
+		.trim()
+		.to_lowercase();
+
+	match payload.as_str() {
+		"auto" | "resume" => Ok(Command::Resume),
+		v => {
+			let duty = v.parse::<f32>()
+				.map_err(|_| format!("unrecognized command \"{}\"", v))?;
+
+			if !(0.0..=100.0).contains(&duty) {
+				return Err(format!("duty {} is outside of 0-100", duty))
+			}
+
+			Ok(Command::SetDuty { duty: duty / 100.0, minutes: None })
+		}
+	}
+}
+
+fn slug(v: &str) -> String {
+	let mut slug = String::new();
+
+	for c in v.chars() {
+		if c.is_ascii_alphanumeric() {
+			slug.push(c.to_ascii_lowercase());
+		} else if !slug.is_empty() && !slug.ends_with('_') {
+			slug.push('_');
+		}
+	}
+
+	slug.trim_end_matches('_').to_string()
+}
+
+fn push_remaining_length(packet: &mut Vec<u8>, mut len: usize) {
+	loop {
+		let mut byte = (len % 128) as u8;
+		len /= 128;
+
+		if len > 0 {
+			byte |= 0x80;
+		}
+
+		packet.push(byte);
+
+		if len == 0 {
+			return
+		}
+	}
+}
+
+fn push_str(packet: &mut Vec<u8>, v: &[u8]) {
+	packet.extend_from_slice(&(v.len() as u16).to_be_bytes());
+	packet.extend_from_slice(v);
+}
+
+fn frame(header: u8, body: Vec<u8>) -> Vec<u8> {
+	let mut packet = vec!(header);
+	push_remaining_length(&mut packet, body.len());
+	packet.extend(body);
+
+	packet
+}
+
+fn connect_packet(client_id: &str, username: Option<&str>, password: Option<&str>, will_topic: &str) -> Vec<u8> {
+	// Clean session with a retained QoS 0 "offline" will on the availability topic
+	let mut flags = 0x02 | 0x04 | 0x20;
+
+	if username.is_some() {
+		flags |= 0x80;
+	}
+
+	if password.is_some() {
+		flags |= 0x40;
+	}
+
+	let mut body = vec!();
+	push_str(&mut body, b"MQTT");
+	body.push(4);
+	body.push(flags);
+	body.extend_from_slice(&KEEP_ALIVE_SECS.to_be_bytes());
+	push_str(&mut body, client_id.as_bytes());
+	push_str(&mut body, will_topic.as_bytes());
+	push_str(&mut body, b"offline");
+
+	if let Some(username) = username {
+		push_str(&mut body, username.as_bytes());
+	}
+
+	if let Some(password) = password {
+		push_str(&mut body, password.as_bytes());
+	}
+
+	frame(CONNECT, body)
+}
+
+fn subscribe_packet(packet_id: u16, topic: &str) -> Vec<u8> {
+	let mut body = packet_id.to_be_bytes().to_vec();
+	push_str(&mut body, topic.as_bytes());
+	body.push(0);
+
+	frame(SUBSCRIBE, body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+	let mut body = vec!();
+	push_str(&mut body, topic.as_bytes());
+	body.extend_from_slice(payload);
+
+	frame(PUBLISH | retain as u8, body)
+}
+
+fn read_packet<R: Read>(stream: &mut R) -> Result<(u8, Vec<u8>)> {
+	let mut byte = [0u8];
+	stream.read_exact(&mut byte)?;
+	let header = byte[0];
+
+	let mut len = 0usize;
+	let mut shift = 0;
+	loop {
+		stream.read_exact(&mut byte)?;
+		len |= ((byte[0] & 0x7F) as usize) << shift;
+
+		if byte[0] & 0x80 == 0 {
+			break
+		}
+
+		shift += 7;
+		if shift > 21 {
+			return Err(Error::new(ErrorKind::InvalidData, "Malformed MQTT remaining length"))
+		}
+	}
+
+	let mut body = vec![0u8; len];
+	stream.read_exact(&mut body)?;
+
+	Ok((header, body))
+}
+
+fn parse_publish(header: u8, body: &[u8]) -> Option<(String, Vec<u8>)> {
+	if body.len() < 2 {
+		return None
+	}
+
+	let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+	let topic = body.get(2..2 + topic_len)?;
+	let topic = String::from_utf8(topic.to_vec()).ok()?;
+
+	// QoS 1 and 2 publishes carry a packet identifier after the topic
+	let offset = if header & 0x06 != 0 { 4 } else { 2 };
+
+	Some((topic, body.get(offset + topic_len..)?.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::status::Mode;
+	use std::net::TcpListener;
+
+	fn config(port: u16) -> MqttConfig {
+		MqttConfig {
+			host: "127.0.0.1".to_string(),
+			port: Some(port),
+			client_id: Some("twd_test".to_string()),
+			username: Some("user".to_string()),
+			password: None,
+			topic_prefix: Some("twd/test".to_string()),
+			discovery_prefix: None,
+			discovery: None
+		}
+	}
+
+	fn snapshot() -> Snapshot {
+		Snapshot {
+			timestamp: 0,
+			controls: vec!(ControlStatus {
+				name: "Exhaust Temp".to_string(),
+				label: "Exhaust Temp(0)".to_string(),
+				temp: Some(38.0),
+				setpoint: 40.0,
+				setpoint_override: false,
+				failsafe: 60.0,
+				p: 0.0,
+				i: 0.0,
+				d: 0.0,
+				ff: 0.0,
+				v: 0.0,
+				contribution: 0.0
+			}),
+			winner: None,
+			schedule: None,
+			fans: vec!(),
+			duty: Some(0.2),
+			mode: Mode::Pid,
+			shadow: false,
+			override_remaining: None,
+			last_error: None,
+			fan_reasserts: 0,
+			budget: None
+		}
+	}
+
+	#[test]
+	fn parses_commands() {
+		assert_eq!(parse_command(b"auto"), Ok(Command::Resume));
+		assert_eq!(parse_command(b" Resume\n"), Ok(Command::Resume));
+		assert_eq!(parse_command(b"25"), Ok(Command::SetDuty { duty: 0.25, minutes: None }));
+		assert!(parse_command(b"101").is_err());
+		assert!(parse_command(b"fast").is_err());
+	}
+
+	#[test]
+	fn encodes_remaining_length() {
+		let mut packet = vec!();
+		push_remaining_length(&mut packet, 321);
+		assert_eq!(packet, vec!(0xC1, 0x02));
+
+		let packet = publish_packet("a/b", &[0u8; 200], true);
+		let (header, body) = read_packet(&mut &packet[..]).unwrap();
+		assert_eq!(header, PUBLISH | 1);
+		assert_eq!(parse_publish(header, &body), Some(("a/b".to_string(), vec![0u8; 200])));
+	}
+
+	#[test]
+	fn gives_up_on_silent_broker() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let config = config(listener.local_addr().unwrap().port());
+		let topics = Topics::new(&config, "test");
+
+		let start = ::std::time::Instant::now();
+		let result = connect(&config, &topics, Duration::from_millis(200));
+		assert!(matches!(result.map_err(|e| e.kind()), Err(ErrorKind::WouldBlock) | Err(ErrorKind::TimedOut)));
+		assert!(start.elapsed() < Duration::from_secs(5));
+	}
+
+	#[test]
+	fn publishes_and_receives_commands_through_broker() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+
+		let (snapshot_send, snapshot_recv) = mpsc::sync_channel(4);
+		let (command_send, command_recv) = mpsc::channel();
+		init_mqtt_thread(config(port), snapshot_recv, command_send);
+
+		let (mut broker, _) = listener.accept().unwrap();
+		broker.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+		let (header, body) = read_packet(&mut broker).unwrap();
+		assert_eq!(header, CONNECT);
+		assert_eq!(&body[0..7], b"\x00\x04MQTT\x04");
+		assert_eq!(body[7], 0x02 | 0x04 | 0x20 | 0x80);
+		broker.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+		let (header, body) = read_packet(&mut broker).unwrap();
+		assert_eq!(header, SUBSCRIBE);
+		assert_eq!(&body[4..body.len() - 1], b"twd/test/command");
+		broker.write_all(&[SUBACK, 3, 0, 1, 0]).unwrap();
+
+		snapshot_send.send(snapshot()).unwrap();
+
+		let mut published = vec!();
+		while !published.iter().any(|(t,_): &(String,Vec<u8>)| t == "twd/test/control/exhaust_temp_0/temperature") {
+			let (header, body) = read_packet(&mut broker).unwrap();
+			assert_eq!(header & 0xF0, PUBLISH);
+			published.push(parse_publish(header, &body).unwrap());
+		}
+
+		let find = |topic: &str| published.iter().find(|(t,_)| t == topic).map(|(_,p)| String::from_utf8(p.clone()).unwrap());
+		assert_eq!(find("twd/test/availability"), Some("online".to_string()));
+		assert_eq!(find("twd/test/mode"), Some("pid".to_string()));
+		assert_eq!(find("twd/test/failsafe"), Some("OFF".to_string()));
+		assert_eq!(find("twd/test/fan_duty"), Some("20.0".to_string()));
+
+		let discovery = find("homeassistant/sensor/twd_test/exhaust_temp_0_temp/config").unwrap();
+		let discovery: serde_json::Value = serde_json::from_str(&discovery).unwrap();
+		assert_eq!(discovery["state_topic"], "twd/test/control/exhaust_temp_0/temperature");
+		assert_eq!(discovery["device_class"], "temperature");
+		assert!(find("homeassistant/number/twd_test/fan_override/config").is_some());
+
+		broker.write_all(&publish_packet("twd/test/command", b"35", false)).unwrap();
+		assert_eq!(command_recv.recv_timeout(Duration::from_secs(5)).map(|v: CommandRequest| v.command), Ok(Command::SetDuty { duty: 0.35, minutes: None }));
+
+		broker.write_all(&publish_packet("twd/test/command", b"auto", false)).unwrap();
+		assert_eq!(command_recv.recv_timeout(Duration::from_secs(5)).map(|v| v.command), Ok(Command::Resume));
+	}
+}
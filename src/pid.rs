@@ -1,5 +1,6 @@
 use crate::metrics;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct PID {
 	setpoint: f32,
 	i_acc: f32,
@@ -7,7 +8,8 @@ pub struct PID {
 	i_factor: f32,
 	d_factor: f32,
 	filter_points: usize,
-	d_filter: Vec<(f32,f32)>
+	d_filter: Vec<(f32,f32)>,
+	last: (f32,f32,f32)
 }
 
 impl PID {
@@ -19,10 +21,31 @@ impl PID {
 			i_factor,
 			d_factor,
 			filter_points,
-			d_filter: vec!()
+			d_filter: vec!(),
+			last: (0.0, 0.0, 0.0)
 		}
 	}
 
+	pub fn setpoint(&self) -> f32 {
+		self.setpoint
+	}
+
+	pub fn set_setpoint(&mut self, setpoint: f32) {
+		self.setpoint = setpoint;
+	}
+
+	/// Carries over accumulated state from a controller this one replaces, so retuning doesn't reset it.
+	pub fn inherit(&mut self, previous: &PID) {
+		self.i_acc = previous.i_acc;
+		self.d_filter = previous.d_filter.clone();
+		self.last = previous.last;
+	}
+
+	/// P, I and D terms from the most recent update.
+	pub fn terms(&self) -> (f32,f32,f32) {
+		self.last
+	}
+
 	pub fn update(&mut self, current: f32, elapsed: f32, (metric, metric_sender): (String, &metrics::MetricSender)) -> f32 {
 		let error = current - self.setpoint;
 
@@ -40,6 +63,8 @@ impl PID {
 
 		metrics::report_metric(&[("p".to_string(),p),("i".to_string(),i),("d".to_string(),d),("v".to_string(),p+i+d)], &[("pid".to_string(), metric.clone())], metric_sender);
 
+		self.last = (p, i, d);
+
 		p + i + d
 	}
 }
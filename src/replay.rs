@@ -0,0 +1,256 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Result, Write};
+use std::time::{Duration, Instant};
+
+use crate::control::ControlLoop;
+use crate::ipmi::SdrEntry;
+use crate::metrics;
+use crate::schedule::{Clock, LocalTime, Scheduler, Targets};
+use crate::status::{ControlStatus, Mode};
+
+/// A single pass of the main loop as written by `--record`, one per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frame {
+	/// Milliseconds since the unix epoch.
+	pub timestamp: u64,
+	/// Milliseconds since the previous pass, as passed to the controllers.
+	pub elapsed: f32,
+	/// Local day of the week(0 is Monday), for schedules.
+	pub weekday: u32,
+	/// Local minutes since midnight, for schedules.
+	pub minute: u32,
+	/// CPU utilization from 0.0 to 1.0 for feed-forward terms.
+	pub load: Option<f32>,
+	/// Sensors read from the SDR and sources, None if reading them failed. Virtual sensors are computed on replay.
+	pub sensors: Option<Vec<SdrEntry>>,
+	pub controls: Vec<ControlStatus>,
+	pub duty: Option<f32>,
+	pub mode: Mode
+}
+
+/// Appends a frame for every pass of the main loop to a JSON Lines file.
+pub struct Recorder {
+	out: BufWriter<File>
+}
+
+impl Recorder {
+	/// Replaces any trace already at `path`.
+	pub fn create(path: &str) -> Result<Recorder> {
+		Ok(Recorder {
+			out: BufWriter::new(File::create(path)?)
+		})
+	}
+
+	pub fn record(&mut self, frame: &Frame) -> Result<()> {
+		serde_json::to_writer(&mut self.out, frame)?;
+		self.out.write_all(b"\n")?;
+
+		// Flushed every pass so the trace survives the daemon being killed
+		self.out.flush()
+	}
+}
+
+pub fn read_trace(path: &str) -> ::std::result::Result<Vec<Frame>, String> {
+	let file = File::open(path).map_err(|e| format!("unable to open {}: {}", path, e))?;
+	let lines = BufReader::new(file).lines().collect::<Result<Vec<_>>>().map_err(|e| format!("unable to read {}: {}", path, e))?;
+
+	let mut frames = vec!();
+
+	for (idx, line) in lines.iter().enumerate() {
+		if line.trim().is_empty() {
+			continue
+		}
+
+		match serde_json::from_str(line) {
+			Ok(frame) => frames.push(frame),
+			// The daemon may have been killed part way through writing the last frame
+			Err(e) if idx + 1 == lines.len() => warn!("Ignoring incomplete last frame of {}: {}", path, e),
+			Err(e) => return Err(format!("invalid frame on line {} of {}: {}", idx + 1, path, e))
+		}
+	}
+
+	Ok(frames)
+}
+
+/// Time as recorded in a frame, with instants counted from the start of the replay.
+struct ReplayClock {
+	now: Instant,
+	time: LocalTime
+}
+
+impl Clock for ReplayClock {
+	fn now(&self) -> (Instant, LocalTime) {
+		(self.now, self.time)
+	}
+}
+
+/// Duty limits from the config being replayed, duties are from 0.0 to 1.0.
+pub struct Limits {
+	pub min: f32,
+	pub max: f32,
+	pub acoustic_budget: bool
+}
+
+/// Feeds every frame through `control_loop`, returning the duty it would have commanded for each.
+///
+/// None is returned for frames where fans would have been handed back to the BMC, as well as paused frames.
+pub fn replay(frames: &[Frame], control_loop: &mut ControlLoop, scheduler: &mut Scheduler, limits: &Limits, metrics: &metrics::MetricSender) -> Vec<Option<f32>> {
+	let start = Instant::now();
+	let mut offset = Duration::from_secs(0);
+
+	frames.iter()
+		.enumerate()
+		.map(|(idx, frame)| {
+			offset += Duration::from_secs_f32(frame.elapsed.max(0.0) / 1000.0);
+
+			let clock = ReplayClock {
+				now: start + offset,
+				time: LocalTime { weekday: frame.weekday, minute: frame.minute }
+			};
+
+			let targets = scheduler.update(&clock, &Targets {
+				setpoints: control_loop.setpoints(),
+				min: limits.min,
+				max: limits.max
+			});
+			control_loop.schedule_setpoints(&targets.setpoints);
+
+			let sensors = frame.sensors.clone()?;
+
+			if frame.mode == Mode::Paused {
+				control_loop.load(sensors);
+				return None
+			}
+
+			match control_loop.step_from(sensors, frame.elapsed, frame.load, metrics) {
+				Ok(control) => {
+					let emergency = limits.acoustic_budget && control_loop.emergency().is_some();
+					Some(targets.duty(control, emergency))
+				},
+				Err(e) => {
+					debug!("Frame {} would hand fans to the BMC: {}", idx + 1, e);
+					None
+				}
+			}
+		})
+		.collect()
+}
+
+/// Frame that was driven by the controllers in the recording and commanded a different duty on replay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+	/// Index into the trace.
+	pub frame: usize,
+	pub recorded: Option<f32>,
+	pub replayed: Option<f32>
+}
+
+impl Difference {
+	/// Absolute difference in duty, 1.0 when only one side handed fans to the BMC.
+	pub fn delta(&self) -> f32 {
+		match (self.recorded, self.replayed) {
+			(Some(recorded), Some(replayed)) => (replayed - recorded).abs(),
+			(None, None) => 0.0,
+			_ => 1.0
+		}
+	}
+}
+
+/// Compares replayed duties with recorded frames under PID or BMC control, overridden and paused frames are skipped.
+///
+/// Returns the number of frames compared and every frame that differs by more than `tolerance`.
+pub fn diff(frames: &[Frame], replayed: &[Option<f32>], tolerance: f32) -> (usize, Vec<Difference>) {
+	let compared = frames.iter()
+		.zip(replayed.iter())
+		.enumerate()
+		.filter(|(_, (frame, _))| frame.mode == Mode::Pid || frame.mode == Mode::Bmc)
+		.map(|(idx, (frame, replayed))| Difference {
+			frame: idx,
+			recorded: frame.duty,
+			replayed: *replayed
+		})
+		.collect::<Vec<_>>();
+
+	let count = compared.len();
+
+	(count, compared.into_iter().filter(|v| v.delta() > tolerance).collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::aggregate::Aggregation;
+	use crate::control::ControlSettings;
+	use crate::ipmi::{IPMIValue, SensorSelector, Thresholds};
+	use std::sync::mpsc;
+
+	fn control_loop(setpoint: f32) -> ControlLoop {
+		let mut control_loop = ControlLoop::new(Aggregation::Max);
+		control_loop.add_control(SensorSelector::new("Exhaust Temp", None, None).unwrap(), ControlSettings {
+			setpoint,
+			failsafe: 70.0,
+			tuning: (0.05, 0.0, 0.0),
+			filter_points: 1,
+			thresholds: Thresholds::default(),
+			feed_forward: None,
+			weight: 1.0,
+			emergency: None,
+			ambient: None
+		});
+
+		control_loop
+	}
+
+	fn frame(temp: Option<i32>, duty: Option<f32>, mode: Mode) -> Frame {
+		Frame {
+			timestamp: 0,
+			elapsed: 1000.0,
+			weekday: 0,
+			minute: 0,
+			load: None,
+			sensors: temp.map(|temp| vec!(SdrEntry {
+				name: "Exhaust Temp".to_string(),
+				number: None,
+				entity: None,
+				value: IPMIValue::Temp(temp)
+			})),
+			controls: vec!(),
+			duty,
+			mode
+		}
+	}
+
+	#[test]
+	fn replays_recorded_trace() {
+		let path = ::std::env::temp_dir().join(format!("twd-trace-{}.jsonl", ::std::process::id()));
+		let path = path.to_str().unwrap();
+
+		let mut recorder = Recorder::create(path).unwrap();
+		for frame in [frame(Some(45), Some(0.25), Mode::Pid), frame(Some(50), Some(0.5), Mode::Pid), frame(None, None, Mode::Bmc), frame(Some(50), Some(0.8), Mode::Override)].iter() {
+			recorder.record(frame).unwrap();
+		}
+
+		let frames = read_trace(path).unwrap();
+		::std::fs::remove_file(path).unwrap_or(());
+		assert_eq!(frames.len(), 4);
+		assert_eq!(frames[1].sensors.as_ref().unwrap()[0].value, IPMIValue::Temp(50));
+
+		let (metrics, _recv) = mpsc::channel();
+		let limits = Limits { min: 0.2, max: 1.0, acoustic_budget: false };
+		let mut scheduler = Scheduler::new(vec!(), Duration::from_secs(0));
+
+		// The recorded setpoint reproduces the trace, a lower one drives fans harder
+		let replayed = replay(&frames, &mut control_loop(40.0), &mut scheduler, &limits, &metrics);
+		assert_eq!(replayed.len(), 4);
+		assert!((replayed[1].unwrap() - 0.5).abs() < 1e-6);
+		assert_eq!(replayed[2], None);
+
+		let (compared, differences) = diff(&frames, &replayed, 0.01);
+		assert_eq!(compared, 3);
+		assert_eq!(differences, vec!());
+
+		let replayed = replay(&frames, &mut control_loop(30.0), &mut scheduler, &limits, &metrics);
+		let (_, differences) = diff(&frames, &replayed, 0.01);
+		assert_eq!(differences.iter().map(|v| v.frame).collect::<Vec<_>>(), vec!(0, 1));
+	}
+}
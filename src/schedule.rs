@@ -0,0 +1,305 @@
+use std::time::{Duration, Instant};
+
+/// Day of the week(0 is Monday) and minutes since local midnight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocalTime {
+	pub weekday: u32,
+	pub minute: u32
+}
+
+pub trait Clock {
+	fn now(&self) -> (Instant, LocalTime);
+}
+
+/// Wall clock in the system's local timezone.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> (Instant, LocalTime) {
+		let now = Instant::now();
+
+		let mut tm: libc::tm = unsafe { ::std::mem::zeroed() };
+		unsafe {
+			let time = libc::time(::std::ptr::null_mut());
+			libc::localtime_r(&time, &mut tm);
+		}
+
+		(now, LocalTime {
+			// tm_wday counts from Sunday
+			weekday: ((tm.tm_wday + 6) % 7) as u32,
+			minute: (tm.tm_hour * 60 + tm.tm_min) as u32
+		})
+	}
+}
+
+pub const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+pub fn parse_day(day: &str) -> Result<u32, String> {
+	DAYS.iter()
+		.position(|v| v.eq_ignore_ascii_case(day))
+		.map(|v| v as u32)
+		.ok_or_else(|| format!("unknown day \"{}\", expected one of {}", day, DAYS.join(", ")))
+}
+
+/// Parses "HH:MM" into minutes since midnight.
+pub fn parse_time(time: &str) -> Result<u32, String> {
+	let mut parts = time.splitn(2, ':');
+	let hour = parts.next().and_then(|v| v.parse::<u32>().ok());
+	let minute = parts.next().and_then(|v| v.parse::<u32>().ok());
+
+	match (hour, minute) {
+		(Some(hour), Some(minute)) if hour < 24 && minute < 60 => Ok(hour * 60 + minute),
+		_ => Err(format!("invalid time \"{}\", expected HH:MM", time))
+	}
+}
+
+/// Values a schedule can change, duties are from 0.0 to 1.0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Targets {
+	/// Setpoint for each control, by label.
+	pub setpoints: Vec<(String, f32)>,
+	pub min: f32,
+	pub max: f32
+}
+
+impl Targets {
+	pub fn setpoint(&self, label: &str) -> Option<f32> {
+		self.setpoints.iter().find(|(v, _)| v == label).map(|(_, v)| *v)
+	}
+
+	/// Limits a control output to `min`..`max`, only raising it to `min` when `uncapped`.
+	pub fn duty(&self, control: f32, uncapped: bool) -> f32 {
+		let cap = if uncapped {
+			1.0
+		} else {
+			self.max
+		};
+
+		control.max(self.min).min(cap)
+	}
+
+	fn lerp(&self, to: &Targets, progress: f32) -> Targets {
+		let mix = |from: f32, to: f32| from + (to - from) * progress;
+
+		Targets {
+			setpoints: to.setpoints.iter()
+				.map(|(label, to)| (label.clone(), self.setpoint(label).map(|from| mix(from, *to)).unwrap_or(*to)))
+				.collect(),
+			min: mix(self.min, to.min),
+			max: mix(self.max, to.max)
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Period {
+	pub name: String,
+	/// Days the period starts on, every day if empty.
+	pub days: Vec<u32>,
+	/// Minutes since midnight, a period ending before it starts runs past midnight into the next day.
+	pub start: u32,
+	pub end: u32,
+	pub setpoints: Vec<(String, f32)>,
+	pub min: Option<f32>,
+	pub max: Option<f32>
+}
+
+impl Period {
+	fn on(&self, weekday: u32) -> bool {
+		self.days.is_empty() || self.days.contains(&weekday)
+	}
+
+	pub fn active(&self, time: LocalTime) -> bool {
+		if self.start == self.end {
+			self.on(time.weekday)
+		} else if self.start < self.end {
+			self.on(time.weekday) && time.minute >= self.start && time.minute < self.end
+		} else {
+			(self.on(time.weekday) && time.minute >= self.start) || (self.on((time.weekday + 6) % 7) && time.minute < self.end)
+		}
+	}
+
+	fn apply(&self, base: &Targets) -> Targets {
+		Targets {
+			setpoints: base.setpoints.iter()
+				.map(|(label, setpoint)| {
+					let scheduled = self.setpoints.iter().find(|(v, _)| v == label).map(|(_, v)| *v);
+					(label.clone(), scheduled.unwrap_or(*setpoint))
+				})
+				.collect(),
+			min: self.min.unwrap_or(base.min),
+			max: self.max.unwrap_or(base.max)
+		}
+	}
+}
+
+/// Picks the first active period and ramps between targets whenever the active period changes.
+pub struct Scheduler {
+	periods: Vec<Period>,
+	ramp: Duration,
+	active: Option<String>,
+	/// Targets and time the current ramp started from.
+	ramp_from: Option<(Targets, Instant)>,
+	last: Option<Targets>,
+	/// Set when periods change so the next update ramps to the new targets.
+	restart: bool,
+	progress: f32
+}
+
+impl Scheduler {
+	pub fn new(periods: Vec<Period>, ramp: Duration) -> Scheduler {
+		Scheduler {
+			periods,
+			ramp,
+			active: None,
+			ramp_from: None,
+			last: None,
+			restart: false,
+			progress: 1.0
+		}
+	}
+
+	/// Swaps in new periods, ramping from wherever the current targets are.
+	pub fn reconfigure(&mut self, periods: Vec<Period>, ramp: Duration) {
+		self.periods = periods;
+		self.ramp = ramp;
+		self.restart = true;
+	}
+
+	/// Name of the active period, None while running the configured defaults.
+	pub fn active(&self) -> Option<&str> {
+		self.active.as_deref()
+	}
+
+	/// Progress of the ramp into the active period from 0.0 to 1.0.
+	pub fn progress(&self) -> f32 {
+		self.progress
+	}
+
+	/// Targets for now, `base` holds the configured values used outside of any period.
+	pub fn update(&mut self, clock: &dyn Clock, base: &Targets) -> Targets {
+		let (now, time) = clock.now();
+
+		let period = self.periods.iter().find(|v| v.active(time));
+		let target = period.map(|v| v.apply(base)).unwrap_or_else(|| base.clone());
+		let name = period.map(|v| v.name.clone());
+
+		if name != self.active || self.restart {
+			if name != self.active {
+				info!("Switching to {} schedule", name.as_deref().unwrap_or("default"));
+			}
+
+			self.active = name;
+			self.restart = false;
+			self.ramp_from = self.last.take().map(|v| (v, now));
+		}
+
+		let (targets, progress) = match self.ramp_from.as_ref() {
+			Some((from, since)) if !self.ramp.is_zero() => {
+				let progress = (now.duration_since(*since).as_secs_f32() / self.ramp.as_secs_f32()).min(1.0);
+				(from.lerp(&target, progress), progress)
+			},
+			_ => (target, 1.0)
+		};
+
+		if progress >= 1.0 {
+			self.ramp_from = None;
+		}
+
+		self.progress = progress;
+		self.last = Some(targets.clone());
+
+		targets
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::Cell;
+
+	struct FakeClock {
+		start: Instant,
+		secs: Cell<u64>,
+		time: Cell<LocalTime>
+	}
+
+	impl FakeClock {
+		fn set(&self, secs: u64, weekday: u32, minute: u32) {
+			self.secs.set(secs);
+			self.time.set(LocalTime { weekday, minute });
+		}
+	}
+
+	impl Clock for FakeClock {
+		fn now(&self) -> (Instant, LocalTime) {
+			(self.start + Duration::from_secs(self.secs.get()), self.time.get())
+		}
+	}
+
+	fn quiet() -> Period {
+		Period {
+			name: "quiet".to_string(),
+			days: vec!(parse_day("fri").unwrap(), parse_day("sat").unwrap()),
+			start: parse_time("22:00").unwrap(),
+			end: parse_time("07:30").unwrap(),
+			setpoints: vec!(("Exhaust Temp".to_string(), 50.0)),
+			min: Some(0.0),
+			max: Some(0.6)
+		}
+	}
+
+	#[test]
+	fn matches_periods() {
+		let period = quiet();
+		let at = |weekday, time| LocalTime { weekday, minute: parse_time(time).unwrap() };
+
+		assert!(period.active(at(4, "22:00")));
+		// Runs past midnight into the following morning
+		assert!(period.active(at(5, "07:29")));
+		assert!(!period.active(at(5, "07:30")));
+		assert!(period.active(at(5, "23:59")));
+		assert!(period.active(at(6, "03:00")));
+		assert!(!period.active(at(6, "22:00")));
+		assert!(!period.active(at(0, "03:00")));
+		assert!(!period.active(at(3, "23:00")));
+
+		assert!(parse_time("24:00").is_err());
+		assert!(parse_day("someday").is_err());
+	}
+
+	#[test]
+	fn ramps_between_periods() {
+		let clock = FakeClock { start: Instant::now(), secs: Cell::new(0), time: Cell::new(LocalTime { weekday: 4, minute: 0 }) };
+		let base = Targets { setpoints: vec!(("Exhaust Temp".to_string(), 40.0), ("Temp 3.1".to_string(), 55.0)), min: 0.2, max: 1.0 };
+		let mut scheduler = Scheduler::new(vec!(quiet()), Duration::from_secs(600));
+
+		clock.set(0, 4, parse_time("21:59").unwrap());
+		assert_eq!(scheduler.update(&clock, &base), base);
+		assert_eq!(scheduler.active(), None);
+
+		clock.set(60, 4, parse_time("22:00").unwrap());
+		assert_eq!(scheduler.update(&clock, &base), base);
+		assert_eq!(scheduler.active(), Some("quiet"));
+
+		// Halfway through the ramp
+		clock.set(360, 4, parse_time("22:05").unwrap());
+		let halfway = scheduler.update(&clock, &base);
+		assert_eq!(halfway.setpoint("Exhaust Temp"), Some(45.0));
+		assert_eq!(halfway.setpoint("Temp 3.1"), Some(55.0));
+		assert!((halfway.min - 0.1).abs() < 1e-6 && (halfway.max - 0.8).abs() < 1e-6);
+		assert_eq!(scheduler.progress(), 0.5);
+
+		clock.set(660, 4, parse_time("22:10").unwrap());
+		let quiet = scheduler.update(&clock, &base);
+		assert_eq!((quiet.setpoint("Exhaust Temp"), quiet.min, quiet.max), (Some(50.0), 0.0, 0.6));
+
+		// Leaving the period ramps back the other way
+		clock.set(1000, 5, parse_time("07:30").unwrap());
+		assert_eq!(scheduler.update(&clock, &base), quiet);
+		clock.set(1300, 5, parse_time("07:35").unwrap());
+		assert_eq!(scheduler.update(&clock, &base).setpoint("Exhaust Temp"), Some(45.0));
+		clock.set(1600, 5, parse_time("07:40").unwrap());
+		assert_eq!(scheduler.update(&clock, &base), base);
+	}
+}
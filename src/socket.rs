@@ -0,0 +1,233 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::status::{Command, CommandRequest, CommandSender, Snapshot};
+
+pub const DEFAULT_PATH: &str = "/run/thermal_watchdog.sock";
+
+/// Commands are applied on the next main loop iteration, which can be held up by a slow BMC.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A single line of JSON sent by a client.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+	Status,
+	Command(Command)
+}
+
+/// A single line of JSON sent in reply to each request.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+	Status(Option<Snapshot>),
+	Ok,
+	Error(String)
+}
+
+/// Binds `path` without a moment where other users could connect, by binding in a directory only root can enter
+/// and moving the socket into place once it is 0600.
+fn bind_private(path: &str) -> Result<UnixListener> {
+	let path = Path::new(path);
+	let dir = path.with_file_name(format!(".{}.{}", path.file_name().and_then(|v| v.to_str()).unwrap_or("socket"), ::std::process::id()));
+
+	// Left behind if an earlier instance with the same pid was killed while binding
+	::std::fs::remove_file(dir.join("socket")).unwrap_or(());
+	::std::fs::remove_dir(&dir).unwrap_or(());
+
+	::std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+	let staged = dir.join("socket");
+	let result = UnixListener::bind(&staged)
+		.and_then(|listener| {
+			::std::fs::set_permissions(&staged, ::std::fs::Permissions::from_mode(0o600))?;
+			::std::fs::rename(&staged, path)?;
+			Ok(listener)
+		});
+
+	::std::fs::remove_file(&staged).unwrap_or(());
+	::std::fs::remove_dir(&dir).unwrap_or(());
+
+	result
+}
+
+/// Binds the control socket and serves requests from a background thread.
+pub fn init_socket_thread(path: &str, snapshots: mpsc::Receiver<Snapshot>, commands: CommandSender) -> Result<()> {
+	// A previous instance that was killed can leave the socket behind, anything else at the path is left alone
+	match ::std::fs::symlink_metadata(path) {
+		Ok(meta) if meta.file_type().is_socket() => {
+			if UnixStream::connect(path).is_ok() {
+				return Err(Error::new(ErrorKind::AddrInUse, "another thermal_watchdog is listening on it"))
+			}
+
+			debug!("Removing stale control socket {}", path);
+			::std::fs::remove_file(path)?;
+		},
+		Ok(_) => return Err(Error::new(ErrorKind::AlreadyExists, "it exists and is not a socket")),
+		Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+		Err(e) => return Err(e)
+	}
+
+	let listener = bind_private(path)?;
+
+	let latest = Arc::new(Mutex::new(None));
+
+	{
+		let latest = latest.clone();
+		thread::spawn(move || {
+			for snapshot in snapshots.iter() {
+				*latest.lock().unwrap() = Some(snapshot);
+			}
+		});
+	}
+
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			match stream {
+				Ok(stream) => {
+					let latest = latest.clone();
+					let commands = commands.clone();
+
+					thread::spawn(move || {
+						if let Err(e) = serve_client(stream, &latest, &commands) {
+							debug!("Control socket client error: {}", e);
+						}
+					});
+				},
+				Err(e) => error!("Control socket accept failed: {}", e)
+			}
+		}
+	});
+
+	Ok(())
+}
+
+fn serve_client(stream: UnixStream, latest: &Mutex<Option<Snapshot>>, commands: &CommandSender) -> Result<()> {
+	let mut writer = stream.try_clone()?;
+
+	for line in BufReader::new(stream).lines() {
+		let line = line?;
+
+		if line.trim().is_empty() {
+			continue
+		}
+
+		let response = match serde_json::from_str::<Request>(&line) {
+			Ok(Request::Status) => Response::Status(latest.lock().unwrap().clone()),
+			Ok(Request::Command(command)) => {
+				info!("Control socket command: {:?}", command);
+				send_command(command, commands)
+			},
+			Err(e) => Response::Error(format!("invalid request: {}", e))
+		};
+
+		let mut encoded = serde_json::to_string(&response)
+			.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+		encoded.push('\n');
+		writer.write_all(encoded.as_bytes())?;
+	}
+
+	Ok(())
+}
+
+fn send_command(command: Command, commands: &CommandSender) -> Response {
+	let (reply, result) = mpsc::channel();
+
+	if commands.send(CommandRequest { command, reply: Some(reply) }).is_err() {
+		return Response::Error("control loop is not running".to_string())
+	}
+
+	match result.recv_timeout(REPLY_TIMEOUT) {
+		Ok(Ok(())) => Response::Ok,
+		Ok(Err(e)) => Response::Error(e),
+		Err(_) => Response::Error("timed out waiting for the control loop".to_string())
+	}
+}
+
+/// Sends a single request to a running daemon and waits for the reply.
+pub fn request(path: &str, request: &Request) -> Result<Response> {
+	let mut stream = UnixStream::connect(path)
+		.map_err(|e| Error::new(e.kind(), format!("Unable to connect to {}, is thermal_watchdog running? {}", path, e)))?;
+
+	let mut encoded = serde_json::to_string(request)
+		.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+	encoded.push('\n');
+	stream.write_all(encoded.as_bytes())?;
+
+	let mut line = String::new();
+	BufReader::new(stream).read_line(&mut line)?;
+
+	serde_json::from_str(&line)
+		.map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid response from daemon: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn path(name: &str) -> String {
+		let path = ::std::env::temp_dir().join(format!("twd-{}-{}.sock", name, ::std::process::id()));
+		::std::fs::remove_file(&path).unwrap_or(());
+		path.to_str().unwrap().to_string()
+	}
+
+	#[test]
+	fn serves_requests() {
+		let path = path("serve");
+		let (snapshots_send, snapshots) = mpsc::channel();
+		let (commands, requests) = mpsc::channel::<CommandRequest>();
+
+		init_socket_thread(&path, snapshots, commands).unwrap();
+		assert_eq!(::std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		let parent = ::std::fs::read_dir(::std::env::temp_dir()).unwrap();
+		assert!(!parent.filter_map(|v| v.ok()).any(|v| v.file_name().to_string_lossy().starts_with(&format!(".twd-serve-{}.sock.", ::std::process::id()))));
+
+		thread::spawn(move || {
+			for request in requests.iter() {
+				let result = match request.command {
+					Command::Resume => Ok(()),
+					_ => Err("rejected".to_string())
+				};
+				request.reply.unwrap().send(result).unwrap();
+			}
+		});
+
+		assert!(matches!(request(&path, &Request::Status).unwrap(), Response::Status(None)));
+		assert!(matches!(request(&path, &Request::Command(Command::Resume)).unwrap(), Response::Ok));
+		assert!(matches!(request(&path, &Request::Command(Command::Pause { minutes: None })).unwrap(), Response::Error(ref e) if e == "rejected"));
+
+		// A running instance keeps its socket
+		let (_send, snapshots) = mpsc::channel();
+		let (commands, _requests) = mpsc::channel();
+		assert_eq!(init_socket_thread(&path, snapshots, commands).map_err(|e| e.kind()), Err(ErrorKind::AddrInUse));
+		assert!(matches!(request(&path, &Request::Status).unwrap(), Response::Status(None)));
+
+		drop(snapshots_send);
+		::std::fs::remove_file(&path).unwrap_or(());
+	}
+
+	#[test]
+	fn replaces_only_stale_sockets() {
+		let path = path("stale");
+
+		// Left behind by an instance that was killed
+		drop(UnixListener::bind(&path).unwrap());
+		let (_send, snapshots) = mpsc::channel();
+		let (commands, _requests) = mpsc::channel();
+		init_socket_thread(&path, snapshots, commands).unwrap();
+		assert!(UnixStream::connect(&path).is_ok());
+		::std::fs::remove_file(&path).unwrap();
+
+		::std::fs::write(&path, "not a socket").unwrap();
+		let (_send, snapshots) = mpsc::channel();
+		let (commands, _requests) = mpsc::channel();
+		assert_eq!(init_socket_thread(&path, snapshots, commands).map_err(|e| e.kind()), Err(ErrorKind::AlreadyExists));
+		assert_eq!(::std::fs::read_to_string(&path).unwrap(), "not a socket");
+		::std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,188 @@
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Who currently owns the fans.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+	/// BMC automatic fan control, either before startup or after an error.
+	Bmc,
+	/// Fans are driven by the PID controllers.
+	Pid,
+	/// Fans are held at a fixed duty requested through a command interface.
+	Override,
+	/// Control has been handed back to the BMC on request.
+	Paused
+}
+
+impl Mode {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Mode::Bmc => "bmc",
+			Mode::Pid => "pid",
+			Mode::Override => "override",
+			Mode::Paused => "paused"
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlStatus {
+	pub name: String,
+	pub label: String,
+	pub temp: Option<f32>,
+	pub setpoint: f32,
+	/// True while `setpoint` is a temporary override rather than the configured value.
+	pub setpoint_override: bool,
+	pub failsafe: f32,
+	pub p: f32,
+	pub i: f32,
+	pub d: f32,
+	/// Feed-forward term from CPU load, 0.0 if the control has none.
+	pub ff: f32,
+	pub v: f32,
+	/// Share of the fan duty from this control, depending on how outputs are aggregated.
+	pub contribution: f32
+}
+
+impl ControlStatus {
+	pub fn tripped(&self) -> bool {
+		self.temp.map(|v| v >= self.failsafe).unwrap_or(false)
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FanStatus {
+	pub name: String,
+	pub rpm: u32,
+	/// RPM expected at the commanded duty from calibration, None if uncalibrated or not under manual control.
+	pub expected: Option<f32>,
+	/// How closely the fan matches its calibration, from 0.0 to 1.0.
+	pub health: Option<f32>
+}
+
+/// How often fans ran above the duty cap in acoustic budget mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BudgetStatus {
+	/// True while a control past its emergency threshold holds fans above the cap.
+	pub exceeded: bool,
+	/// Times the cap was exceeded since startup.
+	pub count: u64,
+	/// Seconds spent above the cap since startup.
+	pub exceeded_secs: u64,
+	/// Label of the control that most recently pushed fans above the cap.
+	pub cause: Option<String>
+}
+
+/// State of the daemon after a single pass of the main loop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+	/// Milliseconds since the unix epoch.
+	pub timestamp: u64,
+	pub controls: Vec<ControlStatus>,
+	/// Label of the control driving the fans, None if none are pushing above the minimum duty.
+	pub winner: Option<String>,
+	/// Name of the active schedule period, None outside of any period.
+	pub schedule: Option<String>,
+	pub fans: Vec<FanStatus>,
+	pub duty: Option<f32>,
+	pub mode: Mode,
+	pub shadow: bool,
+	/// Seconds remaining on a duty override or pause, None if it is held until resumed.
+	pub override_remaining: Option<u64>,
+	/// Control or IPMI error of the last pass, None once a pass succeeds again.
+	pub last_error: Option<String>,
+	/// Times manual fan control was re-asserted after fans stopped following the commanded duty.
+	pub fan_reasserts: u64,
+	/// None unless acoustic budget mode is enabled.
+	pub budget: Option<BudgetStatus>
+}
+
+impl Snapshot {
+	pub fn failsafe(&self) -> bool {
+		self.controls.iter().any(|v| v.tripped())
+	}
+}
+
+pub fn now_ms() -> u64 {
+	let since_epoch = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default();
+
+	since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+}
+
+/// Requests from external interfaces, drained by the main loop once per iteration.
+///
+/// `minutes` limits how long an override lasts, if omitted it is held until a `Resume`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+	/// Hold the fans at a fixed duty(0.0-1.0) instead of the PID output.
+	SetDuty { duty: f32, minutes: Option<f32> },
+	/// Hand fan control back to the BMC.
+	Pause { minutes: Option<f32> },
+	/// Temporarily replace the setpoint of a single control, identified by label.
+	SetSetpoint { control: String, setpoint: f32, minutes: Option<f32> },
+	/// Drop every override and return to PID control.
+	Resume
+}
+
+impl Command {
+	pub fn duration(minutes: Option<f32>) -> Option<Duration> {
+		minutes.map(|v| Duration::from_millis((v.max(0.0) * 60_000.0) as u64))
+	}
+}
+
+pub type CommandReply = mpsc::Sender<Result<(), String>>;
+
+pub struct CommandRequest {
+	pub command: Command,
+	/// Receives the outcome once the main loop has applied the command.
+	pub reply: Option<CommandReply>
+}
+
+pub type CommandSender = mpsc::Sender<CommandRequest>;
+
+/// Replaces the running config with `config`(TOML), applied like a reload and saved over the config file.
+pub struct ConfigRequest {
+	pub config: String,
+	pub reply: CommandReply
+}
+
+pub type ConfigSender = mpsc::Sender<ConfigRequest>;
+
+/// Fans snapshots out to every subscribed interface without ever blocking the main loop.
+///
+/// Each subscriber has a bounded queue, if a subscriber falls behind new snapshots are dropped for it.
+pub struct Publisher {
+	subscribers: Vec<mpsc::SyncSender<Snapshot>>
+}
+
+impl Publisher {
+	pub fn new() -> Publisher {
+		Publisher {
+			subscribers: vec!()
+		}
+	}
+
+	pub fn subscribe(&mut self, depth: usize) -> mpsc::Receiver<Snapshot> {
+		let (send, recv) = mpsc::sync_channel(depth);
+		self.subscribers.push(send);
+
+		recv
+	}
+
+	pub fn publish(&mut self, snapshot: &Snapshot) {
+		self.subscribers.retain(|subscriber| {
+			match subscriber.try_send(snapshot.clone()) {
+				Ok(_) => true,
+				Err(mpsc::TrySendError::Full(_)) => {
+					trace!("Subscriber is behind, dropping snapshot");
+					true
+				},
+				Err(mpsc::TrySendError::Disconnected(_)) => false
+			}
+		});
+	}
+}
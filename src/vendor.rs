@@ -0,0 +1,276 @@
+use std::io::{Error, ErrorKind, Result};
+
+use crate::ipmitool::{self, Op};
+
+/// IANA enterprise numbers reported as "Manufacturer ID" by `ipmitool mc info`.
+pub const DELL: u32 = 674;
+pub const SUPERMICRO: u32 = 10876;
+pub const HP: u32 = 11;
+pub const IBM: u32 = 2;
+pub const LENOVO: u32 = 19046;
+
+/// A single byte of a raw command, either fixed or replaced with the commanded duty.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RawByte {
+	Fixed(u8),
+	Duty
+}
+
+pub type RawCommand = Vec<RawByte>;
+
+/// Raw IPMI commands used to drive the fans of a particular vendor's BMC.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanProfile {
+	pub name: String,
+	/// Sent in order to take fan control away from the BMC.
+	pub manual: Vec<RawCommand>,
+	/// Sent in order to hand fan control back to the BMC.
+	pub automatic: Vec<RawCommand>,
+	/// Sent in order to set the duty of every fan.
+	pub speed: Vec<RawCommand>,
+	/// Value sent for 100% duty, `Duty` bytes are scaled from 0 to this.
+	pub duty_max: u8
+}
+
+impl FanProfile {
+	/// Dell 11th/12th gen iDRAC.
+	pub fn dell() -> FanProfile {
+		FanProfile {
+			name: "dell".to_string(),
+			manual: vec!(fixed(&[0x30, 0x30, 0x01, 0x00])),
+			automatic: vec!(fixed(&[0x30, 0x30, 0x01, 0x01])),
+			speed: vec!(with_duty(&[0x30, 0x30, 0x02, 0xff])),
+			duty_max: 100
+		}
+	}
+
+	/// Supermicro X9/X10/X11.
+	///
+	/// "Full" fan mode stops the BMC from changing duty, after which both zones(CPU and peripheral) are
+	/// set directly. Control is handed back by switching to "optimal" mode.
+	pub fn supermicro() -> FanProfile {
+		FanProfile {
+			name: "supermicro".to_string(),
+			manual: vec!(fixed(&[0x30, 0x45, 0x01, 0x01])),
+			automatic: vec!(fixed(&[0x30, 0x45, 0x01, 0x02])),
+			speed: vec!(
+				with_duty(&[0x30, 0x70, 0x66, 0x01, 0x00]),
+				with_duty(&[0x30, 0x70, 0x66, 0x01, 0x01])
+			),
+			duty_max: 100
+		}
+	}
+
+	pub fn builtin(name: &str) -> Option<FanProfile> {
+		match name {
+			"dell" => Some(FanProfile::dell()),
+			"supermicro" => Some(FanProfile::supermicro()),
+			_ => None
+		}
+	}
+
+	/// Profile for a BMC identified by its "Manufacturer ID".
+	pub fn for_manufacturer(id: u32) -> ::std::result::Result<FanProfile, String> {
+		match id {
+			DELL => Ok(FanProfile::dell()),
+			SUPERMICRO => Ok(FanProfile::supermicro()),
+			HP | IBM | LENOVO => Err(format!("{} BMCs(manufacturer id {}) have no built-in fan profile, their fan control isn't exposed through documented raw commands. Define custom raw commands in [fans] if your model supports them", manufacturer_name(id).unwrap_or("Unknown"), id)),
+			_ => Err(format!("No built-in fan profile for manufacturer id {}, set profile in [fans] or define custom raw commands", id))
+		}
+	}
+
+	/// Profile from user-defined raw commands, each a list of bytes like "0x30 0x30 0x02 0xff {duty}".
+	pub fn custom(manual: &[String], automatic: &[String], speed: &[String], duty_max: u8) -> ::std::result::Result<FanProfile, String> {
+		let parse_all = |commands: &[String]| commands.iter().map(|v| parse_raw(v)).collect::<::std::result::Result<Vec<_>, _>>();
+
+		let profile = FanProfile {
+			name: "custom".to_string(),
+			manual: parse_all(manual)?,
+			automatic: parse_all(automatic)?,
+			speed: parse_all(speed)?,
+			duty_max
+		};
+
+		if profile.manual.is_empty() || profile.automatic.is_empty() || profile.speed.is_empty() {
+			return Err("custom fan profiles need at least one manual, automatic and speed command".to_string())
+		}
+
+		if profile.speed.iter().any(|v| !v.contains(&RawByte::Duty)) {
+			return Err("every custom speed command needs a {duty} byte".to_string())
+		}
+
+		if profile.manual.iter().chain(profile.automatic.iter()).any(|v| v.contains(&RawByte::Duty)) {
+			return Err("{duty} is only valid in speed commands".to_string())
+		}
+
+		Ok(profile)
+	}
+
+	pub fn mode_commands(&self, manual: bool) -> Vec<Vec<u8>> {
+		let commands = if manual {
+			&self.manual
+		} else {
+			&self.automatic
+		};
+
+		commands.iter().map(|v| encode(v, 0)).collect()
+	}
+
+	/// Commands for a duty from 0.0 to 1.0, rounded up so the fans never run slower than asked.
+	pub fn speed_commands(&self, speed: f32) -> Vec<Vec<u8>> {
+		let max = f32::from(self.duty_max);
+		let duty = (speed * max).ceil().clamp(0.0, max) as u8;
+
+		self.speed.iter().map(|v| encode(v, duty)).collect()
+	}
+}
+
+fn fixed(bytes: &[u8]) -> RawCommand {
+	bytes.iter().map(|v| RawByte::Fixed(*v)).collect()
+}
+
+fn with_duty(bytes: &[u8]) -> RawCommand {
+	let mut command = fixed(bytes);
+	command.push(RawByte::Duty);
+
+	command
+}
+
+fn encode(command: &[RawByte], duty: u8) -> Vec<u8> {
+	command.iter()
+		.map(|v| match v {
+			RawByte::Fixed(v) => *v,
+			RawByte::Duty => duty
+		})
+		.collect()
+}
+
+/// Parses bytes separated by whitespace, I.E. "0x30 0x30 0x02 0xff {duty}".
+pub fn parse_raw(command: &str) -> ::std::result::Result<RawCommand, String> {
+	let bytes = command.split_whitespace()
+		.map(|v| {
+			if v == "{duty}" {
+				return Ok(RawByte::Duty)
+			}
+
+			let hex = v.strip_prefix("0x").or_else(|| v.strip_prefix("0X"));
+			hex.and_then(|v| u8::from_str_radix(v, 16).ok())
+				.map(RawByte::Fixed)
+				.ok_or_else(|| format!("invalid byte \"{}\" in raw command \"{}\", expected a value like \"0x30\"", v, command))
+		})
+		.collect::<::std::result::Result<Vec<_>, _>>()?;
+
+	// NetFn and command are the minimum ipmitool raw accepts
+	if bytes.len() < 2 {
+		return Err(format!("raw command \"{}\" needs at least a netfn and command byte", command))
+	}
+
+	Ok(bytes)
+}
+
+/// Formats bytes the way `ipmitool raw` expects them.
+pub fn format_raw(bytes: &[u8]) -> Vec<String> {
+	bytes.iter().map(|v| format!("0x{:02x}", v)).collect()
+}
+
+pub fn manufacturer_name(id: u32) -> Option<&'static str> {
+	match id {
+		DELL => Some("Dell"),
+		SUPERMICRO => Some("Supermicro"),
+		HP => Some("HP"),
+		IBM => Some("IBM"),
+		LENOVO => Some("Lenovo"),
+		_ => None
+	}
+}
+
+fn parse_manufacturer_id(mc_info: &str) -> Option<u32> {
+	mc_info.lines()
+		.filter_map(|v| {
+			let mut parts = v.splitn(2, ':');
+			let key = parts.next()?.trim();
+			let value = parts.next()?.trim();
+
+			if key == "Manufacturer ID" {
+				value.split_whitespace().next()?.parse().ok()
+			} else {
+				None
+			}
+		})
+		.next()
+}
+
+/// Reads the BMC's manufacturer from `ipmitool mc info`.
+pub fn detect_manufacturer() -> Result<u32> {
+	let cmd = ipmitool::run(Op::McInfo, &["mc", "info"])?;
+
+	if !cmd.success {
+		return Err(Error::new(ErrorKind::InvalidData, format!("Reading BMC info failed, {}", cmd.stderr.trim())))
+	}
+
+	parse_manufacturer_id(&cmd.stdout)
+		.ok_or_else(|| Error::new(ErrorKind::InvalidData, "No manufacturer id in \"ipmitool mc info\" output"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn strings(v: &[&str]) -> Vec<String> {
+		v.iter().map(|v| v.to_string()).collect()
+	}
+
+	#[test]
+	fn dell_bytes() {
+		let profile = FanProfile::dell();
+
+		assert_eq!(profile.mode_commands(true), vec!(vec!(0x30, 0x30, 0x01, 0x00)));
+		assert_eq!(profile.mode_commands(false), vec!(vec!(0x30, 0x30, 0x01, 0x01)));
+		assert_eq!(profile.speed_commands(0.0), vec!(vec!(0x30, 0x30, 0x02, 0xff, 0x00)));
+		assert_eq!(profile.speed_commands(0.355), vec!(vec!(0x30, 0x30, 0x02, 0xff, 0x24)));
+		assert_eq!(profile.speed_commands(1.5), vec!(vec!(0x30, 0x30, 0x02, 0xff, 0x64)));
+		assert_eq!(format_raw(&profile.speed_commands(0.2)[0]), strings(&["0x30", "0x30", "0x02", "0xff", "0x14"]));
+	}
+
+	#[test]
+	fn supermicro_bytes() {
+		let profile = FanProfile::supermicro();
+
+		assert_eq!(profile.mode_commands(true), vec!(vec!(0x30, 0x45, 0x01, 0x01)));
+		assert_eq!(profile.mode_commands(false), vec!(vec!(0x30, 0x45, 0x01, 0x02)));
+		assert_eq!(profile.speed_commands(0.5), vec!(
+			vec!(0x30, 0x70, 0x66, 0x01, 0x00, 0x32),
+			vec!(0x30, 0x70, 0x66, 0x01, 0x01, 0x32)
+		));
+	}
+
+	#[test]
+	fn custom_bytes() {
+		let profile = FanProfile::custom(
+			&strings(&["0x30 0x91 0x5a 0x03 0x10 0xff"]),
+			&strings(&["0x30 0x45 0x01 0x00"]),
+			&strings(&["0x30 0x91 0x5A 0x03 0x10 {duty}"]),
+			255).unwrap();
+
+		assert_eq!(profile.mode_commands(true), vec!(vec!(0x30, 0x91, 0x5a, 0x03, 0x10, 0xff)));
+		assert_eq!(profile.speed_commands(0.5), vec!(vec!(0x30, 0x91, 0x5a, 0x03, 0x10, 0x80)));
+
+		assert!(FanProfile::custom(&strings(&["0x30 0x45"]), &strings(&["0x30 0x45"]), &strings(&["0x30 0x70"]), 100).is_err());
+		assert!(FanProfile::custom(&strings(&["0x30 {duty}"]), &strings(&["0x30 0x45"]), &strings(&["0x30 {duty}"]), 100).is_err());
+		assert!(FanProfile::custom(&[], &strings(&["0x30 0x45"]), &strings(&["0x30 {duty}"]), 100).is_err());
+		assert!(parse_raw("0x30 48").is_err());
+		assert!(parse_raw("0x30").is_err());
+	}
+
+	#[test]
+	fn detects_manufacturer() {
+		let mc_info = "Device ID                 : 32\nFirmware Revision         : 1.57\nManufacturer ID           : 674\nManufacturer Name         : DELL Inc\n";
+		assert_eq!(parse_manufacturer_id(mc_info), Some(DELL));
+		assert_eq!(parse_manufacturer_id("Device ID : 32"), None);
+
+		assert_eq!(FanProfile::for_manufacturer(DELL).unwrap().name, "dell");
+		assert_eq!(FanProfile::for_manufacturer(SUPERMICRO).unwrap().name, "supermicro");
+		assert!(FanProfile::for_manufacturer(HP).unwrap_err().contains("HP BMCs"));
+		assert!(FanProfile::for_manufacturer(LENOVO).unwrap_err().contains("Lenovo BMCs"));
+	}
+}
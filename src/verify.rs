@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+
+use crate::calibration::{interpolate, CurvePoint};
+
+/// Change in commanded duty that restarts the settle period.
+const DUTY_CHANGE: f32 = 0.05;
+/// Without a configured RPM range, duty has to move at least this much between checks before the
+/// fans are expected to follow.
+const DIRECTION_DUTY_CHANGE: f32 = 0.15;
+/// Smallest relative RPM change that counts as the fans following a duty change.
+const DIRECTION_RPM_CHANGE: f32 = 0.05;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifySettings {
+	/// Time between checks.
+	pub interval: Duration,
+	/// Time fans get to spin up or down after the duty changes before they are checked.
+	pub settle: Duration,
+	/// Average RPM expected at each duty, if known.
+	pub curve: Option<Vec<CurvePoint>>,
+	/// Allowed distance from the expected RPM, as a fraction of the curve's RPM range.
+	pub tolerance: f32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+	/// Not checked this time, either too soon or there is nothing to compare against.
+	Skipped,
+	Ok,
+	/// Fans aren't doing what was commanded, most likely the BMC took control back.
+	Mismatch(String)
+}
+
+/// Confirms fans actually follow the commanded duty.
+///
+/// With a known RPM curve every check compares the average RPM against the curve. Otherwise
+/// only large duty changes are checked, by confirming RPM moved in the same direction.
+pub struct FanVerifier {
+	settings: VerifySettings,
+	/// Duty and time the current settle period started.
+	settling: Option<(f32, Instant)>,
+	/// Duty and average RPM from the last check that passed.
+	baseline: Option<(f32, f32)>,
+	last_check: Option<Instant>
+}
+
+impl FanVerifier {
+	pub fn new(settings: VerifySettings) -> FanVerifier {
+		FanVerifier {
+			settings,
+			settling: None,
+			baseline: None,
+			last_check: None
+		}
+	}
+
+	pub fn set_settings(&mut self, settings: VerifySettings) {
+		if settings != self.settings {
+			self.settings = settings;
+			self.reset();
+		}
+	}
+
+	/// Forgets everything learned so far, used whenever the BMC had control of the fans.
+	pub fn reset(&mut self) {
+		self.settling = None;
+		self.baseline = None;
+		self.last_check = None;
+	}
+
+	pub fn commanded(&mut self, duty: f32, now: Instant) {
+		match self.settling {
+			Some((settled, _)) if (settled - duty).abs() < DUTY_CHANGE => (),
+			_ => self.settling = Some((duty, now))
+		}
+	}
+
+	/// Average RPM the curve predicts for `duty`.
+	pub fn expected(&self, duty: f32) -> Option<f32> {
+		self.settings.curve.as_ref().and_then(|v| interpolate(v, duty))
+	}
+
+	fn span(&self) -> f32 {
+		let rpms = self.settings.curve.iter().flatten().map(|v| v.rpm);
+		let max = rpms.clone().fold(f32::MIN, f32::max);
+		let min = rpms.fold(f32::MAX, f32::min);
+
+		(max - min).max(0.0)
+	}
+
+	pub fn check(&mut self, duty: f32, rpms: &[u32], now: Instant) -> Verdict {
+		let settled = self.settling
+			.map(|(_, since)| now.duration_since(since) >= self.settings.settle)
+			.unwrap_or(false);
+		let due = self.last_check
+			.map(|v| now.duration_since(v) >= self.settings.interval)
+			.unwrap_or(true);
+
+		// Fans that aren't populated read 0 RPM
+		let spinning = rpms.iter().filter(|v| **v > 0).collect::<Vec<_>>();
+
+		if !settled || !due || spinning.is_empty() {
+			return Verdict::Skipped
+		}
+
+		self.last_check = Some(now);
+
+		let rpm = spinning.iter().map(|v| **v as f32).sum::<f32>() / spinning.len() as f32;
+
+		let verdict = if let Some(expected) = self.expected(duty) {
+			if (rpm - expected).abs() > self.span() * self.settings.tolerance {
+				Verdict::Mismatch(format!("fans average {:.0} RPM, expected ~{:.0} RPM for {:.0}% duty", rpm, expected, duty * 100.0))
+			} else {
+				Verdict::Ok
+			}
+		} else {
+			match self.baseline {
+				Some((base_duty, base_rpm)) if (duty - base_duty).abs() >= DIRECTION_DUTY_CHANGE => {
+					let moved = (rpm - base_rpm) / base_rpm;
+
+					if moved * (duty - base_duty).signum() < DIRECTION_RPM_CHANGE {
+						Verdict::Mismatch(format!("fans went from {:.0} to {:.0} RPM while duty went from {:.0}% to {:.0}%", base_rpm, rpm, base_duty * 100.0, duty * 100.0))
+					} else {
+						Verdict::Ok
+					}
+				},
+				// Too small a change to judge, keep comparing against the older baseline
+				Some(_) => return Verdict::Ok,
+				None => Verdict::Ok
+			}
+		};
+
+		match verdict {
+			Verdict::Ok => self.baseline = Some((duty, rpm)),
+			_ => self.reset()
+		}
+
+		verdict
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn settings(rpm_range: Option<(f32, f32)>) -> VerifySettings {
+		VerifySettings {
+			interval: Duration::from_secs(30),
+			settle: Duration::from_secs(15),
+			curve: rpm_range.map(|(min, max)| vec!(CurvePoint { duty: 0.0, rpm: min }, CurvePoint { duty: 1.0, rpm: max })),
+			tolerance: 0.25
+		}
+	}
+
+	#[test]
+	fn checks_against_rpm_range() {
+		let start = Instant::now();
+		let at = |secs| start + Duration::from_secs(secs);
+		let mut verifier = FanVerifier::new(settings(Some((2000.0, 12000.0))));
+
+		verifier.commanded(0.2, at(0));
+		assert_eq!(verifier.check(0.2, &[4000, 4100], at(5)), Verdict::Skipped);
+		assert_eq!(verifier.check(0.2, &[4000, 4100, 0], at(20)), Verdict::Ok);
+		// Not due again until the interval passes
+		assert_eq!(verifier.check(0.2, &[9000], at(25)), Verdict::Skipped);
+
+		// BMC took over and spun the fans up
+		verifier.commanded(0.22, at(30));
+		match verifier.check(0.22, &[9000, 9100], at(51)) {
+			Verdict::Mismatch(msg) => assert!(msg.contains("expected ~4200 RPM")),
+			v => panic!("unexpected {:?}", v)
+		}
+
+		assert_eq!(verifier.check(0.22, &[], at(100)), Verdict::Skipped);
+	}
+
+	#[test]
+	fn checks_direction_without_range() {
+		let start = Instant::now();
+		let at = |secs| start + Duration::from_secs(secs);
+		let mut verifier = FanVerifier::new(settings(None));
+
+		verifier.commanded(0.2, at(0));
+		assert_eq!(verifier.check(0.2, &[4000], at(20)), Verdict::Ok);
+
+		verifier.commanded(0.5, at(30));
+		assert_eq!(verifier.check(0.5, &[7000], at(50)), Verdict::Ok);
+
+		// Duty dropped but the fans stayed put
+		verifier.commanded(0.2, at(60));
+		assert!(matches!(verifier.check(0.2, &[7050], at(80)), Verdict::Mismatch(_)));
+
+		// The baseline is relearned afterwards
+		verifier.commanded(0.2, at(90));
+		assert_eq!(verifier.check(0.2, &[4000], at(110)), Verdict::Ok);
+	}
+}
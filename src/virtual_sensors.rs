@@ -0,0 +1,114 @@
+use crate::expr::Expr;
+use crate::ipmi::{IPMIValue, SdrEntry, SensorSelector};
+
+/// Temperature computed from other sensors, I.E. exhaust minus inlet for delta-T control.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualSensor {
+	pub name: String,
+	pub expr: Expr,
+	/// Sensor each variable in `expr` reads from.
+	pub inputs: Vec<(String, SensorSelector)>
+}
+
+impl VirtualSensor {
+	/// Value from the entries read so far, Invalid if any input is missing or isn't a temperature.
+	fn value(&self, entries: &[SdrEntry]) -> IPMIValue {
+		let values = self.inputs.iter()
+			.map(|(var, selector)| {
+				let mut matches = entries.iter().filter(|v| selector.matches(v));
+
+				match (matches.next(), matches.next()) {
+					(Some(SdrEntry { value: IPMIValue::Temp(temp), .. }), None) => Ok((var.as_str(), *temp as f32)),
+					(None, _) => Err(format!("{} is not set", selector.label())),
+					(Some(_), None) => Err(format!("{} is not a temperature", selector.label())),
+					(Some(_), Some(_)) => Err(format!("{} matches more than one sensor", selector.label()))
+				}
+			})
+			.collect::<Result<Vec<_>, _>>();
+
+		let result = values.and_then(|values| {
+			let var = |name: &str| values.iter().find(|(v, _)| *v == name).map(|(_, v)| *v);
+			self.expr.eval(&var)
+		});
+
+		match result {
+			// Rounded to whole degrees like the SDR readings it is computed from
+			Ok(value) if value.is_finite() => IPMIValue::Temp(value.round() as i32),
+			Ok(value) => {
+				warn!("{} evaluated to {}", self.name, value);
+				IPMIValue::Invalid
+			},
+			Err(e) => {
+				warn!("Unable to compute {}, {}", self.name, e);
+				IPMIValue::Invalid
+			}
+		}
+	}
+}
+
+/// Entries for every virtual sensor, each able to read sensors defined before it.
+pub fn evaluate(sensors: &[VirtualSensor], entries: &[SdrEntry]) -> Vec<SdrEntry> {
+	let mut all = entries.to_vec();
+
+	for sensor in sensors {
+		let value = sensor.value(&all);
+		trace!("Computed {:?} for {}", value, sensor.name);
+
+		all.push(SdrEntry {
+			name: sensor.name.clone(),
+			number: None,
+			entity: None,
+			value
+		});
+	}
+
+	all.split_off(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(name: &str, entity: Option<(u8, u8)>, value: IPMIValue) -> SdrEntry {
+		SdrEntry { name: name.to_string(), number: None, entity, value }
+	}
+
+	fn sensor(name: &str, expr: &str, inputs: &[(&str, SensorSelector)]) -> VirtualSensor {
+		VirtualSensor {
+			name: name.to_string(),
+			expr: Expr::parse(expr).unwrap(),
+			inputs: inputs.iter().map(|(v, s)| (v.to_string(), s.clone())).collect()
+		}
+	}
+
+	#[test]
+	fn computes_from_sdr() {
+		let entries = vec!(
+			entry("Inlet Temp", Some((7, 1)), IPMIValue::Temp(23)),
+			entry("Exhaust Temp", Some((7, 1)), IPMIValue::Temp(38)),
+			entry("Temp", Some((3, 1)), IPMIValue::Temp(45)),
+			entry("Temp", Some((3, 2)), IPMIValue::Temp(40)),
+			entry("Fan1 RPM", Some((7, 1)), IPMIValue::RPM(4000))
+		);
+		let by_name = |name| SensorSelector::new(name, None, None).unwrap();
+		let cpu = |entity| SensorSelector::new("Temp", None, Some(entity)).unwrap();
+
+		let sensors = vec!(
+			sensor("Delta T", "exhaust - inlet", &[("exhaust", by_name("Exhaust Temp")), ("inlet", by_name("Inlet Temp"))]),
+			sensor("CPU Avg", "avg(cpu1, cpu2)", &[("cpu1", cpu("3.1")), ("cpu2", cpu("3.2"))]),
+			// Earlier virtual sensors can be used as inputs
+			sensor("CPU Delta", "cpu - inlet", &[("cpu", by_name("CPU Avg")), ("inlet", by_name("Inlet Temp"))]),
+			sensor("Ambiguous", "cpu", &[("cpu", by_name("Temp"))]),
+			sensor("Fan", "fan", &[("fan", by_name("Fan1 RPM"))])
+		);
+
+		let values = evaluate(&sensors, &entries).into_iter().map(|v| (v.name, v.value)).collect::<Vec<_>>();
+		assert_eq!(values, vec!(
+			("Delta T".to_string(), IPMIValue::Temp(15)),
+			("CPU Avg".to_string(), IPMIValue::Temp(43)),
+			("CPU Delta".to_string(), IPMIValue::Temp(20)),
+			("Ambiguous".to_string(), IPMIValue::Invalid),
+			("Fan".to_string(), IPMIValue::Invalid)
+		));
+	}
+}
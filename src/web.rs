@@ -0,0 +1,827 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use futures::future::{self, Future};
+use futures::sync::{mpsc as future_mpsc, oneshot};
+use futures::Stream;
+use hyper::header::{self, HeaderValue};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use crate::status::{Command, CommandRequest, CommandSender, ConfigRequest, ConfigSender, Mode, Snapshot};
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+pub const DEFAULT_HISTORY_MINUTES: u64 = 180;
+pub const DEFAULT_SAMPLE_SECS: u64 = 5;
+
+/// Commands are applied on the next main loop iteration, which can be held up by a slow BMC.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most clients streaming snapshots at once.
+const MAX_STREAMS: usize = 16;
+/// Snapshots queued for a streaming client, once full new snapshots are dropped for it.
+const STREAM_DEPTH: usize = 8;
+
+/// Most recent events kept for the API.
+const MAX_EVENTS: usize = 500;
+
+/// Config sections the API can't change, they run commands as root, send raw IPMI commands or hold credentials.
+const FILE_ONLY_SECTIONS: [&str; 7] = ["command_sensors", "drives", "fans", "metrics", "mqtt", "socket", "web"];
+/// Section and key of every value the API never returns.
+const SECRETS: [(&str, &str); 3] = [("metrics", "influx_pw"), ("mqtt", "password"), ("web", "token")];
+const REDACTED: &str = "********";
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+#[derive(Deserialize, Clone)]
+pub struct WebConfig {
+	/// Address to serve the dashboard on, defaults to "127.0.0.1:8080".
+	pub listen: Option<String>,
+	/// Bearer token required to send commands and use the API, both are refused if unset.
+	pub token: Option<String>,
+	/// How far back charts go, defaults to 3 hours.
+	pub history_minutes: Option<u64>,
+	/// Seconds between samples kept for charts, defaults to 5.
+	pub sample_secs: Option<u64>
+}
+
+impl WebConfig {
+	pub fn addr(&self) -> Result<SocketAddr, String> {
+		let listen = self.listen.as_deref().unwrap_or(DEFAULT_LISTEN);
+
+		listen.parse().map_err(|_| format!("invalid web listen address \"{}\", expected a value like \"127.0.0.1:8080\"", listen))
+	}
+
+	pub fn validate(&self) -> Result<(), String> {
+		self.addr()?;
+
+		if self.token.as_ref().map(|v| v.trim().is_empty()).unwrap_or(false) {
+			return Err("web token must not be empty, remove it to disable commands".to_string())
+		}
+
+		if self.history_minutes == Some(0) || self.sample_secs == Some(0) {
+			return Err("web history_minutes and sample_secs must be above 0".to_string())
+		}
+
+		Ok(())
+	}
+
+	fn history(&self) -> History {
+		History::new(
+			Duration::from_secs(self.history_minutes.unwrap_or(DEFAULT_HISTORY_MINUTES) * 60),
+			Duration::from_secs(self.sample_secs.unwrap_or(DEFAULT_SAMPLE_SECS)))
+	}
+}
+
+/// Chart values for a single control.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ControlSample {
+	pub label: String,
+	pub temp: Option<f32>,
+	pub setpoint: f32,
+	pub p: f32,
+	pub i: f32,
+	pub d: f32,
+	pub ff: f32
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Sample {
+	/// Milliseconds since the unix epoch.
+	pub timestamp: u64,
+	pub duty: Option<f32>,
+	pub mode: Mode,
+	pub controls: Vec<ControlSample>
+}
+
+impl Sample {
+	fn new(snapshot: &Snapshot) -> Sample {
+		Sample {
+			timestamp: snapshot.timestamp,
+			duty: snapshot.duty,
+			mode: snapshot.mode,
+			controls: snapshot.controls.iter()
+				.map(|v| ControlSample {
+					label: v.label.clone(),
+					temp: v.temp,
+					setpoint: v.setpoint,
+					p: v.p,
+					i: v.i,
+					d: v.d,
+					ff: v.ff
+				})
+				.collect()
+		}
+	}
+}
+
+/// Ring buffer of snapshots, thinned to one every `interval` so hours of history stay small.
+pub struct History {
+	samples: VecDeque<Sample>,
+	retention: u64,
+	interval: u64
+}
+
+impl History {
+	pub fn new(retention: Duration, interval: Duration) -> History {
+		History {
+			samples: VecDeque::new(),
+			retention: retention.as_millis() as u64,
+			interval: interval.as_millis() as u64
+		}
+	}
+
+	pub fn add(&mut self, snapshot: &Snapshot) {
+		if let Some(last) = self.samples.back() {
+			if snapshot.timestamp < last.timestamp + self.interval {
+				return
+			}
+		}
+
+		self.samples.push_back(Sample::new(snapshot));
+
+		while self.samples.front().map(|v| v.timestamp + self.retention < snapshot.timestamp).unwrap_or(false) {
+			self.samples.pop_front();
+		}
+	}
+
+	/// Samples taken after `timestamp`, oldest first.
+	pub fn since(&self, timestamp: u64) -> Vec<Sample> {
+		self.samples.iter().filter(|v| v.timestamp > timestamp).cloned().collect()
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+	/// A control reached its failsafe temperature.
+	Failsafe,
+	/// Fans changed hands, I.E. from PID control to the BMC.
+	Mode,
+	/// A control or IPMI error from `Snapshot::last_error` that wasn't in the previous snapshot, I.E. a failed sensor read that handed fans to the BMC.
+	///
+	/// A failed fan command makes the daemon restore BMC control and exit, the log is lost with it so that is never an event.
+	Error
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+	/// Milliseconds since the unix epoch.
+	pub timestamp: u64,
+	pub kind: EventKind,
+	pub message: String
+}
+
+/// Notable changes between snapshots, keeping only the most recent `MAX_EVENTS`.
+pub struct EventLog {
+	events: VecDeque<Event>,
+	mode: Mode,
+	/// Labels of controls at or above their failsafe in the last snapshot.
+	tripped: Vec<String>,
+	last_error: Option<String>
+}
+
+impl EventLog {
+	pub fn new() -> EventLog {
+		EventLog {
+			events: VecDeque::new(),
+			// Fans belong to the BMC until the main loop takes them
+			mode: Mode::Bmc,
+			tripped: vec!(),
+			last_error: None
+		}
+	}
+
+	pub fn add(&mut self, snapshot: &Snapshot) {
+		let mut events = vec!();
+
+		for control in snapshot.controls.iter().filter(|v| v.tripped() && !self.tripped.contains(&v.label)) {
+			events.push((EventKind::Failsafe, format!("{} reached {} which is at or above its failsafe of {}", control.label, control.temp.unwrap_or_default(), control.failsafe)));
+		}
+
+		if snapshot.last_error != self.last_error {
+			if let Some(e) = snapshot.last_error.as_ref() {
+				events.push((EventKind::Error, e.clone()));
+			}
+		}
+
+		if snapshot.mode != self.mode {
+			events.push((EventKind::Mode, format!("Mode changed from {} to {}", self.mode.as_str(), snapshot.mode.as_str())));
+		}
+
+		self.mode = snapshot.mode;
+		self.tripped = snapshot.controls.iter().filter(|v| v.tripped()).map(|v| v.label.clone()).collect();
+		self.last_error = snapshot.last_error.clone();
+
+		for (kind, message) in events {
+			self.events.push_back(Event { timestamp: snapshot.timestamp, kind, message });
+		}
+
+		while self.events.len() > MAX_EVENTS {
+			self.events.pop_front();
+		}
+	}
+
+	/// Events after `timestamp`, oldest first.
+	pub fn since(&self, timestamp: u64) -> Vec<Event> {
+		self.events.iter().filter(|v| v.timestamp > timestamp).cloned().collect()
+	}
+}
+
+struct State {
+	history: Mutex<History>,
+	events: Mutex<EventLog>,
+	latest: Mutex<Option<Snapshot>>,
+	/// Clients of the event stream, each with its own bounded queue.
+	streams: Mutex<Vec<future_mpsc::Sender<Snapshot>>>,
+	commands: Mutex<CommandSender>,
+	configs: Mutex<ConfigSender>,
+	/// TOML of the running config, kept up to date by the main loop.
+	config: Arc<Mutex<String>>,
+	token: Option<String>
+}
+
+impl State {
+	fn new(history: History, commands: CommandSender, configs: ConfigSender, config: Arc<Mutex<String>>, token: Option<String>) -> State {
+		State {
+			history: Mutex::new(history),
+			events: Mutex::new(EventLog::new()),
+			latest: Mutex::new(None),
+			streams: Mutex::new(vec!()),
+			commands: Mutex::new(commands),
+			configs: Mutex::new(configs),
+			config,
+			token
+		}
+	}
+
+	fn update(&self, snapshot: Snapshot) {
+		lock(&self.history).add(&snapshot);
+		lock(&self.events).add(&snapshot);
+
+		// Never waits on a client, slow clients miss snapshots instead
+		lock(&self.streams).retain_mut(|client| match client.try_send(snapshot.clone()) {
+			Ok(_) => true,
+			Err(ref e) if e.is_full() => {
+				trace!("Streaming client is behind, dropping snapshot");
+				true
+			},
+			Err(_) => false
+		});
+
+		*lock(&self.latest) = Some(snapshot);
+	}
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// Binds the dashboard and API and serves them from a background thread, returning the address it listens on.
+pub fn init_web_thread(config: WebConfig, snapshots: mpsc::Receiver<Snapshot>, commands: CommandSender, configs: ConfigSender, running_config: Arc<Mutex<String>>) -> Result<SocketAddr, String> {
+	let addr = config.addr()?;
+	let server = Server::try_bind(&addr).map_err(|e| format!("unable to listen on {}: {}", addr, e))?;
+
+	let state = Arc::new(State::new(config.history(), commands, configs, running_config, config.token.clone()));
+
+	{
+		let state = state.clone();
+		thread::spawn(move || {
+			for snapshot in snapshots.iter() {
+				state.update(snapshot);
+			}
+		});
+	}
+
+	let server = server
+		.serve(move || {
+			let state = state.clone();
+			service_fn(move |req| handle(req, &state))
+		})
+		.map_err(|e| error!("Web dashboard failed: {}", e));
+
+	thread::spawn(move || hyper::rt::run(server));
+
+	Ok(addr)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn handle(req: Request<Body>, state: &Arc<State>) -> ResponseFuture {
+	match (req.method(), req.uri().path()) {
+		(&Method::GET, "/") => respond(StatusCode::OK, "text/html; charset=utf-8", Body::from(DASHBOARD)),
+		(&Method::GET, "/status") => json(StatusCode::OK, &*lock(&state.latest)),
+		(&Method::GET, "/history") => {
+			let since = query(req.uri().query(), "since")
+				.and_then(|v| v.parse::<u64>().ok())
+				.unwrap_or(0);
+
+			json(StatusCode::OK, &lock(&state.history).since(since))
+		},
+		(&Method::GET, "/events") => stream(state),
+		(&Method::POST, "/command") => {
+			if let Err(response) = authenticate(&req, state, "commands are disabled, set a token in [web] to enable them") {
+				return response
+			}
+
+			let state = state.clone();
+			with_body(req, move |body| match serde_json::from_slice::<Command>(body) {
+				Ok(command) => {
+					info!("Web command: {:?}", command);
+					send_command(command, &state)
+				},
+				Err(e) => error(StatusCode::BAD_REQUEST, &format!("invalid command: {}", e))
+			})
+		},
+		(_, path) if path.starts_with("/api/") => api(req, state),
+		_ => error(StatusCode::NOT_FOUND, "not found")
+	}
+}
+
+/// Versioned JSON API for fleet tooling, every request needs the bearer token.
+fn api(req: Request<Body>, state: &Arc<State>) -> ResponseFuture {
+	if let Err(response) = authenticate(&req, state, "the API is disabled, set a token in [web] to enable it") {
+		return response
+	}
+
+	match (req.method(), req.uri().path()) {
+		(&Method::GET, "/api/v1/state") => match lock(&state.latest).as_ref() {
+			Some(snapshot) => json(StatusCode::OK, snapshot),
+			None => error(StatusCode::SERVICE_UNAVAILABLE, "control loop has not run yet")
+		},
+		(&Method::GET, "/api/v1/config") => {
+			let config = lock(&state.config).clone();
+
+			match toml::from_str::<toml::value::Table>(&config) {
+				Ok(config) => json(StatusCode::OK, &redact(config)),
+				Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &format!("unable to parse running config: {}", e))
+			}
+		},
+		(&Method::PUT, "/api/v1/config") => {
+			let state = state.clone();
+			with_body(req, move |body| {
+				// JSON mirrors the TOML file, so it's converted back to be checked and saved like one
+				let update = match serde_json::from_slice::<toml::Value>(body) {
+					Ok(toml::Value::Table(v)) => v,
+					Ok(_) => return error(StatusCode::BAD_REQUEST, "invalid config: expected an object"),
+					Err(e) => return error(StatusCode::BAD_REQUEST, &format!("invalid config: {}", e))
+				};
+
+				let running = match toml::from_str::<toml::value::Table>(&lock(&state.config)) {
+					Ok(v) => v,
+					Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, &format!("unable to parse running config: {}", e))
+				};
+
+				let config = match merge_config(&running, update) {
+					Ok(v) => v,
+					Err(e) => return error(StatusCode::FORBIDDEN, &e)
+				};
+
+				match toml::to_string(&config) {
+					Ok(config) => {
+						info!("Web API config update");
+						send_config(config, &state)
+					},
+					Err(e) => error(StatusCode::BAD_REQUEST, &format!("invalid config: {}", e))
+				}
+			})
+		},
+		(&Method::POST, "/api/v1/override") => {
+			let state = state.clone();
+			with_body(req, move |body| match serde_json::from_slice::<Command>(body) {
+				Ok(Command::Resume) => error(StatusCode::BAD_REQUEST, "invalid override: use /api/v1/resume to resume"),
+				Ok(command) => {
+					info!("Web API override: {:?}", command);
+					send_command(command, &state)
+				},
+				Err(e) => error(StatusCode::BAD_REQUEST, &format!("invalid override: {}", e))
+			})
+		},
+		(&Method::POST, "/api/v1/resume") => {
+			info!("Web API resume");
+			send_command(Command::Resume, state)
+		},
+		(&Method::GET, "/api/v1/events") => {
+			let since = query(req.uri().query(), "since")
+				.and_then(|v| v.parse::<u64>().ok())
+				.unwrap_or(0);
+
+			json(StatusCode::OK, &lock(&state.events).since(since))
+		},
+		_ => error(StatusCode::NOT_FOUND, "not found")
+	}
+}
+
+/// Running config with `update` applied, sections in `FILE_ONLY_SECTIONS` are always kept from `running`.
+///
+/// An update may repeat them as they were returned by the API, but refused if it tries to change them.
+fn merge_config(running: &toml::value::Table, mut update: toml::value::Table) -> Result<toml::value::Table, String> {
+	let returned = redact(running.clone());
+
+	for section in FILE_ONLY_SECTIONS.iter() {
+		if let Some(value) = update.remove(*section) {
+			if Some(&value) != returned.get(*section) {
+				return Err(format!("[{}] can only be changed in the config file", section))
+			}
+		}
+
+		if let Some(current) = running.get(*section) {
+			update.insert(section.to_string(), current.clone());
+		}
+	}
+
+	Ok(update)
+}
+
+/// Config with passwords and tokens replaced, so it's safe to return.
+fn redact(mut config: toml::value::Table) -> toml::value::Table {
+	for (section, key) in SECRETS.iter() {
+		if let Some(value) = config.get_mut(*section).and_then(|v| v.get_mut(*key)) {
+			*value = toml::Value::String(REDACTED.to_string());
+		}
+	}
+
+	config
+}
+
+/// Checks the bearer token, `disabled` is the error when no token is configured.
+fn authenticate(req: &Request<Body>, state: &State, disabled: &str) -> Result<(), ResponseFuture> {
+	let token = match state.token.as_deref() {
+		Some(v) => v,
+		None => return Err(error(StatusCode::FORBIDDEN, disabled))
+	};
+
+	if !authorized(req, token) {
+		return Err(error(StatusCode::UNAUTHORIZED, "missing or invalid bearer token"))
+	}
+
+	Ok(())
+}
+
+fn with_body<F>(req: Request<Body>, handler: F) -> ResponseFuture
+	where F: FnOnce(&[u8]) -> ResponseFuture + Send + 'static
+{
+	Box::new(req.into_body().concat2().and_then(move |body| handler(&body)))
+}
+
+/// Value of a single `key=value` pair in a query string, values are expected to need no decoding.
+fn query<'a>(query: Option<&'a str>, key: &str) -> Option<&'a str> {
+	query?.split('&')
+		.filter_map(|v| {
+			let mut parts = v.splitn(2, '=');
+			Some((parts.next()?, parts.next().unwrap_or("")))
+		})
+		.find(|(k, _)| *k == key)
+		.map(|(_, v)| v)
+}
+
+fn authorized(req: &Request<Body>, token: &str) -> bool {
+	let provided = req.headers().get(header::AUTHORIZATION)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.strip_prefix("Bearer "))
+		.unwrap_or("");
+
+	// Compared in constant time so the token can't be guessed a byte at a time
+	provided.len() == token.len() && provided.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn send_command(command: Command, state: &State) -> ResponseFuture {
+	let (reply, result) = mpsc::channel();
+
+	if lock(&state.commands).send(CommandRequest { command, reply: Some(reply) }).is_err() {
+		return error(StatusCode::SERVICE_UNAVAILABLE, "control loop is not running")
+	}
+
+	wait_for_reply(result)
+}
+
+fn send_config(config: String, state: &State) -> ResponseFuture {
+	let (reply, result) = mpsc::channel();
+
+	if lock(&state.configs).send(ConfigRequest { config, reply }).is_err() {
+		return error(StatusCode::SERVICE_UNAVAILABLE, "control loop is not running")
+	}
+
+	wait_for_reply(result)
+}
+
+fn wait_for_reply(result: mpsc::Receiver<Result<(), String>>) -> ResponseFuture {
+	// Waiting on the main loop would otherwise hold up every other request
+	let (done, applied) = oneshot::channel();
+	thread::spawn(move || {
+		let result = result.recv_timeout(REPLY_TIMEOUT)
+			.map_err(|_| (StatusCode::SERVICE_UNAVAILABLE, "timed out waiting for the control loop".to_string()))
+			.and_then(|v| v.map_err(|e| (StatusCode::BAD_REQUEST, e)));
+
+		done.send(result).unwrap_or(());
+	});
+
+	Box::new(applied.then(|result| match result {
+		Ok(Ok(())) => json(StatusCode::OK, &serde_json::json!({ "ok": true })),
+		Ok(Err((status, e))) => error(status, &e),
+		Err(_) => error(StatusCode::SERVICE_UNAVAILABLE, "control loop is not running")
+	}))
+}
+
+/// Streams every snapshot as a server-sent event, starting with the latest.
+fn stream(state: &State) -> ResponseFuture {
+	let mut streams = lock(&state.streams);
+
+	if streams.len() >= MAX_STREAMS {
+		return error(StatusCode::SERVICE_UNAVAILABLE, "too many clients streaming")
+	}
+
+	let (mut client, snapshots) = future_mpsc::channel(STREAM_DEPTH);
+
+	if let Some(snapshot) = lock(&state.latest).clone() {
+		client.try_send(snapshot).unwrap_or(());
+	}
+
+	streams.push(client);
+
+	let events = snapshots
+		.map(|snapshot| event(&snapshot))
+		.map_err(|_| ::std::io::Error::new(::std::io::ErrorKind::BrokenPipe, "snapshots stopped"));
+
+	let mut response = Response::new(Body::wrap_stream(events));
+	response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+	response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+	Box::new(future::ok(response))
+}
+
+fn event(snapshot: &Snapshot) -> String {
+	// Compact JSON never spans lines, so a single data field holds it
+	format!("id: {}\ndata: {}\n\n", snapshot.timestamp, serde_json::to_string(snapshot).unwrap_or_default())
+}
+
+fn respond(status: StatusCode, content_type: &'static str, body: Body) -> ResponseFuture {
+	let mut response = Response::new(body);
+	*response.status_mut() = status;
+	response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+
+	Box::new(future::ok(response))
+}
+
+fn json<T: serde::Serialize>(status: StatusCode, value: &T) -> ResponseFuture {
+	match serde_json::to_vec(value) {
+		Ok(body) => respond(status, "application/json", Body::from(body)),
+		Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+	}
+}
+
+fn error(status: StatusCode, message: &str) -> ResponseFuture {
+	json(status, &serde_json::json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::status::ControlStatus;
+
+	fn state(commands: CommandSender, token: Option<&str>) -> Arc<State> {
+		let (configs, _) = mpsc::channel();
+		Arc::new(State::new(History::new(Duration::from_secs(60), Duration::from_secs(5)), commands, configs, Arc::new(Mutex::new(String::new())), token.map(|v| v.to_string())))
+	}
+
+	fn snapshot(timestamp: u64, temp: f32) -> Snapshot {
+		Snapshot {
+			timestamp,
+			controls: vec!(ControlStatus {
+				name: "Exhaust Temp".to_string(),
+				label: "Exhaust Temp".to_string(),
+				temp: Some(temp),
+				setpoint: 40.0,
+				setpoint_override: false,
+				failsafe: 65.0,
+				p: 0.1,
+				i: 0.2,
+				d: 0.0,
+				ff: 0.0,
+				v: 0.3,
+				contribution: 0.3
+			}),
+			winner: None,
+			schedule: None,
+			fans: vec!(),
+			duty: Some(0.3),
+			mode: Mode::Pid,
+			shadow: false,
+			override_remaining: None,
+			last_error: None,
+			fan_reasserts: 0,
+			budget: None
+		}
+	}
+
+	#[test]
+	fn keeps_thinned_history() {
+		let mut history = History::new(Duration::from_secs(60), Duration::from_secs(5));
+
+		for secs in 0..120 {
+			history.add(&snapshot(secs * 1000, secs as f32));
+		}
+
+		let samples = history.since(0);
+		assert_eq!(samples.first().map(|v| v.timestamp), Some(55_000));
+		assert_eq!(samples.last().map(|v| v.timestamp), Some(115_000));
+		assert_eq!(samples.len(), 13);
+		assert_eq!(history.since(110_000).len(), 1);
+		assert_eq!(samples[0].controls[0].temp, Some(55.0));
+	}
+
+	#[test]
+	fn commands_need_token() {
+		let (commands, requests) = mpsc::channel::<CommandRequest>();
+		let state = state(commands, Some("secret"));
+
+		thread::spawn(move || {
+			for request in requests.iter() {
+				assert_eq!(request.command, Command::Resume);
+				request.reply.unwrap().send(Ok(())).unwrap();
+			}
+		});
+
+		let command = |token: Option<&str>| {
+			let mut req = Request::post("/command");
+			if let Some(token) = token {
+				req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+			}
+
+			handle(req.body(Body::from(r#"{"type":"resume"}"#)).unwrap(), &state).wait().unwrap().status()
+		};
+
+		assert_eq!(command(None), StatusCode::UNAUTHORIZED);
+		assert_eq!(command(Some("secre")), StatusCode::UNAUTHORIZED);
+		assert_eq!(command(Some("secret")), StatusCode::OK);
+
+		let status = handle(Request::get("/history?since=0").body(Body::empty()).unwrap(), &state).wait().unwrap().status();
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(query(Some("since=42&x"), "since"), Some("42"));
+	}
+
+	#[test]
+	fn streams_snapshots_without_blocking() {
+		let (commands, _requests) = mpsc::channel();
+		let state = state(commands, None);
+		state.update(snapshot(1000, 40.0));
+
+		let events = |state: &Arc<State>| handle(Request::get("/events").body(Body::empty()).unwrap(), state).wait().unwrap().into_body().wait();
+
+		let mut fast = events(&state);
+		let slow = events(&state);
+		assert!(String::from_utf8_lossy(&fast.next().unwrap().unwrap()).starts_with("id: 1000\ndata: {\"timestamp\":1000,"));
+
+		// The slow client never reads, its queue fills and further snapshots are dropped for it
+		for secs in 2..40 {
+			state.update(snapshot(secs * 1000, 40.0));
+			assert!(fast.next().unwrap().is_ok());
+		}
+		assert_eq!(lock(&state.streams).len(), 2);
+
+		drop(slow);
+		state.update(snapshot(40_000, 40.0));
+		assert_eq!(lock(&state.streams).len(), 1);
+	}
+
+	#[test]
+	fn logs_events() {
+		let mut events = EventLog::new();
+		events.add(&snapshot(1000, 40.0));
+		events.add(&snapshot(2000, 41.0));
+
+		let mut tripped = snapshot(3000, 66.0);
+		tripped.mode = Mode::Bmc;
+		tripped.last_error = Some("failsafe of 65 exceeded: 66".to_string());
+		events.add(&tripped);
+		tripped.timestamp = 4000;
+		events.add(&tripped);
+
+		let logged = events.since(0).into_iter().map(|v| (v.timestamp, v.kind)).collect::<Vec<_>>();
+		assert_eq!(logged, vec!((1000, EventKind::Mode), (3000, EventKind::Failsafe), (3000, EventKind::Error), (3000, EventKind::Mode)));
+		assert_eq!(events.since(1000)[0].message, "Exhaust Temp reached 66 which is at or above its failsafe of 65");
+		assert_eq!(events.since(1000)[2].message, "Mode changed from pid to bmc");
+	}
+
+	#[test]
+	fn logs_repeated_errors_after_recovery() {
+		let mut events = EventLog::new();
+		let failed = |timestamp: u64| {
+			let mut snapshot = snapshot(timestamp, 40.0);
+			snapshot.last_error = Some("Exhaust Temp is invalid".to_string());
+			snapshot
+		};
+
+		events.add(&failed(1000));
+		events.add(&failed(2000));
+		events.add(&snapshot(3000, 40.0));
+		events.add(&failed(4000));
+
+		let errors = events.since(0).into_iter().filter(|v| v.kind == EventKind::Error).map(|v| v.timestamp).collect::<Vec<_>>();
+		assert_eq!(errors, vec!(1000, 4000));
+	}
+
+	#[test]
+	fn serves_versioned_api() {
+		let (commands, requests) = mpsc::channel::<CommandRequest>();
+		let (configs, config_requests) = mpsc::channel::<ConfigRequest>();
+		let running = Arc::new(Mutex::new("[[controls]]\nname = \"Exhaust Temp\"\nsetpoint = 40.0\n".to_string()));
+		let state = Arc::new(State::new(History::new(Duration::from_secs(60), Duration::from_secs(5)), commands, configs, running.clone(), Some("secret".to_string())));
+
+		thread::spawn(move || {
+			for request in requests.iter() {
+				request.reply.unwrap().send(Ok(())).unwrap();
+			}
+		});
+
+		thread::spawn(move || {
+			for request in config_requests.iter() {
+				let result = ::toml::from_str::<crate::config::AppConfig>(&request.config)
+					.map_err(|e| e.to_string())
+					.and_then(|v| v.validate());
+				if result.is_ok() {
+					*lock(&running) = request.config;
+				}
+				request.reply.send(result).unwrap();
+			}
+		});
+
+		let call = |method: Method, path: &str, body: &str, token: Option<&str>| {
+			let mut req = Request::builder();
+			req.method(method).uri(path);
+			if let Some(token) = token {
+				req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+			}
+
+			let response = handle(req.body(Body::from(body.to_string())).unwrap(), &state).wait().unwrap();
+			let status = response.status();
+			let body = response.into_body().concat2().wait().unwrap();
+			(status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+		};
+
+		assert_eq!(call(Method::GET, "/api/v1/state", "", None).0, StatusCode::UNAUTHORIZED);
+		assert_eq!(call(Method::GET, "/api/v1/state", "", Some("secret")).0, StatusCode::SERVICE_UNAVAILABLE);
+		state.update(snapshot(1000, 40.0));
+		assert_eq!(call(Method::GET, "/api/v1/state", "", Some("secret")).1["mode"], "pid");
+		assert_eq!(call(Method::GET, "/api/v1/events?since=0", "", Some("secret")).1[0]["kind"], "mode");
+
+		let (status, config) = call(Method::GET, "/api/v1/config", "", Some("secret"));
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(config["controls"][0]["setpoint"], 40.0);
+
+		assert_eq!(call(Method::PUT, "/api/v1/config", r#"{"controls": [{"name": "Exhaust Temp", "setpoint": 35.0}]}"#, Some("secret")).0, StatusCode::OK);
+		assert_eq!(call(Method::GET, "/api/v1/config", "", Some("secret")).1["controls"][0]["setpoint"], 35.0);
+		assert_eq!(call(Method::PUT, "/api/v1/config", r#"{"pid": "fast"}"#, Some("secret")).0, StatusCode::BAD_REQUEST);
+		assert_eq!(call(Method::PUT, "/api/v1/config", "[]", Some("secret")).0, StatusCode::BAD_REQUEST);
+		assert_eq!(call(Method::PUT, "/api/v1/config", r#"{"command_sensors": [{"name": "GPU", "command": ["/bin/sh", "-c", "id"]}]}"#, Some("secret")).0, StatusCode::FORBIDDEN);
+		assert!(call(Method::GET, "/api/v1/config", "", Some("secret")).1.get("command_sensors").is_none());
+
+		assert_eq!(call(Method::POST, "/api/v1/override", r#"{"type": "set_duty", "duty": 0.5, "minutes": 10}"#, Some("secret")).0, StatusCode::OK);
+		assert_eq!(call(Method::POST, "/api/v1/override", r#"{"type": "resume"}"#, Some("secret")).0, StatusCode::BAD_REQUEST);
+		assert_eq!(call(Method::POST, "/api/v1/resume", "", Some("secret")).0, StatusCode::OK);
+		assert_eq!(call(Method::GET, "/api/v2/state", "", Some("secret")).0, StatusCode::NOT_FOUND);
+	}
+
+	#[test]
+	fn keeps_file_only_sections() {
+		let running = toml::from_str::<toml::value::Table>(r#"
+			[fans]
+			profile = "dell"
+
+			[web]
+			token = "secret"
+
+			[[command_sensors]]
+			name = "GPU"
+			command = ["/usr/local/bin/gpu-temp"]
+
+			[[controls]]
+			name = "Exhaust Temp"
+			setpoint = 40.0
+		"#).unwrap();
+
+		let update = |json: &str| merge_config(&running, serde_json::from_str(json).unwrap());
+
+		// Sections omitted or repeated unchanged are kept from the running config
+		let merged = update(r#"{"controls": [{"name": "Exhaust Temp", "setpoint": 35.0}]}"#).unwrap();
+		assert_eq!(merged["fans"], running["fans"]);
+		assert_eq!(merged["command_sensors"], running["command_sensors"]);
+		assert_eq!(merged["controls"][0]["setpoint"].as_float(), Some(35.0));
+		assert!(update(r#"{"fans": {"profile": "dell"}, "controls": []}"#).is_ok());
+
+		assert!(update(r#"{"command_sensors": [{"name": "GPU", "command": ["/bin/sh", "-c", "id"]}]}"#).is_err());
+		assert!(update(r#"{"fans": {"profile": "custom", "speed": ["0x30 0x30 0x02 0xff {duty}"]}}"#).is_err());
+		assert!(update(r#"{"drives": {"smartctl": ["/dev/sda"]}}"#).is_err());
+		assert!(update(r#"{"web": {"token": "new"}}"#).is_err());
+
+		// Secrets are never returned, a redacted section sent back keeps the real value
+		let returned = redact(running.clone());
+		assert_eq!(returned["web"]["token"].as_str(), Some(REDACTED));
+		assert_eq!(returned["fans"], running["fans"]);
+		let merged = update(&serde_json::to_string(&returned).unwrap()).unwrap();
+		assert_eq!(merged["web"]["token"].as_str(), Some("secret"));
+	}
+}